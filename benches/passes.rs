@@ -0,0 +1,66 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use rsteg::img::{self, Placement, Sample};
+use rsteg::stego::Plane;
+use rsteg::{EqualizeChannel, StretchMode};
+
+// A 1024x768 photo sized image, noisy enough that no pass takes a shortcut
+const WIDTH: u32 = 1024;
+const HEIGHT: u32 = 768;
+
+fn image() -> Vec<u8> {
+    (0..WIDTH as usize * HEIGHT as usize * 3)
+        .map(|i| ((i * 37 + i / 3071 * 11) % 251) as u8)
+        .collect()
+}
+
+// The cipher a sample at a time with no u64 words, as it was before them, to
+// time the words against on the same input. It runs on one thread, so compare
+// it with the word path built without rayon.
+fn scalar_cipher<T: Sample>(buf: &mut [T], key: [u8; 32], bits: u8) {
+    let mut rng = ChaCha20Rng::from_seed(key);
+    let mask = T::low_mask(bits);
+    for x in buf {
+        *x = T::from_u32(x.to_u32() ^ rng.next_u32() & mask);
+    }
+}
+
+fn passes(c: &mut Criterion) {
+    let buf = image();
+    let key = [7; 32];
+
+    c.bench_function("stretch", |b| b.iter_batched_ref(|| buf.clone(),
+        |buf| img::stretch(buf, 1.0, StretchMode::Channel), BatchSize::LargeInput));
+
+    c.bench_function("equalize", |b| b.iter_batched_ref(|| buf.clone(),
+        |buf| img::equalize(buf, EqualizeChannel::Value), BatchSize::LargeInput));
+
+    c.bench_function("stream_cipher", |b| b.iter_batched_ref(|| buf.clone(),
+        |buf| img::stream_cipher(buf, key, 8), BatchSize::LargeInput));
+
+    c.bench_function("stream_cipher_scalar", |b| b.iter_batched_ref(|| buf.clone(),
+        |buf| scalar_cipher(buf, key, 8), BatchSize::LargeInput));
+
+    // Channels of differing bits take the sample at a time path
+    c.bench_function("stream_cipher_mixed", |b| b.iter_batched_ref(|| buf.clone(),
+        |buf| img::stream_cipher_rgb(buf, key, [8, 7, 8]), BatchSize::LargeInput));
+
+    // 16-bit samples fold four to a u64 word
+    let wide: Vec<u16> = buf.iter().map(|&c| c as u16 * 257).collect();
+    c.bench_function("stream_cipher_16", |b| b.iter_batched_ref(|| wide.clone(),
+        |buf| img::stream_cipher(buf, key, 8), BatchSize::LargeInput));
+
+    c.bench_function("stream_cipher_16_scalar", |b| b.iter_batched_ref(|| wide.clone(),
+        |buf| scalar_cipher(buf, key, 8), BatchSize::LargeInput));
+
+    let hidden: Vec<u8> = buf.iter().map(|c| c >> 6).collect();
+    c.bench_function("conceal", |b| b.iter_batched(|| (WIDTH, HEIGHT, buf.clone(), None),
+        |cover| img::conceal((WIDTH, HEIGHT, &hidden), cover, [2; 3], Plane::Low, 0,
+            Placement::TopLeft, false, None, None).unwrap(),
+        BatchSize::LargeInput));
+}
+
+criterion_group!(benches, passes);
+criterion_main!(benches);
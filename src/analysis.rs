@@ -0,0 +1,169 @@
+use crate::hsv::HSVColor;
+use crate::img::Sample;
+
+// Pairs of values seen fewer times than this are too sparse to test
+const MIN_PAIR_COUNT: usize = 10;
+
+// Outcome of the chi-square test of one channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChiSquare {
+    pub statistic: f64,
+    pub pairs: usize,
+    // Probability the low bits carry embedded data, from 0 to 1
+    pub probability: f64,
+}
+
+// Chi-square attack on the low bits of each of R, G and B. Embedding
+// random bits evens out the counts of each pair of values 2k and 2k + 1, so
+// the closer the pairs are to their mean the likelier the channel carries a
+// payload.
+pub fn chi_square<T: Sample>(buf: &[T]) -> [ChiSquare; 3] {
+    std::array::from_fn(|k| {
+        let mut hist = vec![0usize; T::MAX as usize + 1];
+        for c in buf.iter().skip(k).step_by(3) {
+            hist[c.to_u32() as usize] += 1;
+        }
+
+        let (mut statistic, mut pairs) = (0.0, 0);
+        for pair in hist.chunks_exact(2) {
+            let total = pair[0] + pair[1];
+            if total < MIN_PAIR_COUNT {
+                continue;
+            }
+
+            let expected = total as f64 / 2.0;
+            statistic += (pair[0] as f64 - expected).powi(2) / expected;
+            pairs += 1;
+        }
+
+        // Too few pairs to have any degrees of freedom says nothing
+        let probability = if pairs > 1 {
+            gamma_q((pairs - 1) as f64 / 2.0, statistic / 2.0)
+        } else {
+            0.0
+        };
+
+        ChiSquare { statistic, pairs, probability }
+    })
+}
+
+// Counts of R, G, B and luminance, the HSV value, each in this many bins
+// of equal width over the range of samples
+pub fn histogram<T: Sample>(buf: &[T], bins: usize) -> [Vec<usize>; 4] {
+    let mut hist: [Vec<usize>; 4] = std::array::from_fn(|_| vec![0; bins]);
+    let bin = |v: u64| (v * bins as u64 / (T::MAX as u64 + 1)) as usize;
+    for p in buf.chunks_exact(3) {
+        let [r, g, b] = [0, 1, 2].map(|k| p[k].to_u32() as f32);
+        let val = HSVColor::from_rgb(r, g, b, T::DEPTH).val;
+        for (k, c) in p.iter().enumerate() {
+            hist[k][bin(c.to_u32() as u64)] += 1;
+        }
+        hist[3][bin((val * T::MAX as f32).round() as u64)] += 1;
+    }
+    hist
+}
+
+// Regularized upper incomplete gamma function Q(a, x), by its series below
+// a + 1 and its continued fraction above
+fn gamma_q(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+
+    let scale = (a * x.ln() - x - ln_gamma(a)).exp();
+    if x < a + 1.0 {
+        let (mut term, mut sum) = (1.0 / a, 1.0 / a);
+        for n in 1..500 {
+            term *= x / (a + n as f64);
+            sum += term;
+            if term.abs() < sum.abs() * 1e-15 {
+                break;
+            }
+        }
+        (1.0 - sum * scale).clamp(0.0, 1.0)
+    } else {
+        // Lentz's method
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let (mut c, mut d) = (1.0 / tiny, 1.0 / b);
+        let mut h = d;
+        for n in 1..500 {
+            let an = -(n as f64) * (n as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            d = if d.abs() < tiny { 1.0 / tiny } else { 1.0 / d };
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < 1e-15 {
+                break;
+            }
+        }
+        (h * scale).clamp(0.0, 1.0)
+    }
+}
+
+// Lanczos approximation of ln Γ(x)
+fn ln_gamma(x: f64) -> f64 {
+    const G: [f64; 6] = [76.18009172947146, -86.50532032941677, 24.01409824083091,
+        -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5];
+
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let series = G.iter().enumerate()
+        .fold(1.000000000190015, |s, (i, g)| s + g / (x + 1.0 + i as f64));
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gamma_q_matches_known_values() {
+        // Q(1, x) is e^-x and Q(1/2, x) is erfc(sqrt x)
+        for x in [0.1f64, 1.0, 2.5, 10.0] {
+            assert!((gamma_q(1.0, x) - (-x).exp()).abs() < 1e-9);
+        }
+        assert!((gamma_q(0.5, 1.0) - 0.157299207050285).abs() < 1e-9);
+        assert_eq!(gamma_q(3.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn even_pairs_look_embedded() {
+        // Every pair of values equally common, as random low bits leave them
+        let buf: Vec<u8> = (0..256 * 3 * 20).map(|i| (i / 3 % 256) as u8).collect();
+        for c in chi_square(&buf) {
+            assert_eq!(c.statistic, 0.0);
+            assert_eq!(c.pairs, 128);
+            assert!(c.probability > 0.99);
+        }
+    }
+
+    #[test]
+    fn lopsided_pairs_look_clean() {
+        // Only even values, as a cover with its low bits cleared
+        let buf: Vec<u8> = (0..256 * 3 * 20).map(|i| (i / 3 % 128 * 2) as u8).collect();
+        for c in chi_square(&buf) {
+            assert!(c.probability < 0.01);
+        }
+    }
+
+    #[test]
+    fn histogram_bins_each_channel_and_value() {
+        let buf: [u8; 6] = [0, 128, 255, 64, 10, 20];
+        let [r, g, b, v] = histogram(&buf, 4);
+        assert_eq!(r, [1, 1, 0, 0]);
+        assert_eq!(g, [1, 0, 1, 0]);
+        assert_eq!(b, [1, 0, 0, 1]);
+        assert_eq!(v, [0, 1, 0, 1]);
+        assert_eq!(histogram(&[0u16, 65535, 0], 2)[1], [0, 1]);
+    }
+
+    #[test]
+    fn sparse_images_are_inconclusive() {
+        assert_eq!(chi_square(&[7u8, 8, 9]).map(|c| c.probability), [0.0; 3]);
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::{self, Read, Write};
+use crate::img::ImgError;
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+
+fn err(msg: &str) -> ImgError {
+    ImgError::Decode(format!("BMP {}", msg))
+}
+
+// Decodes an uncompressed 24 or 32-bit BMP into RGB or RGBA samples,
+// returned alongside the sample count per pixel
+pub fn read_bmp<R: Read>(mut r: R) -> Result<(u32, u32, Vec<u8>, usize), ImgError> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data).map_err(|e| ImgError::Decode(e.to_string()))?;
+
+    if data.len() < 54 || &data[..2] != b"BM" {
+        return Err(err("header is truncated"));
+    }
+
+    let offset = u32_at(&data, 10) as usize;
+    let header_size = u32_at(&data, 14) as usize;
+    let width = u32_at(&data, 18) as i32;
+    let height = u32_at(&data, 22) as i32;
+    let bpp = u16_at(&data, 28);
+    let compression = u32_at(&data, 30);
+
+    if width <= 0 || height == 0 {
+        return Err(err("dimensions are invalid"));
+    }
+
+    // Negative height means rows are stored top-down
+    let top_down = height < 0;
+    let (width, height) = (width as usize, height.unsigned_abs() as usize);
+
+    // Channel masks in R, G, B, A order
+    let masks: [u32; 4] = match (bpp, compression) {
+        (24, BI_RGB) => [0xFF0000, 0xFF00, 0xFF, 0],
+        (32, BI_RGB) => [0xFF0000, 0xFF00, 0xFF, 0xFF000000],
+        (32, BI_BITFIELDS) => {
+            if data.len() < 66 {
+                return Err(err("bitfield masks are truncated"));
+            }
+            let alpha = if header_size >= 56 && data.len() >= 70 { u32_at(&data, 66) } else { 0 };
+            [u32_at(&data, 54), u32_at(&data, 58), u32_at(&data, 62), alpha]
+        },
+        _ => return Err(err("format is not 24 or 32-bit uncompressed")),
+    };
+
+    let stride = (width * bpp as usize / 8).div_ceil(4) * 4;
+    let end = stride.checked_mul(height).and_then(|n| n.checked_add(offset));
+    if end.is_none_or(|end| end > data.len()) {
+        return Err(err("pixel data is truncated"));
+    }
+
+    let channel = |px: u32, mask: u32| {
+        if mask == 0 { 0 } else { ((px & mask) >> mask.trailing_zeros()) as u8 }
+    };
+
+    let mut buf = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        let row = if top_down { y } else { height - 1 - y };
+        let row = &data[offset + row * stride..][..stride];
+
+        for x in 0..width {
+            let px = match bpp {
+                24 => u32::from_le_bytes([row[x * 3], row[x * 3 + 1], row[x * 3 + 2], 0]),
+                _ => u32_at(row, x * 4),
+            };
+            buf.extend(masks.iter().map(|m| channel(px, *m)));
+        }
+    }
+
+    // Many writers leave the fourth byte zeroed, treat that as opaque
+    if masks[3] == 0 || buf.chunks_exact(4).all(|p| p[3] == 0) {
+        let rgb = buf.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]).collect();
+        Ok((width as u32, height as u32, rgb, 3))
+    } else {
+        Ok((width as u32, height as u32, buf, 4))
+    }
+}
+
+// Encodes RGB samples as a bottom-up 24-bit BMP, or with alpha as a 32-bit
+// BMP with a V4 header carrying the channel masks
+pub fn write_bmp<W: Write>(mut w: W, width: u32, height: u32, buf: &[u8], alpha: Option<&[u8]>)
+        -> io::Result<()> {
+    let (width, height) = (width as usize, height as usize);
+    let bpp = if alpha.is_some() { 32 } else { 24 };
+    let header_size = if alpha.is_some() { 108 } else { 40 };
+    let stride = (width * bpp / 8).div_ceil(4) * 4;
+    let offset = 14 + header_size;
+
+    let mut h = Vec::with_capacity(offset);
+    h.extend(b"BM");
+    h.extend(((offset + stride * height) as u32).to_le_bytes());
+    h.extend([0; 4]);
+    h.extend((offset as u32).to_le_bytes());
+
+    h.extend((header_size as u32).to_le_bytes());
+    h.extend((width as i32).to_le_bytes());
+    h.extend((height as i32).to_le_bytes());
+    h.extend(1u16.to_le_bytes());
+    h.extend((bpp as u16).to_le_bytes());
+    h.extend((if alpha.is_some() { BI_BITFIELDS } else { BI_RGB }).to_le_bytes());
+    h.extend(((stride * height) as u32).to_le_bytes());
+    h.extend([0; 16]);
+
+    if alpha.is_some() {
+        for mask in [0xFF0000u32, 0xFF00, 0xFF, 0xFF000000] {
+            h.extend(mask.to_le_bytes());
+        }
+        h.extend(b"BGRs");
+        h.resize(offset, 0);
+    }
+    w.write_all(&h)?;
+
+    let mut row = vec![0; stride];
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let p = &buf[(y * width + x) * 3..][..3];
+            match alpha {
+                Some(a) => row[x * 4..x * 4 + 4].copy_from_slice(&[p[2], p[1], p[0], a[y * width + x]]),
+                None => row[x * 3..x * 3 + 3].copy_from_slice(&[p[2], p[1], p[0]]),
+            }
+        }
+        w.write_all(&row)?;
+    }
+
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(width: u32, height: u32, rgb: &[u8], alpha: Option<&[u8]>) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_bmp(&mut data, width, height, rgb, alpha).unwrap();
+        data
+    }
+
+    #[test]
+    fn reads_24_and_32_bit_images() {
+        let rgb: Vec<u8> = (0..5 * 3 * 3).map(|i| (i * 37 % 256) as u8).collect();
+        let alpha: Vec<u8> = (0..5 * 3).map(|i| (i * 11 % 256) as u8 | 1).collect();
+        let rgba: Vec<u8> = rgb.chunks_exact(3).zip(&alpha)
+            .flat_map(|(p, a)| [p[0], p[1], p[2], *a])
+            .collect();
+
+        assert_eq!(read_bmp(&encode(5, 3, &rgb, None)[..]).unwrap(), (5, 3, rgb.clone(), 3));
+        let mut data = encode(5, 3, &rgb, Some(&alpha));
+        assert_eq!(read_bmp(&data[..]).unwrap(), (5, 3, rgba.clone(), 4));
+
+        // 32-bit without masks, in the same byte order
+        data[30..34].copy_from_slice(&BI_RGB.to_le_bytes());
+        assert_eq!(read_bmp(&data[..]).unwrap(), (5, 3, rgba, 4));
+    }
+
+    #[test]
+    fn reads_top_down_images() {
+        let rgb: Vec<u8> = (0..5 * 3 * 3).map(|i| (i * 37 % 256) as u8).collect();
+        let data = encode(5, 3, &rgb, None);
+        let (offset, stride) = (u32_at(&data, 10) as usize, 16);
+
+        let mut flipped = data[..offset].to_vec();
+        flipped[22..26].copy_from_slice(&(-3i32).to_le_bytes());
+        data[offset..].chunks_exact(stride).rev().for_each(|row| flipped.extend(row));
+        assert_eq!(read_bmp(&flipped[..]).unwrap(), (5, 3, rgb, 3));
+    }
+
+    #[test]
+    fn refuses_truncated_and_oversized_images() {
+        let data = encode(5, 3, &[0; 5 * 3 * 3], None);
+        assert!(matches!(read_bmp(&data[..data.len() - 1]), Err(ImgError::Decode(_))));
+
+        for (at, value) in [(22, i32::MIN + 1), (18, i32::MAX), (10, -1)] {
+            let mut data = data.clone();
+            data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            assert!(matches!(read_bmp(&data[..]), Err(ImgError::Decode(_))));
+        }
+    }
+}
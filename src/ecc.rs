@@ -0,0 +1,69 @@
+use crate::img::ImgError;
+
+// Extended Hamming code over nibbles, each stored in a byte: bits 1 to 7 hold
+// the Hamming(7,4) codeword by position and bit 0 the parity of them all. Any
+// single flipped bit in a byte is corrected, and any two are detected.
+
+// Codeword of the low nibble of d
+fn encode_nibble(d: u8) -> u8 {
+    let bit = |i: u8| d >> i & 1;
+    let (d1, d2, d3, d4) = (bit(3), bit(2), bit(1), bit(0));
+    let (p1, p2, p4) = (d1 ^ d2 ^ d4, d1 ^ d3 ^ d4, d2 ^ d3 ^ d4);
+
+    let code = p1 << 1 | p2 << 2 | d1 << 3 | p4 << 4 | d2 << 5 | d3 << 6 | d4 << 7;
+    code | (code.count_ones() as u8 & 1)
+}
+
+// Nibble of a codeword, with a single flipped bit put right. None when two
+// bits are flipped.
+fn decode_nibble(mut code: u8) -> Option<u8> {
+    let syndrome = (1..8).filter(|i| code >> i & 1 == 1).fold(0, |s, i| s ^ i);
+    match (syndrome, code.count_ones() & 1) {
+        (0, 0) => {},
+        (s, 1) => code ^= 1 << s,
+        _ => return None,
+    }
+
+    let bit = |i: u8| code >> i & 1;
+    Some(bit(3) << 3 | bit(5) << 2 | bit(6) << 1 | bit(7))
+}
+
+// Two bytes of code for each byte of data, high nibble first
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    data.iter().flat_map(|b| [encode_nibble(b >> 4), encode_nibble(b & 0xF)]).collect()
+}
+
+// Inverse of encode, correcting a flipped bit in each byte of code
+pub fn decode(code: &[u8]) -> Result<Vec<u8>, ImgError> {
+    code.chunks_exact(2)
+        .map(|c| Some(decode_nibble(c[0])? << 4 | decode_nibble(c[1])?))
+        .collect::<Option<_>>()
+        .ok_or_else(|| ImgError::Decode("payload has more errors than ECC can correct".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrects_any_single_bit_error() {
+        let data: Vec<u8> = (0..=255).collect();
+        let code = encode(&data);
+        assert_eq!(code.len(), 512);
+        assert_eq!(decode(&code).unwrap(), data);
+
+        for bit in 0..8 {
+            let flipped: Vec<u8> = code.iter().map(|c| c ^ 1 << bit).collect();
+            assert_eq!(decode(&flipped).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn detects_double_bit_errors() {
+        let code = encode(b"x");
+        for (a, b) in [(0, 1), (2, 7), (3, 5)] {
+            let flipped = [code[0] ^ (1 << a | 1 << b), code[1]];
+            assert!(matches!(decode(&flipped), Err(ImgError::Decode(_))));
+        }
+    }
+}
@@ -6,8 +6,8 @@ pub struct HSVColor {
 }
 
 impl HSVColor {
-    pub fn from_rgb<T: Into<f32>>(red: T, green: T, blue: T) -> Self {
-        let n: f32 = u8::MAX as f32;
+    pub fn from_rgb<T: Into<f32>>(red: T, green: T, blue: T, depth: u8) -> Self {
+        let n: f32 = ((1u32 << depth) - 1) as f32;
         let (r, g, b) = (red.into() / n, green.into() / n, blue.into() / n);
 
         let v: f32 = r.max(g.max(b));
@@ -17,7 +17,9 @@ impl HSVColor {
             (if c == 0.0 {
                 0.0
             } else if v == r {
-                ((g - b) / c) % 6.0
+                // Euclidean, as % keeps the sign and gives a negative
+                // hue when g < b
+                ((g - b) / c).rem_euclid(6.0)
             } else if v == g {
                 ((b - r) / c) + 2.0
             } else if v == b {
@@ -32,9 +34,13 @@ impl HSVColor {
         HSVColor { hue: h, sat: s, val: v }
     }
 
-    pub fn to_rgb(&self) -> [u8; 3] {
+    pub fn to_rgb(&self, depth: u8) -> [u16; 3] {
+        // Hue is circular, brought into [0, 360) where rounding can leave
+        // a tiny negative on 360 itself
+        let hue = self.hue.rem_euclid(360.0);
+        let h = if hue < 360.0 { hue / 60.0 } else { 0.0 };
+
         let c = self.val * self.sat;
-        let h = self.hue / 60.0;
         let x = c * (1.0 - (h % 2.0 - 1.0).abs());
         let m = self.val - c;
 
@@ -44,15 +50,61 @@ impl HSVColor {
             v if v < 3.0 => (0.0, c, x),
             v if v < 4.0 => (0.0, x, c),
             v if v < 5.0 => (x, 0.0, c),
-            v if v < 6.0 => (c, 0.0, x),
-            _ => unreachable!()
+            _ => (c, 0.0, x),
         };
 
-        let n = u8::MAX as f32;
-        let r = ((r1 + m) * n) as u8;
-        let g = ((g1 + m) * n) as u8;
-        let b = ((b1 + m) * n) as u8;
+        // Rounding can push a channel just past either end of the range
+        let n = ((1u32 << depth) - 1) as f32;
+        let r = ((r1 + m).clamp(0.0, 1.0) * n) as u16;
+        let g = ((g1 + m).clamp(0.0, 1.0) * n) as u16;
+        let b = ((b1 + m).clamp(0.0, 1.0) * n) as u16;
 
         [r, g, b]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_rgb_stays_in_range_at_full_value_and_saturation() {
+        for depth in [8, 16] {
+            let max = ((1u32 << depth) - 1) as u16;
+            for hue in [0.0, 59.999, 60.0, 120.0, 180.0, 240.0, 300.0, 359.999] {
+                let rgb = HSVColor { hue, sat: 1.0, val: 1.0 }.to_rgb(depth);
+                assert!(rgb.iter().all(|&c| c <= max), "{:?} at hue {}", rgb, hue);
+                assert!(rgb.contains(&max));
+            }
+        }
+
+        // Values pushed past 1 by processing clamp rather than overflow
+        let rgb = HSVColor { hue: 30.0, sat: 1.0, val: 1.0001 }.to_rgb(8);
+        assert_eq!(rgb[0], 255);
+    }
+
+    #[test]
+    fn to_rgb_wraps_hue_around() {
+        let rgb = |hue| HSVColor { hue, sat: 1.0, val: 1.0 }.to_rgb(8);
+        assert_eq!(rgb(-60.0), [255, 0, 255]);
+        assert_eq!(rgb(0.0), [255, 0, 0]);
+        assert_eq!(rgb(360.0), [255, 0, 0]);
+        assert_eq!(rgb(720.0), [255, 0, 0]);
+        assert_eq!(rgb(359.999), [255, 0, 0]);
+        assert_eq!(rgb(-f32::EPSILON), [255, 0, 0]);
+    }
+
+    #[test]
+    fn from_rgb_hue_is_never_negative() {
+        let hsv = HSVColor::from_rgb(200u8, 50, 120, 8);
+        assert!((0.0..360.0).contains(&hsv.hue), "hue {}", hsv.hue);
+
+        for rgb in [[200u8, 50, 120], [255, 0, 1], [90, 10, 80], [1, 0, 1]] {
+            let [r, g, b] = rgb;
+            let back = HSVColor::from_rgb(r, g, b, 8).to_rgb(8);
+            for (a, b) in back.iter().zip(rgb) {
+                assert!(a.abs_diff(b as u16) <= 1, "{:?} came back as {:?}", rgb, back);
+            }
+        }
+    }
+}
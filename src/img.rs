@@ -1,36 +1,507 @@
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::fmt;
 use crate::HSVColor;
+use crate::bmp;
+use crate::jpeg;
+use crate::ppm;
+use crate::tiff;
+use crate::webp;
+use crate::key::Seed;
+use crate::progress::Progress;
+use crate::stego::{self, Header, Payload, Plane};
 
-use rand::Rng;
+use png::text_metadata::{ITXtChunk, TEXtChunk, ZTXtChunk};
+use rand::{Rng, RngCore};
+use rand::seq::SliceRandom;
 use rand::SeedableRng;
 use rand_chacha::ChaCha20Rng;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
-pub fn read_image_rgb8(path: PathBuf) -> (u32, u32, Vec<u8>) {
-    let mut decoder = png::Decoder::new(File::open(path).expect("Input file not found"));
-    decoder.set_transformations(png::Transformations::normalize_to_color8());
-    let mut reader = decoder.read_info().expect("Image info failed to read");
+#[derive(Debug)]
+pub enum ImgError {
+    NotFound(PathBuf),
+    Decode(String),
+    SampleSize(usize),
+    DimensionMismatch,
+    // Cover and hidden image width and height
+    HiddenTooLarge((u32, u32), (u32, u32)),
+    BitDepth(u8, u8),
+    UniformBits,
+    PlaneOverlap(u8, u8),
+    Capacity(usize, usize),
+    NoPayload,
+    Authentication,
+    Format(String),
+    // Reading or writing failed other than for a missing input
+    Io(String),
+}
+
+impl fmt::Display for ImgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImgError::NotFound(path) => write!(f, "Input file not found: {}", path.display()),
+            ImgError::Decode(e) => write!(f, "Image failed to decode: {}", e),
+            ImgError::SampleSize(n) => write!(f, "Unexpected sample size: {}", n),
+            ImgError::DimensionMismatch => write!(f, "Image dimensions do not match"),
+            ImgError::HiddenTooLarge((c_w, c_h), (h_w, h_h)) =>
+                write!(f, "Cover is {}x{} but hidden is {}x{}", c_w, c_h, h_w, h_h),
+            ImgError::BitDepth(bits, depth) =>
+                write!(f, "Cannot use {} bits of a {}-bit image", bits, depth),
+            ImgError::UniformBits =>
+                write!(f, "Files and messages need the same bits in every channel"),
+            ImgError::PlaneOverlap(lo, hi) =>
+                write!(f, "Layer in bits {}-{} overlaps another payload", lo, hi),
+            ImgError::Capacity(needed, available) =>
+                write!(f, "Payload of {} bytes exceeds capacity of {} bytes", needed, available),
+            ImgError::NoPayload => write!(f, "No concealed payload found"),
+            ImgError::Authentication => write!(f, "Payload failed authentication"),
+            ImgError::Format(e) => write!(f, "Cannot write output: {}", e),
+            ImgError::Io(e) => write!(f, "Input/output failed: {}", e),
+        }
+    }
+}
+
+impl ImgError {
+    // An input at path failing to read, NotFound only when it is missing
+    pub fn io(path: &Path, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => ImgError::NotFound(path.to_path_buf()),
+            _ => ImgError::Io(format!("{}: {}", path.display(), e)),
+        }
+    }
+}
+
+impl std::error::Error for ImgError {}
+
+impl From<png::DecodingError> for ImgError {
+    fn from(e: png::DecodingError) -> Self {
+        ImgError::Decode(e.to_string())
+    }
+}
+
+impl From<png::EncodingError> for ImgError {
+    fn from(e: png::EncodingError) -> Self {
+        match e {
+            png::EncodingError::IoError(e) => ImgError::from(e),
+            e => ImgError::Format(e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for ImgError {
+    fn from(e: io::Error) -> Self {
+        ImgError::Io(e.to_string())
+    }
+}
+
+impl From<jpeg_decoder::Error> for ImgError {
+    fn from(e: jpeg_decoder::Error) -> Self {
+        ImgError::Decode(e.to_string())
+    }
+}
+
+// A color sample of 8 or 16 bits
+pub trait Sample: Copy + Into<f32> + Send + Sync {
+    const DEPTH: u8;
+    const MAX: u32 = (1 << Self::DEPTH) - 1;
+
+    fn from_u32(v: u32) -> Self;
+    fn to_u32(self) -> u32;
+
+    // Mask of the low bits of a sample, none for 0 and every bit from the
+    // depth up
+    fn low_mask(bits: u8) -> u32 {
+        Self::MAX >> Self::DEPTH.saturating_sub(bits)
+    }
+
+    // Convert a sample of the given depth to this one
+    fn from_depth(v: u32, depth: u8) -> Self {
+        if depth >= Self::DEPTH {
+            Self::from_u32(v >> (depth - Self::DEPTH))
+        } else {
+            Self::from_u32(v * Self::MAX / ((1 << depth) - 1))
+        }
+    }
+
+    // XOR each sample with the masked keystream word of the same index
+    fn xor_words(buf: &mut [Self], words: &[u32], mask: u32) {
+        for (x, w) in buf.iter_mut().zip(words) {
+            *x = Self::from_u32(x.to_u32() ^ w & mask);
+        }
+    }
+}
+
+impl Sample for u8 {
+    const DEPTH: u8 = 8;
+
+    fn from_u32(v: u32) -> Self { v as u8 }
+    fn to_u32(self) -> u32 { self as u32 }
+
+    // Eight samples to a u64 word, the tail one at a time
+    fn xor_words(buf: &mut [u8], words: &[u32], mask: u32) {
+        let mask = u64::from_ne_bytes([mask as u8; 8]);
+        let (mut chunks, mut keys) = (buf.chunks_exact_mut(8), words.chunks_exact(8));
+        for (c, k) in (&mut chunks).zip(&mut keys) {
+            let key = u64::from_ne_bytes(std::array::from_fn(|j| k[j] as u8));
+            let x = u64::from_ne_bytes((&*c).try_into().unwrap()) ^ key & mask;
+            c.copy_from_slice(&x.to_ne_bytes());
+        }
+        for (x, w) in chunks.into_remainder().iter_mut().zip(keys.remainder()) {
+            *x ^= (w & mask as u32) as u8;
+        }
+    }
+}
+
+impl Sample for u16 {
+    const DEPTH: u8 = 16;
+
+    fn from_u32(v: u32) -> Self { v as u16 }
+    fn to_u32(self) -> u32 { self as u32 }
+
+    // Four samples to a u64 word, the tail one at a time
+    fn xor_words(buf: &mut [u16], words: &[u32], mask: u32) {
+        let mask = (mask & 0xFFFF) as u64 * 0x0001_0001_0001_0001;
+        let (mut chunks, mut keys) = (buf.chunks_exact_mut(4), words.chunks_exact(4));
+        for (c, k) in (&mut chunks).zip(&mut keys) {
+            let x = c.iter().rev().fold(0u64, |x, &c| x << 16 | c as u64);
+            let key = k.iter().rev().fold(0u64, |key, &w| key << 16 | (w & 0xFFFF) as u64);
+            let x = x ^ key & mask;
+            c.iter_mut().enumerate().for_each(|(j, c)| *c = (x >> (16 * j)) as u16);
+        }
+        for (x, w) in chunks.into_remainder().iter_mut().zip(keys.remainder()) {
+            *x ^= (w & mask as u32) as u16;
+        }
+    }
+}
+
+// Decoded samples at the bit depth of the source
+pub enum Samples {
+    Eight(Vec<u8>),
+    Sixteen(Vec<u16>),
+}
+
+impl Samples {
+    pub fn depth(&self) -> u8 {
+        match self {
+            Samples::Eight(_) => 8,
+            Samples::Sixteen(_) => 16,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Samples::Eight(b) => b.len(),
+            Samples::Sixteen(b) => b.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_depth<T: Sample>(self) -> Vec<T> {
+        match self {
+            Samples::Eight(b) => b.into_iter().map(|v| T::from_depth(v as u32, 8)).collect(),
+            Samples::Sixteen(b) => b.into_iter().map(|v| T::from_depth(v as u32, 16)).collect(),
+        }
+    }
+}
+
+// Width, height, raw samples and samples per pixel
+pub type Raw = (u32, u32, Samples, usize);
+
+// Width, height, RGB samples and a separate alpha channel
+pub type Rgba<T> = (u32, u32, Vec<T>, Option<Vec<T>>);
+
+pub fn read_image_rgb8(path: PathBuf, bkgd: [u8; 3]) -> Result<(u32, u32, Vec<u8>), ImgError> {
+    read_image(path, bkgd)
+}
+
+pub fn read_image_rgba8(path: PathBuf) -> Result<Rgba<u8>, ImgError> {
+    read_image_rgba(path)
+}
+
+// Read as RGB at the depth of T, compositing alpha onto the background
+pub fn read_image<T: Sample>(path: PathBuf, bkgd: [u8; 3]) -> Result<(u32, u32, Vec<T>), ImgError> {
+    read_image_as(path, bkgd, AlphaMode::Composite)
+}
+
+// Like read_image, handling alpha as mode says
+pub fn read_image_as<T: Sample>(path: PathBuf, bkgd: [u8; 3], mode: AlphaMode)
+        -> Result<(u32, u32, Vec<T>), ImgError> {
+    let (width, height, buf, samples) = read_samples(path)?;
+    Ok((width, height, expand_rgb(&buf.into_depth(), samples, bkgd, mode)?))
+}
+
+// Like read_image, but the alpha channel is split off instead of
+// composited, None if the source has no alpha
+pub fn read_image_rgba<T: Sample>(path: PathBuf) -> Result<Rgba<T>, ImgError> {
+    let (width, height, buf, samples) = read_samples(path)?;
+    let (buf, alpha) = split_alpha(buf.into_depth(), samples)?;
+    Ok((width, height, buf, alpha))
+}
+
+// Timing of an animated PNG, with each frame's delay as a fraction of a second
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    pub plays: u32,
+    pub delays: Vec<(u16, u16)>,
+}
+
+// Ancillary PNG chunks carried from the source to the output, so it says no
+// more about its processing than the source did. Empty for other formats.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub gamma: Option<png::ScaledFloat>,
+    pub chromaticities: Option<png::SourceChromaticities>,
+    pub srgb: Option<png::SrgbRenderingIntent>,
+    pub icc_profile: Option<Vec<u8>>,
+    pub pixel_dims: Option<png::PixelDimensions>,
+    pub exif: Option<Vec<u8>>,
+    pub text: Vec<TEXtChunk>,
+    pub compressed_text: Vec<ZTXtChunk>,
+    pub utf8_text: Vec<ITXtChunk>,
+}
+
+impl Metadata {
+    fn from_info(info: &png::Info) -> Self {
+        Metadata {
+            gamma: info.gama_chunk,
+            chromaticities: info.chrm_chunk,
+            srgb: info.srgb,
+            icc_profile: info.icc_profile.as_deref().map(<[u8]>::to_vec),
+            pixel_dims: info.pixel_dims,
+            exif: info.exif_metadata.as_deref().map(<[u8]>::to_vec),
+            text: info.uncompressed_latin1_text.clone(),
+            compressed_text: info.compressed_latin1_text.clone(),
+            utf8_text: info.utf8_text.clone(),
+        }
+    }
+
+    // Into the info an encoder starts from
+    fn apply(&self, info: &mut png::Info) {
+        info.source_gamma = self.gamma;
+        info.source_chromaticities = self.chromaticities;
+        info.srgb = self.srgb;
+        info.icc_profile = self.icc_profile.clone().map(Cow::Owned);
+        info.pixel_dims = self.pixel_dims;
+        info.exif_metadata = self.exif.clone().map(Cow::Owned);
+        info.uncompressed_latin1_text = self.text.clone();
+        info.compressed_latin1_text = self.compressed_text.clone();
+        info.utf8_text = self.utf8_text.clone();
+    }
+}
+
+pub(crate) fn open(path: PathBuf) -> Result<BufReader<Box<dyn Read>>, ImgError> {
+    let file: Box<dyn Read> = if path.as_os_str() == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(&path).map_err(|e| ImgError::io(&path, e))?)
+    };
+    Ok(BufReader::new(file))
+}
+
+// Output file at path, or stdout when it is -
+pub(crate) fn create(path: PathBuf) -> Result<Box<dyn Write>, ImgError> {
+    Ok(if path.as_os_str() == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        let file = File::create(&path)
+            .map_err(|e| ImgError::Io(format!("{}: {}", path.display(), e)))?;
+        Box::new(file)
+    })
+}
+
+// Decode the raw samples of any supported format, from stdin when the path is -
+pub fn read_samples(path: PathBuf) -> Result<Raw, ImgError> {
+    decode(open(path)?)
+}
+
+// Decode every frame of an animated PNG, or the single image of any other
+// input, alongside its metadata. Frames must cover the whole image, and are
+// taken as stored rather than blended over the frame before.
+pub fn read_frames(path: PathBuf) -> Result<(Vec<Raw>, Option<Animation>, Metadata), ImgError> {
+    let mut r = open(path)?;
+    let magic = r.fill_buf().map_err(|e| ImgError::Decode(e.to_string()))?;
+    if !magic.starts_with(&[0x89, b'P', b'N', b'G']) {
+        return Ok((vec![decode(r)?], None, Metadata::default()));
+    }
+
+    let mut decoder = png::Decoder::new(r);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let Some(control) = reader.info().animation_control else {
+        let frame = png_frame(&mut reader)?;
+        return Ok((vec![frame], None, Metadata::from_info(reader.info())));
+    };
+
+    // A default image outside the animation is only there for viewers
+    // without APNG support
+    if reader.info().frame_control.is_none() {
+        png_frame(&mut reader)?;
+    }
+
+    let (width, height) = reader.info().size();
+    let (mut frames, mut delays) = (Vec::new(), Vec::new());
+    for _ in 0..control.num_frames {
+        let frame = png_frame(&mut reader)?;
+        if (frame.0, frame.1) != (width, height) {
+            return Err(ImgError::Decode("APNG frames must cover the whole image".to_string()));
+        }
+
+        let control = reader.info().frame_control.unwrap_or_default();
+        delays.push((control.delay_num, control.delay_den));
+        frames.push(frame);
+    }
+
+    let animation = Animation { plays: control.num_plays, delays };
+    Ok((frames, Some(animation), Metadata::from_info(reader.info())))
+}
+
+// The metadata of a PNG alone, empty for other formats. Stdin is left for
+// the image itself to be read from.
+pub fn read_metadata(path: PathBuf) -> Result<Metadata, ImgError> {
+    if path.as_os_str() == "-" {
+        return Ok(Metadata::default());
+    }
+
+    let mut r = open(path)?;
+    let magic = r.fill_buf().map_err(|e| ImgError::Decode(e.to_string()))?;
+    if !magic.starts_with(&[0x89, b'P', b'N', b'G']) {
+        return Ok(Metadata::default());
+    }
+    Ok(Metadata::from_info(png::Decoder::new(r).read_info()?.info()))
+}
+
+fn decode<R: BufRead>(mut r: R) -> Result<Raw, ImgError> {
+    // Sniff the signature, anything unrecognized goes to the PNG decoder
+    let magic = r.fill_buf().map_err(|e| ImgError::Decode(e.to_string()))?;
+
+    let handler = FORMATS.iter()
+        .find(|f| f.signatures.iter().any(|s| magic.starts_with(s)))
+        .unwrap_or(&FORMATS[0]);
+    check_size((handler.read)(&mut r)?)
+}
+
+// Refuse decoded samples that don't fill the image exactly, as a truncated
+// or corrupt file would otherwise read as a shifted image
+fn check_size(raw: Raw) -> Result<Raw, ImgError> {
+    let (width, height, ref buf, samples) = raw;
+    let expected = width as usize * height as usize * samples;
+    if buf.len() != expected {
+        return Err(ImgError::Decode(format!("{}x{} image has {} samples, expected {}",
+            width, height, buf.len(), expected)));
+    }
+    Ok(raw)
+}
+
+// PNGs are read with the EXPAND transformation alone. Palettes become RGB,
+// or RGBA where a tRNS chunk gives their entries alpha, entries past its end
+// being opaque. Gray below 8 bits is scaled up to 8, and a tRNS color of a
+// gray or RGB image becomes an alpha channel. 16-bit samples are kept intact.
+fn read_png<R: Read>(r: R) -> Result<Raw, ImgError> {
+    let mut decoder = png::Decoder::new(r);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    png_frame(&mut decoder.read_info()?)
+}
+
+// Decode the next frame, the only one of a plain PNG. Samples per pixel are
+// those of the expanded output rather than of the stored color type.
+fn png_frame<R: Read>(reader: &mut png::Reader<R>) -> Result<Raw, ImgError> {
     let mut buf = vec![0; reader.output_buffer_size()];
-    let info = reader.next_frame(&mut buf).expect("Image data failed to read");
-    let samples = info.color_type.samples();
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+
+    let (color, depth) = reader.output_color_type();
+    if color == png::ColorType::Indexed {
+        return Err(ImgError::Decode("PNG palette was not expanded".to_string()));
+    }
+    let buf = match depth {
+        png::BitDepth::Sixteen => Samples::Sixteen(
+            buf.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect()
+        ),
+        _ => Samples::Eight(buf),
+    };
 
-    let x = |b: u8, f: u8, a: u8| {
-        let a = a as u16;
-        let max = u8::MAX as u16;
-        let f = f as u16 * a / max;
-        let b = b as u16 * (max - a) / max;
-        (f + b) as u8
+    check_size((info.width, info.height, buf, color.samples()))
+}
+
+fn read_jpeg<R: Read>(r: R) -> Result<Raw, ImgError> {
+    let mut decoder = jpeg_decoder::Decoder::new(r);
+    let buf = decoder.decode()?;
+    let info = decoder.info().ok_or(ImgError::Decode("Missing JPEG info".to_string()))?;
+
+    let (buf, samples) = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => (Samples::Eight(buf), 1),
+        jpeg_decoder::PixelFormat::L16 => (Samples::Sixteen(
+            buf.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect()
+        ), 1),
+        jpeg_decoder::PixelFormat::RGB24 => (Samples::Eight(buf), 3),
+        jpeg_decoder::PixelFormat::CMYK32 => (Samples::Eight(
+            buf.chunks_exact(4).flat_map(|s| {
+                let k = s[3] as u16;
+                s[..3].iter().map(move |c| (*c as u16 * k / u8::MAX as u16) as u8)
+            }).collect()
+        ), 3),
     };
 
-    let bkgd = [0, 0, 0];
+    Ok((info.width as u32, info.height as u32, buf, samples))
+}
+
+// Split the trailing alpha sample off gray+alpha and RGBA pixels
+pub fn split_alpha<T: Sample>(buf: Vec<T>, samples: usize)
+        -> Result<(Vec<T>, Option<Vec<T>>), ImgError> {
+    match samples {
+        2 | 4 => {
+            let color: Vec<T> = buf.chunks_exact(samples)
+                .flat_map(|s| &s[..samples - 1]).copied().collect();
+            let alpha = buf.chunks_exact(samples).map(|s| s[samples - 1]).collect();
+            Ok((expand_rgb(&color, samples - 1, [0, 0, 0], AlphaMode::Composite)?, Some(alpha)))
+        },
+        _ => Ok((expand_rgb(&buf, samples, [0, 0, 0], AlphaMode::Composite)?, None)),
+    }
+}
+
+// How the alpha channel of a source is turned into plain RGB
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum AlphaMode {
+    /// Blend straight alpha colors onto the background
+    Composite,
+    /// Blend colors already multiplied by their alpha onto the background
+    Premultiplied,
+    /// Drop the alpha and keep the colors as stored
+    Ignore,
+}
+
+// Expand 1-4 sample pixels to RGB, turning alpha into color per mode
+pub fn expand_rgb<T: Sample>(buf: &[T], samples: usize, bkgd: [u8; 3], mode: AlphaMode)
+        -> Result<Vec<T>, ImgError> {
+    if !(1..=4).contains(&samples) {
+        return Err(ImgError::SampleSize(samples));
+    }
+
+    let x = |b: u8, f: T, a: T| {
+        let (a, max) = (a.to_u32(), T::MAX);
+        let b = T::from_depth(b as u32, 8).to_u32() * (max - a) / max;
+        match mode {
+            AlphaMode::Composite => T::from_u32(f.to_u32() * a / max + b),
+            AlphaMode::Premultiplied => T::from_u32((f.to_u32() + b).min(max)),
+            AlphaMode::Ignore => f,
+        }
+    };
 
-    (info.width, info.height, buf.chunks_exact(samples).flat_map(|s|
+    Ok(buf.chunks_exact(samples).flat_map(|s|
         match s.len() {
             1 => [s[0], s[0], s[0]],
             2 => {
-                let g = x(0, s[0], s[1]);
-                [g, g, g]
+                let r = x(bkgd[0], s[0], s[1]);
+                let g = x(bkgd[1], s[0], s[1]);
+                let b = x(bkgd[2], s[0], s[1]);
+                [r, g, b]
             },
             3 => [s[0], s[1], s[2]],
             4 => {
@@ -39,95 +510,1842 @@ pub fn read_image_rgb8(path: PathBuf) -> (u32, u32, Vec<u8>) {
                 let b = x(bkgd[2], s[2], s[3]);
                 [r, g, b]
             },
-            _ => panic!("Unexpected sample size"),
+            _ => unreachable!(),
         }
     ).collect())
 }
 
-pub fn stretch(buf: &mut [u8]) {
-    let maxx: u8 = u8::MAX;
+// Apply f to each chunk of n samples, in parallel with the rayon feature.
+// Chunks are handed out in runs of about a percent of the buffer, ticking
+// the progress of the pass named by label after each.
+fn for_chunks<T: Sample>(buf: &mut [T], n: usize, label: &'static str,
+        f: impl Fn(&mut [T]) + Send + Sync) {
+    let progress = Progress::new(label, buf.len());
+    let run = n * (buf.len() / n / 100).max(1);
+    let f = |run: &mut [T]| {
+        run.chunks_exact_mut(n).for_each(&f);
+        progress.tick(run.len());
+    };
+
+    #[cfg(feature = "rayon")]
+    buf.par_chunks_mut(run).for_each(f);
+    #[cfg(not(feature = "rayon"))]
+    buf.chunks_mut(run).for_each(f);
+}
+
+// Apply f to each block of up to n samples along with its index
+fn for_blocks<T: Sample>(buf: &mut [T], n: usize, label: &'static str,
+        f: impl Fn(usize, &mut [T]) + Send + Sync) {
+    let progress = Progress::new(label, buf.len());
+    let f = |(i, b): (usize, &mut [T])| {
+        f(i, b);
+        progress.tick(b.len());
+    };
+
+    #[cfg(feature = "rayon")]
+    buf.par_chunks_mut(n).enumerate().for_each(f);
+    #[cfg(not(feature = "rayon"))]
+    buf.chunks_mut(n).enumerate().for_each(f);
+}
+
+// First value at which the running total of counts exceeds cut
+fn cut_point(counts: impl Iterator<Item = usize>, cut: usize) -> u32 {
+    counts.scan(0, |total, c| { *total += c; Some(*total) })
+        .position(|total| total > cut)
+        .unwrap_or(0) as u32
+}
+
+// Range spanned by the values clip percent in from either end of their histogram
+fn clip_range(values: impl Iterator<Item = u32>, max: u32, clip: f32) -> (u32, u32) {
+    let mut hist = vec![0usize; max as usize + 1];
+    for v in values {
+        hist[v as usize] += 1;
+    }
+
+    let cut = (hist.iter().sum::<usize>() as f32 * clip / 100.0) as usize;
+    (cut_point(hist.iter().copied(), cut), max - cut_point(hist.iter().rev().copied(), cut))
+}
+
+// Rec. 601 luma of a pixel
+fn luma<T: Sample>(p: &[T]) -> u32 {
+    (299 * p[0].to_u32() + 587 * p[1].to_u32() + 114 * p[2].to_u32()) / 1000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum StretchMode {
+    /// Stretch R, G and B independently, which can shift the color balance
+    Channel,
+    /// Scale all channels by one factor from the luma, preserving hue
+    Luma,
+}
+
+// Stretches the values clip percent in from either end of the histogram to
+// span the full range, clamping those beyond them. In Channel mode each of
+// R, G and B maps its own low end to 0 and high end to the maximum, and a
+// flat channel, all black included, is left exactly as it is rather than
+// pushed to either end, so it can't tint the others.
+pub fn stretch<T: Sample>(buf: &mut [T], clip: f32, mode: StretchMode) {
+    let maxx = T::MAX;
+
+    if mode == StretchMode::Luma {
+        let (min, max) = clip_range(buf.chunks_exact(3).map(luma), maxx, clip);
+        if max <= min {
+            return;
+        }
+
+        for_chunks(buf, 3, "stretch", |p| {
+            let y = luma(p);
+            if y == 0 {
+                return;
+            }
+
+            let target = (y.clamp(min, max) - min) as f32 * maxx as f32 / (max - min) as f32;
+            for c in p.iter_mut() {
+                *c = T::from_u32(((c.to_u32() as f32 * target / y as f32).round() as u32).min(maxx));
+            }
+        });
+        return;
+    }
+
+    let ranges: [(u32, u32); 3] = std::array::from_fn(|k| {
+        clip_range(buf.chunks_exact(3).map(|p| p[k].to_u32()), maxx, clip)
+    });
+
+    for_chunks(buf, 3, "stretch", |p| {
+        for (c, &(min, max)) in p.iter_mut().zip(&ranges) {
+            // A flat channel has no range to stretch and keeps its value
+            if max <= min {
+                continue;
+            }
 
-    let minmaxs = buf.chunks_exact(3).fold(
-        vec![(maxx, 0u8); 3],
-        |minmaxs, p| {
-            let b = p.iter().zip(minmaxs);
-            b.map(|(p, (min, max))| (min.min(*p), max.max(*p))).collect()
+            let v = c.to_u32().clamp(min, max) - min;
+            *c = T::from_u32((v as u64 * maxx as u64 / (max - min) as u64) as u32);
         }
-    );
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum EqualizeChannel {
+    /// HSV value, the brightness
+    Value,
+    /// HSV saturation
+    Sat,
+}
 
-    for p in buf.chunks_exact_mut(3) {
-        for (c, (min, max)) in p.iter_mut()
-                                .zip(minmaxs.iter()) {
-            let new = if *max == 0 {0}
-            else {(*c - *min) as u16 * maxx as u16 / (*max - *min) as u16};
-            *c = new as u8;
+impl EqualizeChannel {
+    fn field(self, hsv: &mut HSVColor) -> &mut f32 {
+        match self {
+            EqualizeChannel::Value => &mut hsv.val,
+            EqualizeChannel::Sat => &mut hsv.sat,
         }
     }
 }
 
-pub fn equalize(buf: &mut [u8]) {
-    // Convert image to HSV color
-    let mut hsvs: Vec<HSVColor> = 
-        buf.chunks_exact(3).map(|p| {
-            HSVColor::from_rgb(p[0], p[1], p[2])
-        }).collect();
+// The channel is binned at the sample depth, which for the HSV value, the
+// largest sample of a pixel, gives a bin for every sample value. Each bin
+// present maps to its rank among them in [0, 1], spreading them evenly over
+// the range.
+pub fn equalize<T: Sample>(buf: &mut [T], channel: EqualizeChannel) {
+    let hsv = |p: &[T]| HSVColor::from_rgb(p[0], p[1], p[2], T::DEPTH);
+    let bin = |v: f32| (v * T::MAX as f32).round() as usize;
 
-    // Create a sorted vector of unique values for the CDF
-    let mut vals: Vec<f32> = hsvs.iter().map(|hsv| hsv.val).collect();
-    vals.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    vals.dedup();
+    let mut hist = vec![0usize; T::MAX as usize + 1];
+    for p in buf.chunks_exact(3) {
+        hist[bin(*channel.field(&mut hsv(p)))] += 1;
+    }
 
-    // Define the CDF with range [0, 1]
-    let cdf = |v| vals.binary_search_by(|a|
-        a.partial_cmp(&v).unwrap()
-    ).unwrap() as f32 / (vals.len() - 1) as f32;
+    // Define the CDF with range [0, 1] as a lookup table
+    let levels = hist.iter().filter(|n| **n > 0).count().max(2) - 1;
+    let cdf: Vec<f32> = hist.iter()
+        .scan(0, |rank, n| {
+            let v = *rank as f32 / levels as f32;
+            *rank += (*n > 0) as usize;
+            Some(v)
+        })
+        .collect();
 
     // Equalize and convert back to RGB
-    for (p, hsv) in buf.chunks_exact_mut(3).zip(hsvs.iter_mut()) {
-        hsv.val = cdf(hsv.val);
-        for (c, new) in p.iter_mut().zip(hsv.to_rgb()) {
-            *c = new;
+    for_chunks(buf, 3, "equalize", |p| {
+        let mut hsv = hsv(p);
+        let v = channel.field(&mut hsv);
+        *v = cdf[bin(*v)];
+        for (c, new) in p.iter_mut().zip(hsv.to_rgb(T::DEPTH)) {
+            *c = T::from_u32(new as u32);
+        }
+    });
+}
+
+// Color grading pass, rotating hue by hue_shift degrees and scaling
+// saturation and value, both clamped to [0, 1]
+pub fn adjust_hsv<T: Sample>(buf: &mut [T], hue_shift: f32, sat_mul: f32, val_mul: f32) {
+    for_chunks(buf, 3, "adjust", |p| {
+        let mut hsv = HSVColor::from_rgb(p[0], p[1], p[2], T::DEPTH);
+
+        // to_rgb wraps the hue back into range
+        hsv.hue += hue_shift;
+        hsv.sat = (hsv.sat * sat_mul).clamp(0.0, 1.0);
+        hsv.val = (hsv.val * val_mul).clamp(0.0, 1.0);
+
+        for (c, new) in p.iter_mut().zip(hsv.to_rgb(T::DEPTH)) {
+            *c = T::from_u32(new as u32);
+        }
+    });
+}
+
+// Gamma correction of every sample by a lookup table over all values
+pub fn gamma<T: Sample>(buf: &mut [T], value: f32) {
+    let max = T::MAX as f32;
+    let lut: Vec<T> = (0..=T::MAX)
+        .map(|v| T::from_u32(((v as f32 / max).powf(1.0 / value) * max).round() as u32))
+        .collect();
+
+    for_chunks(buf, 1, "gamma", |c| c[0] = lut[c[0].to_u32() as usize]);
+}
+
+// Photographic negative, flipping every sample within its range. Its own
+// inverse, and flipping the low bits too, so it can wrap embedding either way.
+pub fn invert<T: Sample>(buf: &mut [T]) {
+    for_chunks(buf, 1, "invert", |c| c[0] = T::from_u32(T::MAX - c[0].to_u32()));
+}
+
+// Round every sample to the nearest of levels evenly spaced over its full
+// range, unlike rescale_bits keeping the image as bright as it was. Fewer
+// than 2 levels are taken as 2, and as many levels as values change nothing.
+pub fn posterize<T: Sample>(buf: &mut [T], levels: u32) {
+    let (max, steps) = (T::MAX as u64, levels.max(2) as u64 - 1);
+    for_chunks(buf, 1, "posterize", |c| {
+        let level = (c[0].to_u32() as u64 * steps + max / 2) / max;
+        c[0] = T::from_u32(((level * max + steps / 2) / steps) as u32);
+    });
+}
+
+// Cut every sample down to the bits of its channel with Floyd-Steinberg
+// error diffusion, so gradients dither rather than band. Each level is chosen
+// as the closest once scaled back up to the full range, the way reveal
+// restores it.
+pub fn dither<T: Sample>(buf: &mut [T], width: u32, bits: [u8; 3]) {
+    let width = width as usize * 3;
+    let levels = bits.map(|b| ((1u32 << b) - 1) as f32);
+    if width == 0 {
+        return;
+    }
+
+    let max = T::MAX as f32;
+    let mut err = vec![0f32; buf.len()];
+
+    for i in 0..buf.len() {
+        let (v, levels) = ((buf[i].to_u32() as f32 + err[i]).clamp(0.0, max), levels[i % 3]);
+        if levels == 0.0 {
+            buf[i] = T::from_u32(0);
+            continue;
+        }
+        let q = (v * levels / max).round();
+        buf[i] = T::from_u32(q as u32);
+
+        // Spread the error right and over the row below, channel by channel
+        let e = v - q * max / levels;
+        let x = i % width;
+        let mut spread = |j: usize, w: f32| if j < err.len() { err[j] += e * w };
+        if x + 3 < width {
+            spread(i + 3, 7.0 / 16.0);
+            spread(i + width + 3, 1.0 / 16.0);
+        }
+        if x >= 3 {
+            spread(i + width - 3, 3.0 / 16.0);
         }
+        spread(i + width, 5.0 / 16.0);
+    }
+}
+
+// Bins of the per-tile histograms of adaptive equalization
+const TILE_BINS: usize = 256;
+
+// Contrast limited adaptive histogram equalization of the HSV value. The image
+// is split into a grid of tiles, each equalized by the CDF of its own
+// histogram with bins clipped at clip times the mean and the excess spread
+// over all of them. Each pixel blends the CDFs of the four nearest tile
+// centers so no seams show.
+pub fn equalize_adaptive<T: Sample>(buf: &mut [T], width: u32, height: u32, tiles: u32, clip: f32) {
+    let (width, height) = (width as usize, height as usize);
+    if width == 0 || height == 0 {
+        return;
     }
+
+    let bin = |p: &[T]| {
+        let v = p[0].to_u32().max(p[1].to_u32()).max(p[2].to_u32()) as usize;
+        v * TILE_BINS / (T::MAX as usize + 1)
+    };
+
+    let (t_width, t_height) = (width.div_ceil(tiles as usize), height.div_ceil(tiles as usize));
+    let (nx, ny) = (width.div_ceil(t_width), height.div_ceil(t_height));
+
+    // Clipped CDF of every tile, row by row
+    let cdfs: Vec<Vec<f32>> = (0..nx * ny).map(|t| {
+        let (tx, ty) = (t % nx * t_width, t / nx * t_height);
+        let mut hist = vec![0f32; TILE_BINS];
+        let mut n = 0;
+        for row in buf.chunks_exact(width * 3).skip(ty).take(t_height) {
+            for p in row[tx * 3..((tx + t_width).min(width)) * 3].chunks_exact(3) {
+                hist[bin(p)] += 1.0;
+                n += 1;
+            }
+        }
+
+        let limit = (clip * n as f32 / TILE_BINS as f32).max(1.0);
+        let excess: f32 = hist.iter_mut().map(|h| {
+            let e = (*h - limit).max(0.0);
+            *h -= e;
+            e
+        }).sum();
+        hist.iter_mut().for_each(|h| *h += excess / TILE_BINS as f32);
+
+        hist.iter().scan(0.0, |total, h| { *total += h; Some(*total / n as f32) }).collect()
+    }).collect();
+
+    // Neighbouring tile indices of a coordinate and the weight of the second
+    let nearest = |c: usize, size: usize, n: usize| {
+        let f = ((c as f32 + 0.5) / size as f32 - 0.5).clamp(0.0, (n - 1) as f32);
+        let i = f as usize;
+        (i, (i + 1).min(n - 1), f - i as f32)
+    };
+
+    for_blocks(buf, width * 3, "equalize", |y, row| {
+        let (y0, y1, wy) = nearest(y, t_height, ny);
+
+        for (x, p) in row.chunks_exact_mut(3).enumerate() {
+            let (x0, x1, wx) = nearest(x, t_width, nx);
+            let b = bin(p);
+            let cdf = |tx: usize, ty: usize| cdfs[ty * nx + tx][b];
+
+            let mut hsv = HSVColor::from_rgb(p[0], p[1], p[2], T::DEPTH);
+            hsv.val = (cdf(x0, y0) * (1.0 - wx) + cdf(x1, y0) * wx) * (1.0 - wy)
+                + (cdf(x0, y1) * (1.0 - wx) + cdf(x1, y1) * wx) * wy;
+            for (c, new) in p.iter_mut().zip(hsv.to_rgb(T::DEPTH)) {
+                *c = T::from_u32(new as u32);
+            }
+        }
+    });
+}
+
+// Samples ciphered per seek of the keystream
+const CIPHER_BLOCK: usize = 4096;
+
+pub fn stream_cipher<T: Sample>(buf: &mut [T], key: Seed, bits: u8) {
+    stream_cipher_at(buf, key, [bits; 3], 0)
+}
+
+// Cipher of RGB samples carrying a different number of bits in each channel
+pub fn stream_cipher_rgb<T: Sample>(buf: &mut [T], key: Seed, bits: [u8; 3]) {
+    stream_cipher_at(buf, key, bits, 0)
 }
 
-pub fn stream_cipher(buf: &mut [u8], key: u64, bits: u8) {
-    // Seed PRNG with key
-    let mut rng = ChaCha20Rng::seed_from_u64(key);
+// Cipher samples that sit at index start onwards of the full buffer. The mask
+// of sample i is the low bits of word i of the keystream, so any range can be
+// ciphered on its own. With the same bits in every channel the words are
+// XORed in a u64 at a time.
+pub fn stream_cipher_at<T: Sample>(buf: &mut [T], key: Seed, bits: [u8; 3], start: usize) {
+    let masks = bits.map(T::low_mask);
 
-    let max: u8 = u8::MAX >> (8 - bits);
+    for_blocks(buf, CIPHER_BLOCK, "cipher", |i, block| {
+        // Seed PRNG with key and seek to the first sample of the block
+        let mut rng = ChaCha20Rng::from_seed(key);
+        let first = start + i * CIPHER_BLOCK;
+        rng.set_word_pos(first as u128);
 
-    // XOR each pixel with the stream
-    for x in buf.iter_mut() {
-        *x ^= rng.gen_range(0..=max);
+        // XOR each pixel with the stream
+        if masks.iter().all(|&m| m == masks[0]) {
+            let mut words = vec![0u32; block.len()];
+            rng.fill(&mut words[..]);
+            T::xor_words(block, &words, masks[0]);
+        } else {
+            for (j, x) in block.iter_mut().enumerate() {
+                *x = T::from_u32(x.to_u32() ^ rng.next_u32() & masks[(first + j) % 3]);
+            }
+        }
+    });
+}
+
+// Moves the low bits of each of R, G and B to key derived positions among
+// them, the same in every pixel, or moves them back with inverse. The order
+// is drawn from a third stream of the cipher's seed, and the bits above are
+// left alone.
+pub fn permute_planes<T: Sample>(buf: &mut [T], key: Seed, bits: [u8; 3], inverse: bool) {
+    let mut rng = ChaCha20Rng::from_seed(key);
+    rng.set_stream(2);
+    let orders: [Vec<u8>; 3] = bits.map(|b| {
+        let mut order: Vec<u8> = (0..b).collect();
+        order.shuffle(&mut rng);
+        if inverse {
+            let mut back = vec![0; order.len()];
+            order.iter().enumerate().for_each(|(from, &to)| back[to as usize] = from as u8);
+            order = back;
+        }
+        order
+    });
+    let masks = bits.map(T::low_mask);
+
+    for_chunks(buf, 3, "permute", |p| {
+        for (k, c) in p.iter_mut().enumerate() {
+            let v = c.to_u32();
+            let moved = orders[k].iter().enumerate()
+                .fold(0, |m, (from, &to)| m | (v >> from & 1) << to);
+            *c = T::from_u32(v & !masks[k] | moved);
+        }
+    });
+}
+
+// Splits samples, already cut down to their top bits, into n shares that
+// XOR back to them. All but the last are random, so any n - 1 of them say
+// nothing of the samples.
+pub fn split_shares<T: Sample>(buf: &[T], bits: [u8; 3], n: usize) -> Vec<Vec<T>> {
+    let masks = bits.map(T::low_mask);
+    let mut rng = rand::thread_rng();
+    let mut last = buf.to_vec();
+    let mut shares: Vec<Vec<T>> = (1..n).map(|_| {
+        last.iter_mut().enumerate().map(|(i, c)| {
+            let share = rng.next_u32() & masks[i % 3];
+            *c = T::from_u32(c.to_u32() ^ share);
+            T::from_u32(share)
+        }).collect()
+    }).collect();
+    shares.push(last);
+    shares
+}
+
+// XORs a share into samples, so folding every share of split_shares in
+// turn into the first gives back what was split
+pub fn combine_shares<T: Sample>(buf: &mut [T], share: &[T]) -> Result<(), ImgError> {
+    if buf.len() != share.len() {
+        return Err(ImgError::DimensionMismatch);
     }
+    for (c, s) in buf.iter_mut().zip(share) {
+        *c = T::from_u32(c.to_u32() ^ s.to_u32());
+    }
+    Ok(())
+}
+
+// Where conceal puts a hidden image smaller than the cover
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement {
+    TopLeft,
+    Center,
+    // Repeated to fill the whole cover
+    Tile,
+    // Pixels shuffled over the cover by the key
+    Scatter(Seed),
+    // Textured pixels alone, whose gradient reaches this many 8-bit levels
+    Adaptive(u32),
+}
+
+// Pixels of the cover past those holding reserved samples whose luma
+// gradient, over the bits from the given one up, reaches the threshold in
+// 8-bit levels. Embedding below those bits leaves the gradient as it was, so
+// reveal finds the same pixels in the stego image.
+pub fn textured_pixels<T: Sample>(buf: &[T], width: u32, bits: u8, reserved: usize,
+        threshold: u32) -> Vec<usize> {
+    let (width, pixels) = (width as usize, buf.len() / 3);
+    let height = pixels.checked_div(width).unwrap_or(0);
+    let threshold = threshold * T::MAX / 255;
+    let mask = if bits >= T::DEPTH { 0 } else { T::MAX >> bits << bits };
+    let high: Vec<T> = buf.iter().map(|c| T::from_u32(c.to_u32() & mask)).collect();
+    let lumas: Vec<u32> = high.chunks_exact(3).map(luma).collect();
+
+    // Differences to the next pixel right and down, or back from the edges
+    let at = |x: usize, y: usize| lumas[y * width + x];
+    let next = |i: usize, len: usize| if i + 1 < len { i + 1 } else { i.saturating_sub(1) };
+    (reserved.div_ceil(3).min(pixels)..pixels).filter(|i| {
+        let (x, y) = (i % width, i / width);
+        let dx = at(next(x, width), y).abs_diff(at(x, y));
+        let dy = at(x, next(y, height)).abs_diff(at(x, y));
+        dx + dy >= threshold
+    }).collect()
 }
 
-pub fn conceal(buf: &mut [u8], bits: u8, width: u32, height: u32, path: PathBuf) {
-    // Decode hidden image
-    let (i_width, i_height, i_buf) = read_image_rgb8(path);
+// Conceals the hidden image, with samples already cut down to their top bits,
+// in the low or high bits of the cover. Returns the stego image with the
+// cover's dimensions and alpha. A header records the size and offset of
+// the hidden image so reveal can cut it back out, followed by a checksum of
+// the hidden image's bits if asked for and the iterations of a stretched
+// password, and the stego image is tagged for authentication with the mac
+// key if given. A low plane image may sit offset bits up, layered over
+// another payload below it, with its header in those same bits. A cover too
+// small for the header and the rest is refused.
+#[allow(clippy::too_many_arguments)]
+pub fn conceal<T: Sample>(hidden: (u32, u32, &[T]), cover: Rgba<T>, bits: [u8; 3], plane: Plane,
+        offset: u8, placement: Placement, checksum: bool, kdf: Option<u32>, mac: Option<Seed>)
+        -> Result<Rgba<T>, ImgError> {
+    let (width, height, buf) = hidden;
+    let (c_width, c_height, mut c_buf, c_alpha) = cover;
 
     // Exit if hidden image is too large
-    if i_width != width || i_height != height {
-        panic!("Image dimensions do not match");
+    if width > c_width || height > c_height {
+        return Err(ImgError::HiddenTooLarge((c_width, c_height), (width, height)));
     }
 
-    let mask = u8::MAX << bits;
+    // Each of R, G and B is written over its own number of bits, in the low
+    // plane from offset up. The tag is kept in the lowest bits, so layers
+    // above them go unauthenticated.
+    let offset = if plane == Plane::High { 0 } else { offset };
+    let mac = mac.filter(|_| offset == 0);
+    let top = bits.iter().max().unwrap() + offset;
+    if top > T::DEPTH || bits == [0; 3] {
+        return Err(ImgError::BitDepth(top - offset, T::DEPTH));
+    }
+    let shifts = bits.map(|b| if plane == Plane::High { T::DEPTH - b } else { offset });
+    let masks: [u32; 3] = std::array::from_fn(|k| {
+        T::MAX & !(T::low_mask(bits[k]) << shifts[k])
+    });
+    let x = |p: &mut [T], i_p: &[T]| for (k, (c, i_c)) in p.iter_mut().zip(i_p).enumerate() {
+        *c = T::from_u32(c.to_u32() & masks[k] | i_c.to_u32() << shifts[k]);
+    };
+
+    let mut header = Header {
+        payload: Payload::Image { width, height, x: 0, y: 0 },
+        bits: stego::header_bits(bits),
+        channels: bits,
+        authenticated: mac.is_some(),
+        plane,
+        offset,
+        compressed: false,
+        ecc: false,
+        checksum,
+        kdf: kdf.is_some(),
+    };
+    let reserved = header.reserved_slots();
+
+    // A cover too small for even the header, such as an empty one, carries
+    // nothing
+    if reserved > c_buf.len() {
+        let bytes = |n: usize| n * header.bits as usize / 8;
+        return Err(ImgError::Capacity(bytes(reserved), bytes(c_buf.len())));
+    }
+
+    // Offset recorded for reveal. Of the tiles, the first down the left edge
+    // clear of the header is cut out where the cover has room for it.
+    let (o_x, o_y) = match placement {
+        Placement::Center => ((c_width - width) / 2, (c_height - height) / 2),
+        Placement::Tile if height > 0 => {
+            let rows = reserved.div_ceil(c_width as usize * 3) as u32;
+            let y = rows.div_ceil(height) * height;
+            if y + height <= c_height { (0, y) } else { (0, 0) }
+        },
+        _ => (0, 0),
+    };
+
+    let (w, h) = (width as usize, height as usize);
+    let order = pixel_order((c_width, c_height, &c_buf), top, reserved, plane, placement)?;
+    if let Some(order) = order {
+        if order.len() < w * h {
+            return Err(ImgError::DimensionMismatch);
+        }
+
+        for (p, i) in buf.chunks_exact(3).zip(order) {
+            x(&mut c_buf[i * 3..i * 3 + 3], p);
+        }
+    } else if w > 0 && h > 0 {
+        let tile = placement == Placement::Tile;
+        let (o_x, o_y) = (o_x as usize, o_y as usize);
+
+        for_blocks(&mut c_buf, c_width as usize * 3, "conceal", |y, row| {
+            let (i_y, x_range) = if tile {
+                (y % h, 0..row.len() / 3)
+            } else if (o_y..o_y + h).contains(&y) {
+                (y - o_y, o_x..o_x + w)
+            } else {
+                return;
+            };
+
+            let i_row = &buf[i_y * w * 3..][..w * 3];
+            for i in x_range {
+                let i_x = if tile { i % w } else { i - o_x };
+                x(&mut row[i * 3..i * 3 + 3], &i_row[i_x * 3..i_x * 3 + 3]);
+            }
+        });
+    }
+
+    header.payload = Payload::Image { width, height, x: o_x, y: o_y };
+    stego::write_header(&mut c_buf, &header);
+    if checksum {
+        let pixels = carrier_pixels((c_width, c_height, &c_buf), &header, placement)?;
+        stego::write_checksum(&mut c_buf, &header, &stego::pixel_samples(&pixels));
+    }
+    if let Some(iterations) = kdf {
+        stego::write_kdf(&mut c_buf, &header, iterations);
+    }
+    if let Some(mac) = mac {
+        stego::sign(&mut c_buf, header.bits, mac);
+    }
+
+    Ok((c_width, c_height, c_buf, c_alpha))
+}
+
+// Cover pixels the pixels of a hidden image go to in turn, for placements
+// that don't lay them out as a block. The texture adaptive placement looks
+// at is in the bits above top, so is the same before and after concealing.
+fn pixel_order<T: Sample>(cover: (u32, u32, &[T]), top: u8, reserved: usize, plane: Plane,
+        placement: Placement) -> Result<Option<Vec<usize>>, ImgError> {
+    let (c_width, c_height, c_buf) = cover;
+    Ok(match placement {
+        Placement::Scatter(key) => {
+            Some(stego::scatter_pixels(c_width as usize * c_height as usize, reserved, key))
+        },
+        Placement::Adaptive(_) if plane == Plane::High => {
+            return Err(ImgError::Format("adaptive embedding needs the low plane".to_string()));
+        },
+        Placement::Adaptive(threshold) => {
+            Some(textured_pixels(c_buf, c_width, top, reserved, threshold))
+        },
+        _ => None,
+    })
+}
+
+// Pixels of a stego image carrying the hidden image its header describes,
+// concealed with this placement
+pub fn carrier_pixels<T: Sample>(stego: (u32, u32, &[T]), header: &Header, placement: Placement)
+        -> Result<Vec<usize>, ImgError> {
+    let (c_width, c_height, _) = stego;
+    let Payload::Image { width, height, x, y } = header.payload else {
+        return Err(ImgError::NoPayload);
+    };
+    let (w, h, c_w) = (width as usize, height as usize, c_width as usize);
+
+    let top = header.channels.iter().max().unwrap() + header.shift();
+    match pixel_order(stego, top, header.reserved_slots(), header.plane, placement)? {
+        Some(order) => Ok(order.into_iter().take(w * h).collect()),
+        None if placement == Placement::Tile => Ok((0..c_w * c_height as usize).collect()),
+        None => Ok((0..w * h).map(|i| (y as usize + i / w) * c_w + x as usize + i % w).collect()),
+    }
+}
 
-    for (c, i_c) in buf.iter_mut().zip(i_buf.iter()) {
-        *c |= *i_c & mask;
+// Mean squared error between two images and the peak signal to noise ratio
+// in dB it gives, infinite when they are identical
+pub fn psnr<T: Sample>(a: &[T], b: &[T]) -> Result<(f64, f64), ImgError> {
+    if a.len() != b.len() {
+        return Err(ImgError::DimensionMismatch);
     }
+
+    let sum: f64 = a.iter().zip(b)
+        .map(|(a, b)| (a.to_u32() as f64 - b.to_u32() as f64).powi(2))
+        .sum();
+    let mse = sum / a.len().max(1) as f64;
+    Ok((mse, 10.0 * (T::MAX as f64 * T::MAX as f64 / mse).log10()))
 }
 
-pub fn write_image_rgb8(buf: &[u8], width: u32, height: u32, path: PathBuf) {
-    let file = File::create(path).expect("Failed to create output file");
-    let w = &mut BufWriter::new(file);
+// Absolute difference of each sample of two images, multiplied by gain so
+// changes to the low bits show up
+pub fn diff<T: Sample>(a: &[T], b: &[T], gain: u32) -> Result<Vec<T>, ImgError> {
+    if a.len() != b.len() {
+        return Err(ImgError::DimensionMismatch);
+    }
 
-    let mut encoder = png::Encoder::new(w, width, height);
-    encoder.set_color(png::ColorType::Rgb);
-    encoder.set_depth(png::BitDepth::Eight);
+    Ok(a.iter().zip(b)
+        .map(|(a, b)| a.to_u32().abs_diff(b.to_u32()).saturating_mul(gain).min(T::MAX))
+        .map(T::from_u32)
+        .collect())
+}
 
-    let mut writer = encoder.write_header().expect("Failed to write output header");
+// Conceals the top bits of a same-sized hidden image in the low bits of the
+// cover, with no header, entirely in memory. At 0 bits the cover is left as
+// is, and at the full depth it is replaced.
+pub fn conceal_buf<T: Sample>(cover: &mut [T], hidden: &[T], bits: u8) -> Result<(), ImgError> {
+    if cover.len() != hidden.len() {
+        return Err(ImgError::DimensionMismatch);
+    }
 
-    writer.write_image_data(buf).expect("Failed to write output data");
+    if bits > T::DEPTH {
+        return Err(ImgError::BitDepth(bits, T::DEPTH));
+    }
+
+    let mask = T::MAX & !T::low_mask(bits);
+    for (c, h) in cover.iter_mut().zip(hidden) {
+        *c = T::from_u32(c.to_u32() & mask | h.to_u32() >> (T::DEPTH - bits));
+    }
+
+    Ok(())
+}
+
+// Inverse of conceal_buf, scaling the low bits of each sample back up to the
+// full range
+pub fn reveal_buf<T: Sample>(stego: &[T], bits: u8) -> Vec<T> {
+    reveal_rgb(stego, [bits; 3])
+}
+
+// Reveal of RGB samples carrying a different number of bits in each channel
+pub fn reveal_rgb<T: Sample>(stego: &[T], bits: [u8; 3]) -> Vec<T> {
+    let mut buf = stego.to_vec();
+    rescale_bits(&mut buf, bits);
+    buf
+}
+
+// Maps the low bits of each of R, G and B onto the full range of the
+// sample, so at 1 bit 0 and 1 become black and white and at 4 bits each step
+// is 17 levels of an 8-bit image. The bits above are dropped, and a channel
+// of 0 bits is zeroed.
+pub fn rescale_bits<T: Sample>(buf: &mut [T], bits: [u8; 3]) {
+    rescale_bits_to(buf, bits, T::MAX);
+}
+
+// As rescale_bits, onto 0 to max instead. A max above that of the sample is
+// taken as the sample's.
+pub fn rescale_bits_to<T: Sample>(buf: &mut [T], bits: [u8; 3], max: u32) {
+    let (masks, max) = (bits.map(T::low_mask), max.min(T::MAX));
+    for (i, c) in buf.iter_mut().enumerate() {
+        let m = masks[i % 3];
+        *c = T::from_u32(((c.to_u32() & m) * max).checked_div(m).unwrap_or(0));
+    }
+}
+
+// Black and white image of one bit plane, white where the bit is set
+pub fn bit_plane<T: Sample>(buf: &[T], bit: u8) -> Vec<T> {
+    buf.iter().map(|c| T::from_u32(if c.to_u32() >> bit & 1 == 1 { T::MAX } else { 0 })).collect()
+}
+
+// Collect the pixels at the given indices, in order
+pub fn gather<T: Copy>(buf: &[T], samples: usize, indices: &[usize]) -> Vec<T> {
+    indices.iter().flat_map(|i| &buf[i * samples..(i + 1) * samples]).copied().collect()
+}
+
+// Nearest neighbor downscale of an image to the given width, keeping its
+// aspect ratio. Images no wider are returned as they are.
+pub fn downscale<T: Copy>(buf: &[T], samples: usize, width: u32, height: u32, to: u32)
+        -> (u32, u32, Vec<T>) {
+    if to >= width {
+        return (width, height, buf.to_vec());
+    }
+    let h = (height as u64 * to as u64).div_ceil(width as u64).max(1) as u32;
+
+    let (w_from, h_from, to_u, h_u) = (width as usize, height as usize, to as usize, h as usize);
+    let buf = (0..h_u).flat_map(|y| {
+        let row = y * h_from / h_u;
+        (0..to_u).flat_map(move |x| {
+            let i = (row * w_from + x * w_from / to_u) * samples;
+            &buf[i..i + samples]
+        })
+    }).copied().collect();
+    (to, h, buf)
+}
+
+// Cut a w by h rectangle at x, y out of an image with the given samples per pixel
+pub fn crop<T: Copy>(buf: &[T], samples: usize, width: u32, x: u32, y: u32, w: u32, h: u32) -> Vec<T> {
+    let (width, x, y, w, h) = (width as usize, x as usize, y as usize, w as usize, h as usize);
+
+    buf.chunks_exact(width * samples).skip(y).take(h)
+        .flat_map(|row| &row[x * samples..(x + w) * samples])
+        .copied()
+        .collect()
+}
+
+// Inverse of crop, writing a w pixel wide rectangle back at x, y
+pub fn paste<T: Copy>(buf: &mut [T], samples: usize, width: u32, x: u32, y: u32, w: u32, part: &[T]) {
+    let (width, x, y, w) = (width as usize, x as usize, y as usize, w as usize);
+    if w == 0 {
+        return;
+    }
+
+    let rows = buf.chunks_exact_mut(width * samples).skip(y);
+    for (row, p) in rows.zip(part.chunks_exact(w * samples)) {
+        row[x * samples..(x + w) * samples].copy_from_slice(p);
+    }
+}
+
+// Run f over each whole size by size block of an image, cut out on a grid
+// from the top left and pasted back once changed. Blocks part way off the
+// right or bottom edge are left alone.
+pub fn for_each_block<T: Copy>(buf: &mut [T], samples: usize, width: u32, height: u32, size: u32,
+        mut f: impl FnMut(&mut Vec<T>) -> Result<(), ImgError>) -> Result<(), ImgError> {
+    if size == 0 || size > width || size > height {
+        return Err(ImgError::HiddenTooLarge((width, height), (size, size)));
+    }
+
+    for y in (0..height / size).map(|b| b * size) {
+        for x in (0..width / size).map(|b| b * size) {
+            let mut block = crop(buf, samples, width, x, y, size, size);
+            f(&mut block)?;
+            paste(buf, samples, width, x, y, size, &block);
+        }
+    }
+    Ok(())
+}
+
+pub fn write_image_rgb8(buf: &[u8], alpha: Option<&[u8]>, width: u32, height: u32, path: PathBuf)
+        -> Result<(), ImgError> {
+    write_image(buf, alpha, width, height, path)
+}
+
+// Formats an image can be written as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Png,
+    Bmp,
+    Ppm,
+    Tiff,
+    Jpeg,
+    Webp,
+}
+
+// A format images are read in, found by its signatures, and written in when
+// it has a Format, found by its extensions
+pub struct Handler {
+    pub name: &'static str,
+    pub extensions: &'static [&'static str],
+    // Whether samples survive a round trip exactly, as a payload needs
+    pub lossless: bool,
+    pub write: Option<Format>,
+    signatures: &'static [&'static [u8]],
+    read: fn(&mut dyn BufRead) -> Result<Raw, ImgError>,
+}
+
+// Every format handled, PNG first as the fallback for unrecognized input
+// and extensions
+pub const FORMATS: &[Handler] = &[
+    Handler {
+        name: "PNG",
+        extensions: &["png"],
+        lossless: true,
+        write: Some(Format::Png),
+        signatures: &[&[0x89, b'P', b'N', b'G']],
+        read: |r| read_png(r),
+    },
+    Handler {
+        name: "JPEG",
+        extensions: &["jpg", "jpeg"],
+        lossless: false,
+        write: Some(Format::Jpeg),
+        signatures: &[&[0xFF, 0xD8, 0xFF]],
+        read: |r| read_jpeg(r),
+    },
+    Handler {
+        name: "BMP",
+        extensions: &["bmp"],
+        lossless: true,
+        write: Some(Format::Bmp),
+        signatures: &[b"BM"],
+        read: |r| {
+            let (width, height, buf, samples) = bmp::read_bmp(r)?;
+            Ok((width, height, Samples::Eight(buf), samples))
+        },
+    },
+    Handler {
+        name: "PPM",
+        extensions: &["ppm", "pnm"],
+        lossless: true,
+        write: Some(Format::Ppm),
+        signatures: &[b"P6", b"P5"],
+        read: |r| ppm::read_ppm(r),
+    },
+    Handler {
+        name: "TIFF",
+        extensions: &["tif", "tiff"],
+        lossless: true,
+        write: Some(Format::Tiff),
+        signatures: &[b"II*\0", b"MM\0*"],
+        read: |r| tiff::read_tiff(r),
+    },
+    Handler {
+        name: "WebP",
+        extensions: &["webp"],
+        lossless: true,
+        write: Some(Format::Webp),
+        signatures: &[b"RIFF"],
+        read: |r| webp::read_webp(r),
+    },
+];
+
+impl Format {
+    // Chosen by extension, PNG for any other
+    pub fn from_path(path: &Path) -> Result<Self, ImgError> {
+        let ext = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase());
+        let handler = FORMATS.iter()
+            .find(|f| ext.as_deref().is_some_and(|e| f.extensions.contains(&e)))
+            .unwrap_or(&FORMATS[0]);
+
+        handler.write.ok_or_else(|| ImgError::Format(format!("{} can only be read", handler.name)))
+    }
+
+    pub fn handler(self) -> &'static Handler {
+        FORMATS.iter().find(|f| f.write == Some(self)).unwrap()
+    }
+}
+
+// Effort the PNG encoder spends shrinking its output
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Compression {
+    /// Encode quickly, leaving files larger, as the png crate does unless told
+    Fast,
+    /// Balance time and size
+    Default,
+    /// Encode slowly for the smallest files
+    Best,
+}
+
+// Collapse the replicated samples of a gray RGB image back to one per pixel,
+// refusing images with any color that would be lost
+pub fn to_gray<T: Sample>(buf: &[T]) -> Result<Vec<T>, ImgError> {
+    buf.chunks_exact(3)
+        .map(|p| if p[0].to_u32() == p[1].to_u32() && p[1].to_u32() == p[2].to_u32() {
+            Ok(p[0])
+        } else {
+            Err(ImgError::Format("pixels are not gray, collapsing them would lose data".to_string()))
+        })
+        .collect()
+}
+
+// Writes at the depth of T, with alpha when a channel is given, to stdout
+// when the path is -. The format follows the extension, and a buffer of one
+// sample per pixel is written as grayscale.
+pub fn write_image<T: Sample>(buf: &[T], alpha: Option<&[T]>, width: u32, height: u32, path: PathBuf)
+        -> Result<(), ImgError> {
+    write_image_with(buf, alpha, width, height, path, &Metadata::default(), Compression::Fast)
+}
+
+// Like write_image, with the metadata of a source and the compression of a
+// PNG output. Only PNG carries metadata.
+pub fn write_image_with<T: Sample>(buf: &[T], alpha: Option<&[T]>, width: u32, height: u32,
+        path: PathBuf, meta: &Metadata, compression: Compression) -> Result<(), ImgError> {
+    let format = Format::from_path(&path)?;
+    let samples = if buf.len() == width as usize * height as usize { 1 } else { 3 };
+    if matches!(format, Format::Bmp | Format::Webp) && T::DEPTH == 16 {
+        let name = format.handler().name;
+        return Err(ImgError::Format(format!("{} cannot hold 16-bit samples", name)));
+    } else if matches!(format, Format::Ppm | Format::Jpeg) && alpha.is_some() {
+        let name = format.handler().name;
+        return Err(ImgError::Format(format!("{} cannot hold an alpha channel", name)));
+    }
+
+    let mut encoded = Vec::new();
+    let w = &mut encoded;
+    match format {
+        Format::Png => write_png(w, buf, samples, alpha, width, height, meta, compression)?,
+        Format::Bmp => {
+            // BMP has no plain grayscale, so gray written as RGB
+            let bytes = |b: &[T]| b.iter().map(|c| c.to_u32() as u8).collect::<Vec<u8>>();
+            let rgb = bytes(buf).into_iter().flat_map(|c| vec![c; 4 - samples]).collect::<Vec<u8>>();
+            bmp::write_bmp(w, width, height, &rgb, alpha.map(bytes).as_deref())?;
+        },
+        Format::Ppm => ppm::write_ppm(w, width, height, buf, samples)?,
+        Format::Tiff => tiff::write_tiff(w, width, height, buf, samples, alpha)?,
+        Format::Jpeg => jpeg::write_jpeg(w, width, height, buf, samples)?,
+        Format::Webp => webp::write_webp(w, width, height, buf, samples, alpha)?,
+    }
+
+    write_encoded(path, &encoded)
+}
+
+// Writes an image only once it is encoded in full, so an encoder that refuses
+// it, as WebP does past its largest size, leaves no empty or truncated file
+fn write_encoded(path: PathBuf, encoded: &[u8]) -> Result<(), ImgError> {
+    let mut w = create(path)?;
+    w.write_all(encoded)?;
+    w.flush()?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_png<T: Sample, W: Write>(w: W, buf: &[T], samples: usize, alpha: Option<&[T]>,
+        width: u32, height: u32, meta: &Metadata, compression: Compression)
+        -> Result<(), ImgError> {
+    let encoder = png_encoder::<T, W>(w, samples, alpha.is_some(), width, height, meta,
+        compression)?;
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&png_data(buf, samples, alpha))?;
+    Ok(())
+}
+
+// Encode the frames of an animation, all the same size, as an animated PNG
+pub fn write_frames<T: Sample>(frames: &[Rgba<T>], animation: &Animation, meta: &Metadata,
+        compression: Compression, path: PathBuf) -> Result<(), ImgError> {
+    if Format::from_path(&path)? != Format::Png {
+        return Err(ImgError::Format("only PNG can hold an animation".to_string()));
+    }
+
+    let (width, height, first, alpha) = &frames[0];
+    let samples = if first.len() == *width as usize * *height as usize { 1 } else { 3 };
+
+    let mut encoded = Vec::new();
+    let mut encoder = png_encoder::<T, _>(&mut encoded, samples, alpha.is_some(),
+        *width, *height, meta, compression)?;
+    encoder.set_animated(frames.len() as u32, animation.plays)?;
+    let mut writer = encoder.write_header()?;
+
+    for ((_, _, buf, alpha), (num, den)) in frames.iter().zip(&animation.delays) {
+        writer.set_frame_delay(*num, *den)?;
+        writer.write_image_data(&png_data(buf, samples, alpha.as_deref()))?;
+    }
+    writer.finish()?;
+
+    write_encoded(path, &encoded)
+}
+
+pub(crate) fn png_encoder<'a, T: Sample, W: Write>(w: W, samples: usize, alpha: bool,
+        width: u32, height: u32, meta: &Metadata, compression: Compression)
+        -> Result<png::Encoder<'a, W>, ImgError> {
+    let mut info = png::Info::with_size(width, height);
+    meta.apply(&mut info);
+    info.color_type = match (samples, alpha) {
+        (1, false) => png::ColorType::Grayscale,
+        (1, true) => png::ColorType::GrayscaleAlpha,
+        (_, false) => png::ColorType::Rgb,
+        (_, true) => png::ColorType::Rgba,
+    };
+    info.bit_depth = if T::DEPTH == 16 { png::BitDepth::Sixteen } else { png::BitDepth::Eight };
+    info.compression = match compression {
+        Compression::Fast => png::Compression::Fast,
+        Compression::Default => png::Compression::Default,
+        Compression::Best => png::Compression::Best,
+    };
+    Ok(png::Encoder::with_info(w, info)?)
+}
+
+// Interleave any alpha and lay samples out as PNG bytes
+pub(crate) fn png_data<T: Sample>(buf: &[T], samples: usize, alpha: Option<&[T]>) -> Vec<u8> {
+    let pixels: Vec<T> = match alpha {
+        Some(alpha) => buf.chunks_exact(samples).zip(alpha)
+            .flat_map(|(p, a)| p.iter().copied().chain([*a]))
+            .collect(),
+        None => buf.to_vec(),
+    };
+
+    // PNG stores multi-byte samples big endian
+    let n = T::DEPTH as usize / 8;
+    pixels.iter()
+        .flat_map(|c| c.to_u32().to_be_bytes().into_iter().skip(4 - n))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: Seed = [7; 32];
+
+    #[test]
+    fn equalize_spreads_values_at_each_depth() {
+        let mut buf: Vec<u8> = [10, 20, 30, 40].iter().flat_map(|v| [*v; 3]).collect();
+        equalize(&mut buf, EqualizeChannel::Value);
+        assert_eq!(buf, [0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255]);
+
+        let mut buf: Vec<u16> = [1000, 2000].iter().flat_map(|v| [*v; 3]).collect();
+        equalize(&mut buf, EqualizeChannel::Value);
+        assert_eq!(buf, [0, 0, 0, 65535, 65535, 65535]);
+    }
+
+    // The original equalize, a CDF over a sorted list of the unique values
+    fn equalize_unique(buf: &mut [u8]) {
+        let mut hsvs: Vec<HSVColor> = buf.chunks_exact(3)
+            .map(|p| HSVColor::from_rgb(p[0], p[1], p[2], 8))
+            .collect();
+
+        let mut vals: Vec<f32> = hsvs.iter().map(|hsv| hsv.val).collect();
+        vals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        vals.dedup();
+
+        let cdf = |v| vals.binary_search_by(|a| a.partial_cmp(&v).unwrap()).unwrap() as f32
+            / (vals.len() - 1) as f32;
+
+        for (p, hsv) in buf.chunks_exact_mut(3).zip(hsvs.iter_mut()) {
+            hsv.val = cdf(hsv.val);
+            for (c, new) in p.iter_mut().zip(hsv.to_rgb(8)) {
+                *c = new as u8;
+            }
+        }
+    }
+
+    #[test]
+    fn equalize_matches_unique_value_cdf() {
+        let orig: Vec<u8> = (0..64 * 48)
+            .flat_map(|i| [(i % 64 * 3) as u8, (i / 64 * 2) as u8, (i % 7 * 5) as u8])
+            .map(|c| c / 2 + 30)
+            .collect();
+
+        let (mut a, mut b) = (orig.clone(), orig);
+        equalize(&mut a, EqualizeChannel::Value);
+        equalize_unique(&mut b);
+        assert!(a.iter().zip(&b).all(|(a, b)| a.abs_diff(*b) <= 1));
+    }
+
+    #[test]
+    fn equalize_saturation_keeps_value() {
+        let mut buf: Vec<u8> = vec![200, 190, 180, 200, 100, 100, 200, 150, 100];
+        equalize(&mut buf, EqualizeChannel::Sat);
+        assert!(buf.chunks_exact(3).all(|p| p.iter().max() == Some(&200)));
+        assert_eq!(&buf[..3], [200, 200, 200]);
+        assert_eq!(&buf[3..6], [200, 0, 0]);
+    }
+
+    #[test]
+    fn dither_preserves_the_mean_of_a_flat_image() {
+        // 100 falls between the 1 bit levels, so dithering mixes both
+        let mut buf = vec![100u8; 16 * 16 * 3];
+        dither(&mut buf, 16, [1; 3]);
+        assert!(buf.iter().all(|c| *c <= 1));
+
+        let mean = buf.iter().map(|c| *c as f32 * 255.0).sum::<f32>() / buf.len() as f32;
+        assert!((mean - 100.0).abs() < 8.0, "mean {}", mean);
+    }
+
+    #[test]
+    fn gamma_one_is_identity() {
+        let orig: Vec<u8> = (0..=255).collect();
+        let mut buf = orig.clone();
+        gamma(&mut buf, 1.0);
+        assert_eq!(buf, orig);
+
+        let orig: Vec<u16> = (0..=65535).collect();
+        let mut buf = orig.clone();
+        gamma(&mut buf, 1.0);
+        assert_eq!(buf, orig);
+    }
+
+    #[test]
+    fn invert_twice_is_identity() {
+        let orig: Vec<u8> = (0..=255).collect();
+        let mut buf = orig.clone();
+        invert(&mut buf);
+        assert_eq!(buf[..3], [255, 254, 253]);
+        invert(&mut buf);
+        assert_eq!(buf, orig);
+
+        let mut buf: Vec<u16> = vec![0, 1000, 65535];
+        invert(&mut buf);
+        assert_eq!(buf, [65535, 64535, 0]);
+    }
+
+    #[test]
+    fn posterize_rounds_to_even_levels() {
+        let mut buf: Vec<u8> = vec![0, 63, 64, 127, 128, 191, 192, 255];
+        posterize(&mut buf, 2);
+        assert_eq!(buf, [0, 0, 0, 0, 255, 255, 255, 255]);
+
+        let mut buf: Vec<u8> = vec![0, 42, 43, 127, 128, 212, 213, 255];
+        posterize(&mut buf, 4);
+        assert_eq!(buf, [0, 0, 85, 85, 170, 170, 255, 255]);
+        let once = buf.clone();
+        posterize(&mut buf, 4);
+        assert_eq!(buf, once);
+
+        let orig: Vec<u16> = vec![0, 1, 32767, 65535];
+        let mut buf = orig.clone();
+        posterize(&mut buf, 65536);
+        assert_eq!(buf, orig);
+    }
+
+    #[test]
+    fn gamma_brightens_midtones() {
+        let mut buf: Vec<u8> = vec![0, 64, 128, 255];
+        gamma(&mut buf, 2.2);
+        assert_eq!(buf[0], 0);
+        assert!(buf[1] > 64 && buf[2] > 128);
+        assert_eq!(buf[3], 255);
+    }
+
+    #[test]
+    fn stretch_leaves_a_single_color_alone() {
+        let mut buf: Vec<u8> = [12, 200, 0].repeat(4);
+        stretch(&mut buf, 0.0, StretchMode::Channel);
+        assert_eq!(buf, [12, 200, 0].repeat(4));
+
+        let mut buf: Vec<u16> = [30000, 30000, 65535].repeat(4);
+        stretch(&mut buf, 25.0, StretchMode::Channel);
+        assert_eq!(buf, [30000, 30000, 65535].repeat(4));
+    }
+
+    #[test]
+    fn stretch_two_pixels_to_the_extremes() {
+        let mut buf: Vec<u8> = vec![10, 50, 7, 20, 50, 3];
+        stretch(&mut buf, 0.0, StretchMode::Channel);
+        assert_eq!(buf, [0, 50, 255, 255, 50, 0]);
+
+        let mut buf: Vec<u16> = vec![1, 2, 3, 4, 5, 6];
+        stretch(&mut buf, 0.0, StretchMode::Channel);
+        assert_eq!(buf, [0, 0, 0, 65535, 65535, 65535]);
+    }
+
+    #[test]
+    fn stretch_maps_each_channel_min_and_max_to_the_ends() {
+        // Each channel over its own range, with blue all black
+        let mut buf: Vec<u8> = vec![40, 100, 0, 60, 101, 0, 50, 130, 0];
+        stretch(&mut buf, 0.0, StretchMode::Channel);
+        assert_eq!(buf, [0, 0, 0, 255, 8, 0, 127, 255, 0]);
+
+        // A clipped outlier is clamped to the end it's beyond
+        let mut buf: Vec<u8> = (0..100).flat_map(|i| [i + 100, 0, 255])
+            .chain([0, 0, 255])
+            .collect();
+        stretch(&mut buf, 1.0, StretchMode::Channel);
+        let reds: Vec<u8> = buf.iter().step_by(3).copied().collect();
+        assert_eq!((reds[0], reds[99], reds[100]), (0, 255, 0));
+        assert!(buf.chunks_exact(3).all(|p| p[1] == 0 && p[2] == 255));
+
+        // Black stays black in either mode
+        for mode in [StretchMode::Channel, StretchMode::Luma] {
+            let mut buf = vec![0u16; 12];
+            stretch(&mut buf, 0.0, mode);
+            assert_eq!(buf, [0; 12]);
+        }
+    }
+
+    #[test]
+    fn stream_cipher_is_an_involution() {
+        let orig: Vec<u8> = (0..10_000).map(|i| (i * 31 % 256) as u8).collect();
+
+        for bits in 1..=8 {
+            let mut buf = orig.clone();
+            stream_cipher(&mut buf, KEY, bits);
+            assert_ne!(buf, orig);
+            stream_cipher(&mut buf, KEY, bits);
+            assert_eq!(buf, orig);
+        }
+
+        let orig: Vec<u16> = (0..10_000).map(|i| (i * 7919 % 65536) as u16).collect();
+        let mut buf = orig.clone();
+        stream_cipher(&mut buf, KEY, 16);
+        stream_cipher(&mut buf, KEY, 16);
+        assert_eq!(buf, orig);
+    }
+
+    #[test]
+    fn permute_planes_moves_bits_and_back() {
+        let orig: Vec<u8> = (0..=255).flat_map(|v| [v, v, !v]).collect();
+        let mut buf = orig.clone();
+        permute_planes(&mut buf, KEY, [8, 4, 2], false);
+        assert_ne!(buf, orig);
+        // Each sample keeps its set bits, and the bits above the permuted ones
+        for (p, o) in buf.iter().zip(&orig) {
+            assert_eq!(p.count_ones(), o.count_ones());
+        }
+        assert!(buf.chunks_exact(3).zip(orig.chunks_exact(3))
+            .all(|(p, o)| p[1] >> 4 == o[1] >> 4 && p[2] >> 2 == o[2] >> 2));
+        permute_planes(&mut buf, KEY, [8, 4, 2], true);
+        assert_eq!(buf, orig);
+
+        let orig: Vec<u16> = (0..300).map(|i| (i * 7919 % 65536) as u16).collect();
+        let mut buf = orig.clone();
+        permute_planes(&mut buf, KEY, [16; 3], false);
+        permute_planes(&mut buf, KEY, [16; 3], true);
+        assert_eq!(buf, orig);
+    }
+
+    #[test]
+    fn stream_cipher_only_touches_low_bits() {
+        let mut buf = vec![0xA0u8; 1000];
+        stream_cipher(&mut buf, KEY, 4);
+        assert!(buf.iter().all(|c| c & 0xF0 == 0xA0));
+    }
+
+    #[test]
+    fn stream_cipher_at_matches_full_buffer() {
+        let orig: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let mut full = orig.clone();
+        stream_cipher(&mut full, KEY, 8);
+
+        let mut part = orig[5000..6000].to_vec();
+        stream_cipher_at(&mut part, KEY, [8; 3], 5000);
+        assert_eq!(part, full[5000..6000]);
+    }
+
+    #[test]
+    fn word_xor_matches_the_keystream_sample_by_sample() {
+        // Odd lengths leave a tail past the last whole u64
+        let mut rng = ChaCha20Rng::from_seed(KEY);
+        let words: Vec<u32> = (0..10_003).map(|_| rng.next_u32()).collect();
+        for bits in [1, 5, 8] {
+            let orig: Vec<u8> = (0..10_003).map(|i| (i * 31 % 256) as u8).collect();
+            let mut buf = orig.clone();
+            stream_cipher(&mut buf, KEY, bits);
+            let mask = u8::low_mask(bits);
+            assert!(buf.iter().zip(&orig).zip(&words)
+                .all(|((b, o), w)| *b == o ^ (w & mask) as u8));
+        }
+
+        let orig: Vec<u16> = (0..10_003).map(|i| (i * 7919 % 65536) as u16).collect();
+        for bits in [3, 16] {
+            let mut buf = orig.clone();
+            stream_cipher(&mut buf, KEY, bits);
+            let mask = u16::low_mask(bits);
+            assert!(buf.iter().zip(&orig).zip(&words)
+                .all(|((b, o), w)| *b == o ^ (w & mask) as u16));
+        }
+    }
+
+    // Recovered samples may be off by up to the quantization step of the bits kept
+    fn assert_revealed(revealed: &[u8], hidden: &[u8], bits: u8) {
+        assert_eq!(revealed.len(), hidden.len());
+        for (r, h) in revealed.iter().zip(hidden) {
+            assert!(r.abs_diff(*h) <= 1 << (8 - bits), "{} revealed as {} at {} bits", h, r, bits);
+        }
+    }
+
+    #[test]
+    fn rescale_bits_spans_full_range() {
+        let mut buf = vec![0u8, 1, 2, 3, 254, 255];
+        rescale_bits(&mut buf, [1; 3]);
+        assert_eq!(buf, [0, 255, 0, 255, 0, 255]);
+
+        let mut buf: Vec<u8> = (0..16).chain([0x3f, 0xf0]).collect();
+        rescale_bits(&mut buf, [4; 3]);
+        let expected: Vec<u8> = (0..16).map(|v| v * 17).chain([255, 0]).collect();
+        assert_eq!(buf, expected);
+
+        let mut buf = vec![1u16, 1, 1];
+        rescale_bits(&mut buf, [1, 2, 16]);
+        assert_eq!(buf, [65535, 21845, 1]);
+
+        // Channels carrying nothing come out black
+        let mut buf = vec![1u8, 1, 1];
+        rescale_bits(&mut buf, [0, 1, 0]);
+        assert_eq!(buf, [0, 255, 0]);
+    }
+
+    #[test]
+    fn rescale_bits_to_a_narrower_range() {
+        let mut buf: Vec<u8> = (0..4).chain([0, 3]).collect();
+        rescale_bits_to(&mut buf, [2; 3], 120);
+        assert_eq!(buf, [0, 40, 80, 120, 0, 120]);
+
+        let mut buf = vec![1u8, 1, 1];
+        rescale_bits_to(&mut buf, [1; 3], 1000);
+        assert_eq!(buf, [255; 3]);
+    }
+
+    #[test]
+    fn shares_combine_to_the_original() {
+        let hidden: Vec<u8> = gradient(300).iter().map(|c| c >> 5).collect();
+        let shares = split_shares(&hidden, [3; 3], 4);
+        assert_eq!(shares.len(), 4);
+        assert!(shares.iter().flatten().all(|c| *c < 8));
+
+        let mut combined = shares[0].clone();
+        for share in &shares[1..] {
+            combine_shares(&mut combined, share).unwrap();
+        }
+        assert_eq!(combined, hidden);
+        assert!(matches!(combine_shares(&mut combined, &[0; 3]), Err(ImgError::DimensionMismatch)));
+    }
+
+    fn gradient(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn conceal_buf_round_trips_at_every_bit_count() {
+        let hidden = gradient(8 * 8 * 3);
+
+        for bits in 1..=8 {
+            let mut cover: Vec<u8> = (0..hidden.len()).map(|i| (255 - i % 256) as u8).collect();
+            conceal_buf(&mut cover, &hidden, bits).unwrap();
+            assert_revealed(&reveal_buf(&cover, bits), &hidden, bits);
+        }
+    }
+
+    #[test]
+    fn conceal_buf_is_exact_at_full_depth() {
+        let hidden = gradient(300);
+        let mut cover = vec![0x5A; 300];
+        conceal_buf(&mut cover, &hidden, 8).unwrap();
+        assert_eq!(reveal_buf(&cover, 8), hidden);
+    }
+
+    #[test]
+    fn conceal_buf_handles_no_bits_and_too_many() {
+        let orig = gradient(30);
+        let mut cover = orig.clone();
+        conceal_buf(&mut cover, &[0xFF; 30], 0).unwrap();
+        assert_eq!(cover, orig);
+        assert_eq!(reveal_buf(&cover, 0), [0; 30]);
+
+        assert!(matches!(conceal_buf(&mut cover, &[0; 30], 9), Err(ImgError::BitDepth(9, 8))));
+        let mut wide = vec![0u16; 30];
+        conceal_buf(&mut wide, &[0xFFFF; 30], 16).unwrap();
+        assert_eq!(reveal_buf(&wide, 16), [0xFFFF; 30]);
+    }
+
+    #[test]
+    fn conceal_at_full_depth_and_none() {
+        // The header takes the first 9 pixels at 8 bits, so the image sits
+        // clear of it
+        let hidden = gradient(6 * 4 * 3);
+        let revealed = conceal_and_reveal((6, 4, &hidden), (16, 16), 8, Plane::Low,
+            Placement::Center);
+        assert_eq!(revealed, hidden);
+
+        let cover = (16, 16, gradient(16 * 16 * 3), None);
+        let err = conceal((6, 4, &hidden), cover, [0; 3], Plane::Low, 0, Placement::Center, false,
+            None, None);
+        assert!(matches!(err, Err(ImgError::BitDepth(0, 8))));
+    }
+
+    #[test]
+    fn conceal_at_a_bit_offset_keeps_the_bits_around_it() {
+        let hidden: Vec<u8> = gradient(8 * 8 * 3).iter().map(|c| c >> 6).collect();
+        let c_buf = gradient(16 * 16 * 3);
+        let (_, _, stego, _) = conceal((8, 8, &hidden), (16, 16, c_buf.clone(), None), [2; 3],
+            Plane::Low, 3, Placement::TopLeft, false, None, None).unwrap();
+        assert!(stego.iter().zip(&c_buf).all(|(s, c)| (s ^ c) & 0b1110_0111 == 0));
+
+        let header = stego::read_header_at(&stego, 2, 3).unwrap();
+        assert_eq!(header.offset, 3);
+        assert!(stego::read_header(&stego, 2).is_none());
+
+        // Below the rows the header takes, the hidden image sits in bits 3 and 4
+        assert!(header.reserved_slots() <= 3 * 16 * 3);
+        let revealed: Vec<u8> = crop(&stego, 3, 16, 0, 3, 8, 5).iter()
+            .map(|c| c >> 3 & 3)
+            .collect();
+        assert_eq!(revealed, hidden[3 * 8 * 3..]);
+    }
+
+    #[test]
+    fn conceal_buf_keeps_the_cover_high_bits() {
+        let mut cover = gradient(30);
+        let orig = cover.clone();
+        conceal_buf(&mut cover, &[0xFF; 30], 3).unwrap();
+        assert!(cover.iter().zip(&orig).all(|(c, o)| c >> 3 == o >> 3 && c & 7 == 7));
+    }
+
+    #[test]
+    fn expand_rgb_handles_each_alpha_mode() {
+        let px = [200u8, 100, 50, 128];
+        let expand = |mode| expand_rgb(&px, 4, [255, 255, 255], mode).unwrap();
+        assert_eq!(expand(AlphaMode::Composite), [227, 177, 152]);
+        assert_eq!(expand(AlphaMode::Premultiplied), [255, 227, 177]);
+        assert_eq!(expand(AlphaMode::Ignore), [200, 100, 50]);
+    }
+
+    #[test]
+    fn paste_undoes_crop() {
+        let buf = gradient(6 * 5 * 3);
+        let part = crop(&buf, 3, 6, 2, 1, 3, 2);
+        let mut blank = vec![0; buf.len()];
+        paste(&mut blank, 3, 6, 2, 1, 3, &part);
+        assert_eq!(crop(&blank, 3, 6, 2, 1, 3, 2), part);
+        let set = |b: &[u8]| b.iter().filter(|&&c| c != 0).count();
+        assert_eq!(set(&blank), set(&part));
+    }
+
+    #[test]
+    fn animations_round_trip_through_apng() {
+        let frames: Vec<Rgba<u8>> = (0..3)
+            .map(|i| (4, 3, gradient(4 * 3 * 3).iter().map(|c| c ^ i).collect(), None))
+            .collect();
+        let animation = Animation { plays: 2, delays: vec![(1, 10), (2, 10), (3, 10)] };
+        let path = std::env::temp_dir().join(format!("rsteg-anim-{}.png", std::process::id()));
+
+        write_frames(&frames, &animation, &Metadata::default(), Compression::Default,
+            path.clone()).unwrap();
+        let (read, read_animation, _) = read_frames(path.clone()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(read_animation, Some(animation));
+        for ((_, _, buf, _), (w, h, raw, samples)) in frames.iter().zip(read) {
+            assert_eq!((w, h, samples), (4, 3, 3));
+            assert_eq!(&raw.into_depth::<u8>(), buf);
+        }
+    }
+
+    #[test]
+    fn only_missing_inputs_are_not_found() {
+        let file = std::env::temp_dir().join(format!("rsteg-io-{}.png", std::process::id()));
+        std::fs::write(&file, b"").unwrap();
+        let missing = file.with_extension("gone");
+        let under_file = file.join("in.png");
+
+        let opened = (open(missing.clone()).err(), open(under_file.clone()).err());
+        let created = create(under_file).err();
+        std::fs::remove_file(file).unwrap();
+
+        assert!(matches!(opened, (Some(ImgError::NotFound(p)), Some(ImgError::Io(_)))
+            if p == missing));
+        assert!(matches!(created, Some(ImgError::Io(_))));
+    }
+
+    #[test]
+    fn refused_encodes_leave_no_file() {
+        let path = std::env::temp_dir().join(format!("rsteg-wide-{}.webp", std::process::id()));
+        let wide = vec![0u8; 16385 * 3];
+        assert!(write_image(&wide, None, 16385, 1, path.clone()).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn metadata_carries_through_png() {
+        let meta = Metadata {
+            gamma: Some(png::ScaledFloat::from_scaled(45455)),
+            text: vec![TEXtChunk::new("Title", "cover")],
+            utf8_text: vec![ITXtChunk::new("Author", "Zoë")],
+            ..Metadata::default()
+        };
+        let path = std::env::temp_dir().join(format!("rsteg-meta-{}.png", std::process::id()));
+
+        write_image_with(&gradient(4 * 3 * 3), None, 4, 3, path.clone(), &meta,
+            Compression::Best).unwrap();
+        let (_, _, read) = read_frames(path.clone()).unwrap();
+        let only = read_metadata(path.clone()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        for read in [read, only] {
+            assert_eq!(read.gamma, meta.gamma);
+            assert_eq!(read.text, meta.text);
+            assert_eq!(read.utf8_text[0].get_text().unwrap(), "Zoë");
+        }
+    }
+
+    // A 2x2 palette PNG at the given bit depth, with alpha for the first
+    // palette entries if trns is given
+    fn indexed_png(depth: png::BitDepth, indices: &[u8], trns: Option<&[u8]>) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 2, 2);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(depth);
+        encoder.set_palette(vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 10, 20, 30]);
+        if let Some(trns) = trns {
+            encoder.set_trns(trns.to_vec());
+        }
+        encoder.write_header().unwrap().write_image_data(indices).unwrap();
+        data
+    }
+
+    #[test]
+    fn indexed_pngs_expand_to_their_palette() {
+        // Rows of two 4-bit and then 2-bit indices, packed high first
+        let opaque = [
+            indexed_png(png::BitDepth::Eight, &[0, 1, 2, 3], None),
+            indexed_png(png::BitDepth::Four, &[0x01, 0x23], None),
+            indexed_png(png::BitDepth::Two, &[0b0001_0000, 0b1011_0000], None),
+        ];
+        for data in opaque {
+            let (w, h, buf, samples) = decode(&data[..]).unwrap();
+            assert_eq!((w, h, samples), (2, 2, 3));
+            assert_eq!(buf.into_depth::<u8>(), [255, 0, 0, 0, 255, 0, 0, 0, 255, 10, 20, 30]);
+        }
+
+        // Entries past the end of tRNS stay opaque
+        let data = indexed_png(png::BitDepth::Eight, &[0, 1, 2, 3], Some(&[0, 128]));
+        let (_, _, buf, samples) = decode(&data[..]).unwrap();
+        assert_eq!(samples, 4);
+        let (color, alpha) = split_alpha(buf.into_depth::<u8>(), samples).unwrap();
+        assert_eq!(color, [255, 0, 0, 0, 255, 0, 0, 0, 255, 10, 20, 30]);
+        assert_eq!(alpha.unwrap(), [0, 128, 255, 255]);
+
+        let (_, _, buf, samples) = decode(&data[..]).unwrap();
+        let composited = expand_rgb(&buf.into_depth::<u8>(), samples, [0, 0, 0],
+            AlphaMode::Composite).unwrap();
+        assert_eq!(composited, [0, 0, 0, 0, 128, 0, 0, 0, 255, 10, 20, 30]);
+    }
+
+    #[test]
+    fn short_buffers_are_refused() {
+        assert!(check_size((4, 3, Samples::Eight(vec![0; 36]), 3)).is_ok());
+        let Err(err) = check_size((4, 3, Samples::Eight(vec![0; 35]), 3)) else {
+            panic!("short buffer was accepted");
+        };
+        assert_eq!(err.to_string(), "Image failed to decode: 4x3 image has 35 samples, \
+            expected 36");
+        assert!(check_size((4, 3, Samples::Sixteen(vec![0; 13]), 1)).is_err());
+    }
+
+    #[test]
+    fn tiff_round_trips() {
+        let rgb = gradient(5 * 3 * 3);
+        let mut data = Vec::new();
+        tiff::write_tiff(&mut data, 5, 3, &rgb, 3, None).unwrap();
+        let (w, h, buf, samples) = decode(&data[..]).unwrap();
+        assert_eq!((w, h, samples), (5, 3, 3));
+        assert_eq!(buf.into_depth::<u8>(), rgb);
+
+        let gray: Vec<u16> = (0..6).map(|i| i * 10000).collect();
+        let mut data = Vec::new();
+        tiff::write_tiff(&mut data, 3, 2, &gray, 1, Some(&[1, 2, 3, 4, 5, 6])).unwrap();
+        let (_, _, buf, samples) = decode(&data[..]).unwrap();
+        assert_eq!((buf.depth(), samples), (16, 2));
+        let (color, alpha) = split_alpha(buf.into_depth::<u16>(), samples).unwrap();
+        assert_eq!(color.iter().step_by(3).copied().collect::<Vec<u16>>(), gray);
+        assert_eq!(alpha.unwrap(), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn formats_follow_the_extension() {
+        let format = |p: &str| Format::from_path(Path::new(p));
+        assert_eq!(format("a.TIFF").unwrap(), Format::Tiff);
+        assert_eq!(format("a.pnm").unwrap(), Format::Ppm);
+        assert_eq!(format("a").unwrap(), Format::Png);
+        assert_eq!(format("a.webm").unwrap(), Format::Png);
+        assert_eq!(format("a.webp").unwrap(), Format::Webp);
+        assert!(!format("a.jpeg").unwrap().handler().lossless);
+    }
+
+    #[test]
+    fn diff_amplifies_and_saturates() {
+        assert_eq!(diff(&[10u8, 10, 200], &[11, 10, 100], 32).unwrap(), [32, 0, 255]);
+        assert!(matches!(diff(&[0u8; 3], &[0; 6], 1), Err(ImgError::DimensionMismatch)));
+    }
+
+    #[test]
+    fn conceal_buf_handles_extreme_values() {
+        for (cover, hidden) in [(0u8, 0u8), (0, 255), (255, 0), (255, 255)] {
+            for bits in [1, 4, 8] {
+                let mut c = vec![cover; 3];
+                conceal_buf(&mut c, &[hidden; 3], bits).unwrap();
+                assert_eq!(reveal_buf(&c, bits), [hidden; 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn conceal_buf_rejects_mismatched_sizes() {
+        assert!(conceal_buf(&mut [0u8; 6], &[0u8; 3], 1).is_err());
+    }
+
+    #[test]
+    fn conceal_buf_round_trips_a_single_pixel() {
+        let hidden = [200, 100, 7];
+        for bits in 1..=8 {
+            let mut cover = [10u8, 20, 30];
+            conceal_buf(&mut cover, &hidden, bits).unwrap();
+            assert_revealed(&reveal_buf(&cover, bits), &hidden, bits);
+        }
+    }
+
+    // Conceal through the full pipeline main uses, then reveal by header
+    fn conceal_and_reveal(hidden: (u32, u32, &[u8]), cover: (u32, u32), bits: u8,
+            plane: Plane, placement: Placement) -> Vec<u8> {
+        let shifted: Vec<u8> = hidden.2.iter().map(|c| c >> (8 - bits)).collect();
+        let c_buf = gradient(cover.0 as usize * cover.1 as usize * 3);
+        let (c_width, _, stego, _) = conceal((hidden.0, hidden.1, &shifted),
+            (cover.0, cover.1, c_buf, None), [bits; 3], plane, 0, placement, false, None, None)
+            .unwrap();
+
+        let header = stego::read_header(&stego, bits).unwrap();
+        let Payload::Image { width, height, x, y } = header.payload else { panic!() };
+        assert_eq!((width, height), (hidden.0, hidden.1));
+        assert_eq!(header.plane, plane);
+
+        let mut buf = match placement {
+            Placement::Scatter(key) => {
+                let pixels = cover.0 as usize * cover.1 as usize;
+                let mut order = stego::scatter_pixels(pixels, header.reserved_slots(), key);
+                order.truncate(width as usize * height as usize);
+                gather(&stego, 3, &order)
+            },
+            Placement::Adaptive(threshold) => {
+                let reserved = header.reserved_slots();
+                let mut order = textured_pixels(&stego, c_width, bits, reserved, threshold);
+                order.truncate(width as usize * height as usize);
+                gather(&stego, 3, &order)
+            },
+            _ => crop(&stego, 3, c_width, x, y, width, height),
+        };
+        if plane == Plane::High {
+            buf.iter_mut().for_each(|c| *c >>= 8 - bits);
+        }
+        reveal_buf(&buf, bits)
+    }
+
+    #[test]
+    fn conceal_round_trips_clear_of_the_header() {
+        let hidden = gradient(4 * 3 * 3);
+
+        // Tiled copies beyond the first are untouched by the header
+        for bits in [1, 2, 5, 8] {
+            let revealed = conceal_and_reveal((4, 3, &hidden), (16, 16), bits, Plane::Low,
+                Placement::Tile);
+            assert_revealed(&revealed, &hidden, bits);
+        }
+
+        let revealed = conceal_and_reveal((4, 3, &hidden), (16, 16), 8, Plane::High, Placement::Tile);
+        assert_eq!(revealed, hidden);
+    }
+
+    #[test]
+    fn tiles_are_cut_out_clear_of_the_header() {
+        // At 8 bits the header's 27 samples take up 3 rows of a 4 pixel wide
+        // cover, so the fourth copy is the first whole one
+        let hidden = gradient(4 * 3);
+        let tiled = |c_height: u32| {
+            let cover = (4, c_height, gradient(4 * c_height as usize * 3), None);
+            let (_, _, stego, _) = conceal((4, 1, &hidden), cover, [8; 3], Plane::Low, 0,
+                Placement::Tile, false, None, None).unwrap();
+            stego::read_header(&stego, 8).unwrap().payload
+        };
+        assert_eq!(tiled(8), Payload::Image { width: 4, height: 1, x: 0, y: 3 });
+        assert_eq!(conceal_and_reveal((4, 1, &hidden), (4, 8), 8, Plane::Low, Placement::Tile),
+            hidden);
+
+        // No copy is clear of it
+        assert_eq!(tiled(3), Payload::Image { width: 4, height: 1, x: 0, y: 0 });
+    }
+
+    #[test]
+    fn scattered_pixels_survive_the_header() {
+        // Nearly every cover pixel carries one, so scattering over all of
+        // them would put some under the header's 9 pixels
+        let hidden = gradient(15 * 16 * 3);
+        let revealed = conceal_and_reveal((15, 16, &hidden), (16, 16), 8, Plane::Low,
+            Placement::Scatter([3; 32]));
+        assert_eq!(revealed, hidden);
+    }
+
+    #[test]
+    fn reveal_cuts_out_the_stored_size() {
+        // Not the 200x200 of the cover, and whole away from the header
+        let hidden = gradient(50 * 30 * 3);
+        for placement in [Placement::Center, Placement::Scatter([3; 32])] {
+            let revealed = conceal_and_reveal((50, 30, &hidden), (200, 200), 3, Plane::Low,
+                placement);
+            assert_eq!(revealed.len(), 50 * 30 * 3);
+            assert_revealed(&revealed, &hidden, 3);
+        }
+    }
+
+    #[test]
+    fn conceal_round_trips_each_placement() {
+        let hidden = gradient(20 * 20 * 3);
+        let header = stego::header_slots(4);
+
+        let placements = [Placement::TopLeft, Placement::Center, Placement::Scatter([3; 32]),
+            Placement::Adaptive(16)];
+        for placement in placements {
+            let revealed = conceal_and_reveal((20, 20, &hidden), (32, 32), 4, Plane::Low, placement);
+
+            // Samples written over by the header are only checked when the
+            // image sits elsewhere
+            let skip = if placement == Placement::TopLeft { header } else { 0 };
+            assert_revealed(&revealed[skip..], &hidden[skip..], 4);
+        }
+    }
+
+    #[test]
+    fn empty_images_pass_through() {
+        let mut buf: Vec<u8> = Vec::new();
+        for mode in [StretchMode::Channel, StretchMode::Luma] {
+            stretch(&mut buf, 0.0, mode);
+            stretch(&mut buf, 1.0, mode);
+        }
+        for channel in [EqualizeChannel::Value, EqualizeChannel::Sat] {
+            equalize(&mut buf, channel);
+        }
+        equalize_adaptive(&mut buf, 0, 0, 8, 2.0);
+        equalize_adaptive(&mut buf, 4, 0, 8, 2.0);
+        gamma(&mut buf, 2.2);
+        adjust_hsv(&mut buf, 90.0, 1.5, 0.5);
+        dither(&mut buf, 0, [2; 3]);
+        stream_cipher(&mut buf, [1; 32], 8);
+        permute_planes(&mut buf, [1; 32], [4; 3], false);
+        assert!(buf.is_empty());
+        assert!(psnr(&buf, &buf).is_ok());
+        assert_eq!(downscale(&buf, 3, 0, 0, 4).2, buf);
+
+        // Nothing hidden in an empty cover, and an empty image costs only
+        // its header
+        let cover = |w, h| (w, h, vec![7u8; w as usize * h as usize * 3], None);
+        let err = conceal((0, 0, &[]), cover(0, 0), [2; 3], Plane::Low, 0, Placement::TopLeft,
+            false, None, None);
+        assert!(matches!(err, Err(ImgError::Capacity(..))));
+        for placement in [Placement::TopLeft, Placement::Center, Placement::Tile,
+                Placement::Scatter([3; 32])] {
+            let (w, h, stego, _) = conceal((0, 0, &[]), cover(16, 16), [2; 3], Plane::Low, 0,
+                placement, true, None, None).unwrap();
+            assert_eq!((w, h), (16, 16));
+            let header = stego::read_header(&stego, 2).unwrap();
+            assert!(matches!(header.payload, Payload::Image { width: 0, height: 0, .. }));
+            let carriers = carrier_pixels((w, h, &stego), &header, placement).unwrap();
+            assert_eq!(carriers.len(), if placement == Placement::Tile { 16 * 16 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn downscale_samples_the_nearest_pixels() {
+        let buf: Vec<u8> = (0..4 * 3).collect();
+        assert_eq!(downscale(&buf, 1, 4, 3, 2), (2, 2, vec![0, 2, 4, 6]));
+        assert_eq!(downscale(&buf, 3, 2, 2, 1), (1, 1, vec![0, 1, 2]));
+        assert_eq!(downscale(&buf, 1, 4, 3, 8), (4, 3, buf.clone()));
+
+        // Thin images keep at least a row
+        assert_eq!(downscale(&[1u8; 100], 1, 100, 1, 10).1, 1);
+    }
+
+    #[test]
+    fn checksum_covers_the_hidden_image_bits() {
+        let cover = (32, 32, gradient(32 * 32 * 3), None);
+        let hidden = vec![2; 20 * 20 * 3];
+        for placement in [Placement::Center, Placement::Scatter([3; 32])] {
+            let (w, h, mut buf, _) = conceal((20, 20, &hidden), cover.clone(), [2; 3],
+                Plane::Low, 0, placement, true, None, None).unwrap();
+            let header = stego::read_header(&buf, 2).unwrap();
+            let samples = stego::pixel_samples(&carrier_pixels((w, h, &buf), &header, placement)
+                .unwrap());
+            assert!(stego::checksum_matches(&buf, &header, &samples));
+
+            buf[samples[samples.len() - 1]] ^= 1;
+            assert!(!stego::checksum_matches(&buf, &header, &samples));
+        }
+    }
+
+    #[test]
+    fn carrier_pixels_follow_the_placement() {
+        let cover = (32, 32, gradient(32 * 32 * 3), None);
+        let hidden = gradient(4 * 2 * 3);
+        let carriers = |placement| {
+            let (w, h, buf, _) = conceal((4, 2, &hidden), cover.clone(), [2; 3], Plane::Low, 0,
+                placement, false, None, None).unwrap();
+            let header = stego::read_header(&buf, 2).unwrap();
+            carrier_pixels((w, h, &buf), &header, placement).unwrap()
+        };
+
+        assert_eq!(carriers(Placement::Center), [494, 495, 496, 497, 526, 527, 528, 529]);
+        assert_eq!(carriers(Placement::Tile).len(), 32 * 32);
+
+        // Scattered pixels stay clear of the header
+        let scattered = carriers(Placement::Scatter([3; 32]));
+        assert_eq!(scattered.len(), 8);
+        assert!(scattered.iter().all(|&i| i >= stego::header_slots(2).div_ceil(3)));
+    }
+
+    #[test]
+    fn adaptive_placement_skips_flat_regions() {
+        // Left half flat, right half a checkerboard in the high bits
+        let cover: Vec<u8> = (0..16 * 16).flat_map(|i| {
+            let (x, y) = (i % 16, i / 16);
+            [if x >= 8 && (x + y) % 2 == 1 { 0xf0 } else { 0x10 }; 3]
+        }).collect();
+
+        let textured = textured_pixels(&cover, 16, 4, 0, 16);
+        assert!(textured.iter().all(|i| i % 16 >= 7));
+        assert_eq!(textured.iter().filter(|i| *i % 16 >= 8).count(), 16 * 8);
+
+        // Low bits don't move the gradient, and too few pixels refuse
+        let noisy: Vec<u8> = cover.iter().enumerate().map(|(i, c)| c | (i % 16) as u8).collect();
+        assert_eq!(textured_pixels(&noisy, 16, 4, 0, 16), textured);
+        let result = conceal((16, 10, &[0; 16 * 10 * 3]), (16, 16, cover, None), [4; 3],
+            Plane::Low, 0, Placement::Adaptive(16), false, None,
+            None);
+        assert!(matches!(result, Err(ImgError::DimensionMismatch)));
+    }
+
+    #[test]
+    fn conceal_rejects_hidden_images_larger_than_the_cover() {
+        let hidden = gradient(5 * 4 * 3);
+        let cover = (4, 4, gradient(4 * 4 * 3), None);
+        let err = conceal((5, 4, &hidden), cover, [2; 3], Plane::Low, 0, Placement::TopLeft, false,
+            None, None);
+        assert!(matches!(err, Err(ImgError::HiddenTooLarge((4, 4), (5, 4)))));
+        assert_eq!(err.unwrap_err().to_string(), "Cover is 4x4 but hidden is 5x4");
+    }
+
+    #[test]
+    fn conceal_round_trips_different_bits_per_channel() {
+        let (hidden, bits) = (gradient(20 * 20 * 3), [2, 1, 3]);
+        let shifted: Vec<u8> = hidden.iter().enumerate().map(|(i, c)| c >> (8 - bits[i % 3])).collect();
+        let cover = (32, 32, gradient(32 * 32 * 3), None);
+        let (_, _, stego, _) = conceal((20, 20, &shifted), cover, bits, Plane::Low, 0,
+            Placement::Center, false, None, None).unwrap();
+
+        let header = stego::read_header(&stego, 1).unwrap();
+        assert_eq!(header.channels, bits);
+        let Payload::Image { width, height, x, y } = header.payload else { panic!() };
+
+        let revealed = reveal_rgb(&crop(&stego, 3, 32, x, y, width, height), bits);
+        for (i, (r, h)) in revealed.iter().zip(&hidden).enumerate() {
+            assert!(r.abs_diff(*h) <= 1 << (8 - bits[i % 3]), "{} revealed as {}", h, r);
+        }
+    }
 }
@@ -0,0 +1,320 @@
+use std::f32::consts::PI;
+use std::io::{self, Write};
+use crate::img::Sample;
+
+// Scale of the quantization tables, as in libjpeg's quality setting
+const QUALITY: u32 = 90;
+
+// Quantization tables of Annex K.1, in row order
+const LUMA_QUANT: [u32; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+const CHROMA_QUANT: [u32; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+// Huffman tables of Annex K.3, as the count of codes of each length from 1
+// to 16 followed by the symbols in code order
+const LUMA_DC: ([u8; 16], &[u8]) = (
+    [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+    &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+);
+const CHROMA_DC: ([u8; 16], &[u8]) = (
+    [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+    &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+);
+const LUMA_AC: ([u8; 16], &[u8]) = (
+    [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D],
+    &[
+        0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61,
+        0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52,
+        0xD1, 0xF0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25,
+        0x26, 0x27, 0x28, 0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45,
+        0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64,
+        0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83,
+        0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99,
+        0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6,
+        0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3,
+        0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8,
+        0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+    ],
+);
+const CHROMA_AC: ([u8; 16], &[u8]) = (
+    [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+    &[
+        0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61,
+        0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33,
+        0x52, 0xF0, 0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18,
+        0x19, 0x1A, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44,
+        0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63,
+        0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A,
+        0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97,
+        0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4,
+        0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA,
+        0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7,
+        0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+    ],
+);
+
+// Row order index of each coefficient in zigzag order, running along the
+// antidiagonals from the top left
+fn zigzag() -> [usize; 64] {
+    let mut order = [0; 64];
+    let mut n = 0;
+    for s in 0..15 {
+        for i in 0..=s {
+            let row = if s % 2 == 0 { s - i } else { i };
+            if row < 8 && s - row < 8 {
+                order[n] = row * 8 + s - row;
+                n += 1;
+            }
+        }
+    }
+    order
+}
+
+// Code and length of each symbol of a table, built as canonical Huffman codes
+fn codes((counts, symbols): ([u8; 16], &[u8])) -> [(u16, u8); 256] {
+    let mut table = [(0, 0); 256];
+    let (mut code, mut symbols) = (0u16, symbols.iter());
+    for (len, &count) in (1..=16).zip(&counts) {
+        for &s in symbols.by_ref().take(count as usize) {
+            table[s as usize] = (code, len);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    table
+}
+
+// Entropy coded bits, with a zero stuffed after each 0xFF byte
+struct Bits {
+    out: Vec<u8>,
+    acc: u32,
+    n: u8,
+}
+
+impl Bits {
+    fn put(&mut self, (code, len): (u16, u8)) {
+        self.acc = self.acc << len | code as u32 & ((1 << len) - 1);
+        self.n += len;
+        while self.n >= 8 {
+            self.n -= 8;
+            let byte = (self.acc >> self.n) as u8;
+            self.out.push(byte);
+            if byte == 0xFF {
+                self.out.push(0);
+            }
+        }
+        self.acc &= (1 << self.n) - 1;
+    }
+
+    // Pad the last byte with ones
+    fn flush(&mut self) {
+        if self.n > 0 {
+            self.put((0x7F, 8 - self.n));
+        }
+    }
+}
+
+// Bits needed for the magnitude of v, and v in them as JPEG stores it
+fn magnitude(v: i32) -> (u16, u8) {
+    let size = (32 - v.unsigned_abs().leading_zeros()) as u8;
+    let bits = if v < 0 { v - 1 } else { v };
+    (bits as u16, size)
+}
+
+// One component of the image, its blocks coded with these tables
+struct Component<'a> {
+    cos: &'a [[f32; 8]; 8],
+    quant: [f32; 64],
+    dc: &'a [(u16, u8); 256],
+    ac: &'a [(u16, u8); 256],
+    last_dc: i32,
+}
+
+impl Component<'_> {
+    // Transform, quantize and code one 8x8 block of level shifted samples
+    fn encode(&mut self, block: &[f32; 64], zigzag: &[usize; 64], bits: &mut Bits) {
+        // Down the columns, then along the rows
+        let cos = self.cos;
+        let cols: [f32; 64] = std::array::from_fn(|p| {
+            (0..8).map(|y| cos[p / 8][y] * block[y * 8 + p % 8]).sum()
+        });
+        let c = |k: usize| if k == 0 { 0.5f32.sqrt() } else { 1.0 };
+        let coef = zigzag.map(|z| {
+            let (u, v) = (z / 8, z % 8);
+            let sum: f32 = (0..8).map(|x| cos[v][x] * cols[u * 8 + x]).sum();
+            (c(u) * c(v) * sum / 4.0 / self.quant[z]).round() as i32
+        });
+
+        let (code, size) = magnitude(coef[0] - self.last_dc);
+        self.last_dc = coef[0];
+        bits.put(self.dc[size as usize]);
+        bits.put((code, size));
+
+        let mut run = 0;
+        for &v in &coef[1..] {
+            if v == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                bits.put(self.ac[0xF0]);
+                run -= 16;
+            }
+            let (code, size) = magnitude(v);
+            bits.put(self.ac[(run << 4 | size) as usize]);
+            bits.put((code, size));
+            run = 0;
+        }
+        if run > 0 {
+            bits.put(self.ac[0x00]);
+        }
+    }
+}
+
+fn marker<W: Write>(w: &mut W, marker: u8, data: &[u8]) -> io::Result<()> {
+    w.write_all(&[0xFF, marker])?;
+    w.write_all(&(data.len() as u16 + 2).to_be_bytes())?;
+    w.write_all(data)
+}
+
+// Encodes gray or RGB samples as a baseline JPEG, with no chroma
+// subsampling. 16-bit samples are cut to their high byte. Lossy, as
+// anything in the low bits is lost.
+pub fn write_jpeg<W: Write, T: Sample>(mut w: W, width: u32, height: u32, buf: &[T],
+        samples: usize) -> io::Result<()> {
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "JPEG is at most 65535 wide"));
+    }
+
+    let zigzag = zigzag();
+    let scale = |q: &[u32; 64]| q.map(|v| ((v * (200 - 2 * QUALITY) + 50) / 100).clamp(1, 255));
+    let quant = [scale(&LUMA_QUANT), scale(&CHROMA_QUANT)];
+    let tables = [[LUMA_DC, LUMA_AC], [CHROMA_DC, CHROMA_AC]];
+    let codes = tables.map(|t| t.map(codes));
+    let cos: [[f32; 8]; 8] = std::array::from_fn(|k| {
+        std::array::from_fn(|x| ((2 * x + 1) as f32 * k as f32 * PI / 16.0).cos())
+    });
+    let n = samples.min(3);
+
+    w.write_all(&[0xFF, 0xD8])?;
+    marker(&mut w, 0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0")?;
+    let mut dqt = Vec::new();
+    for (id, q) in quant.iter().enumerate().take(n.min(2)) {
+        dqt.push(id as u8);
+        dqt.extend(zigzag.map(|z| q[z] as u8));
+    }
+    marker(&mut w, 0xDB, &dqt)?;
+
+    let mut sof = vec![8];
+    sof.extend((height as u16).to_be_bytes());
+    sof.extend((width as u16).to_be_bytes());
+    sof.push(n as u8);
+    for id in 0..n {
+        sof.extend([id as u8 + 1, 0x11, id.min(1) as u8]);
+    }
+    marker(&mut w, 0xC0, &sof)?;
+
+    let mut dht = Vec::new();
+    for (id, pair) in tables.iter().enumerate().take(n.min(2)) {
+        for (class, (counts, symbols)) in pair.iter().enumerate() {
+            dht.push((class as u8) << 4 | id as u8);
+            dht.extend(counts);
+            dht.extend(*symbols);
+        }
+    }
+    marker(&mut w, 0xC4, &dht)?;
+
+    let mut sos = vec![n as u8];
+    for id in 0..n {
+        sos.extend([id as u8 + 1, (id.min(1) as u8) * 0x11]);
+    }
+    sos.extend([0, 63, 0]);
+    marker(&mut w, 0xDA, &sos)?;
+
+    let mut components: Vec<Component> = (0..n).map(|id| Component {
+        cos: &cos,
+        quant: quant[id.min(1)].map(|q| q as f32),
+        dc: &codes[id.min(1)][0],
+        ac: &codes[id.min(1)][1],
+        last_dc: 0,
+    }).collect();
+
+    // Pixels past the edge repeat the last row and column
+    let (width, height) = (width as usize, height as usize);
+    let level = |x: usize, y: usize| -> [f32; 3] {
+        let p = &buf[(y.min(height - 1) * width + x.min(width - 1)) * samples..];
+        let c = |k: usize| (p[k].to_u32() >> (T::DEPTH - 8)) as f32;
+        if n == 1 {
+            return [c(0) - 128.0, 0.0, 0.0];
+        }
+        let (r, g, b) = (c(0), c(1), c(2));
+        [
+            0.299 * r + 0.587 * g + 0.114 * b - 128.0,
+            -0.168736 * r - 0.331264 * g + 0.5 * b,
+            0.5 * r - 0.418688 * g - 0.081312 * b,
+        ]
+    };
+
+    let mut bits = Bits { out: Vec::new(), acc: 0, n: 0 };
+    for by in (0..height).step_by(8) {
+        for bx in (0..width).step_by(8) {
+            let mut blocks = [[0.0; 64]; 3];
+            for p in 0..64 {
+                let ycc = level(bx + p % 8, by + p / 8);
+                for (block, c) in blocks.iter_mut().zip(ycc) {
+                    block[p] = c;
+                }
+            }
+            for (component, block) in components.iter_mut().zip(&blocks) {
+                component.encode(block, &zigzag, &mut bits);
+            }
+        }
+    }
+    bits.flush();
+
+    w.write_all(&bits.out)?;
+    w.write_all(&[0xFF, 0xD9])?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_stays_close() {
+        // Smooth color, odd sizes so edge blocks are padded
+        let (w, h) = (21usize, 13usize);
+        let rgb: Vec<u8> = (0..w * h * 3).map(|i| {
+            let (x, y, k) = (i / 3 % w, i / 3 / w, i % 3);
+            (x * 6 + y * 4 + k * 40) as u8
+        }).collect();
+        for (buf, samples) in [(rgb.clone(), 3), (rgb.iter().step_by(3).copied().collect(), 1)] {
+            let mut data = Vec::new();
+            write_jpeg(&mut data, w as u32, h as u32, &buf, samples).unwrap();
+            let mut decoder = jpeg_decoder::Decoder::new(&data[..]);
+            let out = decoder.decode().unwrap();
+            let info = decoder.info().unwrap();
+            assert_eq!((info.width as usize, info.height as usize), (w, h));
+            assert_eq!(out.len(), buf.len());
+            assert!(out.iter().zip(&buf).all(|(a, b)| a.abs_diff(*b) <= 4));
+        }
+    }
+}
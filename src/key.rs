@@ -0,0 +1,75 @@
+use hmac::{Hmac, KeyInit, Mac};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+
+// Full 256-bit ChaCha20 key
+pub type Seed = [u8; 32];
+
+// Expand a numeric key the same way seed_from_u64 does
+pub fn from_u64(key: u64) -> Seed {
+    ChaCha20Rng::seed_from_u64(key).get_seed()
+}
+
+// Derive a key from a passphrase by hashing it with SHA-256
+pub fn from_password(password: &str) -> Seed {
+    Sha256::digest(password.as_bytes()).into()
+}
+
+// Derive a key from a passphrase with PBKDF2-HMAC-SHA256, so each guess at
+// it costs that many iterations. The salt is fixed, as reveal has only the
+// iterations recorded in the header to go on.
+pub fn stretch_password(password: &str, iterations: u32) -> Seed {
+    pbkdf2(password.as_bytes(), b"rsteg", iterations)
+}
+
+// The first and only block of PBKDF2 output, a full key
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32) -> Seed {
+    let mac = Hmac::<Sha256>::new_from_slice(password).unwrap();
+    let mut u: Seed = mac.clone().chain_update(salt).chain_update(1u32.to_be_bytes())
+        .finalize().into_bytes().into();
+
+    let mut key = u;
+    for _ in 1..iterations {
+        u = mac.clone().chain_update(u).finalize().into_bytes().into();
+        key.iter_mut().zip(&u).for_each(|(k, u)| *k ^= u);
+    }
+    key
+}
+
+// Derive a key from the contents of a key file, hashed with SHA-256
+pub fn from_bytes(data: &[u8]) -> Seed {
+    Sha256::digest(data).into()
+}
+
+// Parse a key written as 64 hex digits
+pub fn from_hex(s: &str) -> Result<Seed, String> {
+    if s.len() != 64 || !s.is_ascii() {
+        return Err("expected 64 hex digits".to_string());
+    }
+
+    let mut seed = [0; 32];
+    for (b, pair) in seed.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+        let pair = std::str::from_utf8(pair).unwrap();
+        *b = u8::from_str_radix(pair, 16).map_err(|e| format!("{}: {}", pair, e))?;
+    }
+
+    Ok(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pbkdf2_matches_the_test_vectors() {
+        let vectors = [
+            (1, "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"),
+            (4096, "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"),
+        ];
+        for (iterations, expected) in vectors {
+            assert_eq!(pbkdf2(b"password", b"salt", iterations), from_hex(expected).unwrap());
+        }
+        assert_ne!(stretch_password("pass", 2), stretch_password("pass", 3));
+    }
+}
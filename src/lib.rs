@@ -0,0 +1,29 @@
+//! Lightweight PNG image encryption and steganography.
+
+pub mod hsv;
+pub use crate::hsv::HSVColor;
+
+pub mod img;
+pub use crate::img::{
+    ImgError, Sample, Samples,
+    read_image_rgb8, read_image_rgba8, write_image_rgb8,
+    read_image, read_image_as, read_image_rgba, write_image, write_image_with, Metadata, AlphaMode,
+    stretch, StretchMode, equalize, EqualizeChannel, equalize_adaptive, gamma, invert, adjust_hsv,
+    stream_cipher, stream_cipher_rgb, permute_planes, conceal, conceal_buf, reveal_buf, reveal_rgb,
+    rescale_bits, rescale_bits_to, split_shares, combine_shares,
+};
+
+pub mod stego;
+pub mod stream;
+pub mod analysis;
+pub mod ecc;
+
+pub mod key;
+pub mod progress;
+pub use crate::key::Seed;
+
+mod bmp;
+mod jpeg;
+mod ppm;
+mod tiff;
+mod webp;
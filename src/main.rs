@@ -1,85 +1,2135 @@
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
 
-use clap::Parser;
+use clap::{ArgGroup, Parser, ValueEnum};
 
-mod hsv;
-use crate::hsv::HSVColor;
+use rsteg::{analysis, img, key, progress, stream};
+use rsteg::img::{Animation, Compression, Format, Placement, Raw, Rgba};
+use rsteg::stego::{self, Embedding, Header, Payload, Plane};
+use rsteg::stream::{RowReader, RowWriter};
+use rsteg::{AlphaMode, HSVColor, ImgError, Metadata, Sample, Samples, Seed};
+use rsteg::{StretchMode, EqualizeChannel};
 
-mod img;
-
-// TODO background color option
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum ConcealPos {
+    /// In the top left corner
+    Topleft,
+    /// In the middle of the cover
+    Center,
+    /// Repeated to fill the cover
+    Tile,
+}
 
 // CLI arg definition
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
+#[command(group(ArgGroup::new("embed")
+    .multiple(true)
+    .args(["conceal", "conceal_file", "message"])))]
+#[command(group(ArgGroup::new("image")
+    .multiple(true)
+    .args(["conceal", "reveal"])))]
+#[command(group(ArgGroup::new("payload")
+    .multiple(true)
+    .args(["conceal", "conceal_file", "message", "reveal", "reveal_file", "reveal_message"])))]
 struct Args {
-    input: PathBuf,
+    /// Input image (PNG, JPEG, BMP, PPM or TIFF), - to read it from stdin
+    #[arg(required_unless_present_any(["selftest", "list_formats", "batch"]))]
+    input: Option<PathBuf>,
+
+    /// Run on every image in this directory instead of one input, writing
+    /// each under its own name to the output directory
+    #[arg(long, value_name="DIR",
+        conflicts_with_all(["input", "combine"]),
+        requires("output"))]
+    batch: Option<PathBuf>,
+
+    /// Check the conceal, reveal, cipher and color conversion round trips on
+    /// generated images, with no input, and exit
+    #[arg(long,
+        exclusive(true))]
+    selftest: bool,
 
+    /// List the image formats read and written, and whether each is lossless
+    /// and so safe to hold a payload, and exit
+    #[arg(long,
+        exclusive(true))]
+    list_formats: bool,
+
+    /// Output path, - to write to stdout. Written as BMP, PPM, TIFF or JPEG for
+    /// those extensions and PNG otherwise
     #[arg(short, long, default_value("out.png"))]
     output: PathBuf,
 
+    /// Reveal an image concealed in the input
     #[arg(short, long,
         group="mode",
-        requires("bits"))]
+        requires("width"))]
     reveal: bool,
 
+    /// Reveal the layer concealed in this range of bits, counted from 0 for
+    /// the lowest, instead of the low bits
+    #[arg(long, value_name="LO-HI", value_parser=parse_planes,
+        group="width",
+        requires("reveal"))]
+    planes: Option<[u8; 2]>,
+
+    /// Reveal the low bits of the whole input even without a concealed header
+    #[arg(long,
+        requires("reveal"))]
+    force: bool,
+
+    /// Conceal the input in the low bits of this cover image
     #[arg(short, long,
         group="mode",
         requires("bits"))]
     conceal: Option<PathBuf>,
 
-    #[arg(short, long, value_name="KEY")]
+    /// Also conceal this image as a layer in the range of bits LO-HI of the
+    /// cover, clear of the main payload. Can be repeated
+    #[arg(long, value_name="IMAGE:LO-HI", value_parser=parse_layer,
+        requires("conceal"))]
+    layer: Vec<(PathBuf, [u8; 2])>,
+
+    /// Split the input into N shares, each concealed in its own copy of the
+    /// cover as OUTPUT_share0.png and so on. Revealing it takes all N.
+    #[arg(long, value_name="N", value_parser=clap::value_parser!(u8).range(2..),
+        requires("conceal"),
+        conflicts_with_all(["verify", "grayscale"]))]
+    split: Option<u8>,
+
+    /// The other shares of a split image, XORed with the input to reveal it
+    #[arg(long, value_name="SHARE", num_args=1..,
+        requires("reveal"))]
+    combine: Vec<PathBuf>,
+
+    /// Where a hidden image smaller than the cover is placed
+    #[arg(long, value_name="POS", value_enum,
+        requires("conceal"),
+        conflicts_with("scatter"),
+        default_value("topleft"))]
+    conceal_pos: ConcealPos,
+
+    /// Conceal the payload again in every SIZE by SIZE block of the cover,
+    /// each with its own header. Revealing with the same SIZE recovers it
+    /// from any whole block left after the image is cropped
+    #[arg(long, value_name="SIZE", value_parser=clap::value_parser!(u32).range(1..),
+        requires("payload"),
+        conflicts_with_all(["split", "layer", "combine", "highlight", "adaptive"]))]
+    redundant: Option<u32>,
+
+    /// Conceal this harmless image as a decoy in the bits reveal reads first,
+    /// authenticated by --decoy-key, and the input in the bits above it under
+    /// a header only the main key finds
+    #[arg(long, value_name="IMAGE",
+        requires_all(["conceal", "secret", "decoy_key"]),
+        conflicts_with_all(["layer", "split", "redundant", "scatter", "adaptive", "permute_planes",
+            "authenticate", "checksum", "kdf_iterations", "bit_offset", "plane", "highlight",
+            "stream"]))]
+    decoy: Option<PathBuf>,
+
+    /// Key of the decoy, given as --key is. Revealing with it shows the decoy,
+    /// and with the main key the input
+    #[arg(long, value_name="KEY",
+        requires("decoy"))]
+    decoy_key: Option<u64>,
+
+    /// Which bits of the cover a hidden image is written over. Reveal takes
+    /// this from the header, falling back to the option without one
+    #[arg(long, value_name="PLANE", value_enum,
+        default_value("low"))]
+    plane: Plane,
+
+    /// Print the PSNR of the stego image against this original cover
+    #[arg(long, value_name="ORIGINAL",
+        conflicts_with_all(["reveal", "reveal_file", "reveal_message", "capacity", "bit_planes",
+            "analyze", "diff"]))]
+    verify: Option<PathBuf>,
+
+    /// Also write a copy of the stego image here with the pixels carrying the
+    /// payload in full red, and those holding the header and tag in full blue
+    #[arg(long, value_name="PATH",
+        requires("embed"),
+        conflicts_with("split"))]
+    highlight: Option<PathBuf>,
+
+    /// Print the payload capacity of the input, and whether PAYLOAD fits
+    #[arg(long, value_name="PAYLOAD",
+        group="mode",
+        requires("bits"),
+        num_args(0..=1))]
+    capacity: Option<Option<PathBuf>>,
+
+    /// Conceal the bytes of any file in the input
+    #[arg(long, value_name="PATH",
+        group="mode",
+        group="bytes",
+        requires("width"))]
+    conceal_file: Option<PathBuf>,
+
+    /// Reveal a concealed file, writing it to the output path
+    #[arg(long,
+        group="mode",
+        requires("bits"))]
+    reveal_file: bool,
+
+    /// Conceal a text message in the input
+    #[arg(short, long, value_name="STRING",
+        group="mode",
+        group="bytes",
+        requires("width"))]
+    message: Option<String>,
+
+    /// Deflate the concealed file or message first, where that shrinks it.
+    /// Reveal inflates it again by itself
+    #[arg(long,
+        requires("bytes"))]
+    compress: bool,
+
+    /// Store the concealed file or message with parity, doubling its size, so
+    /// reveal can correct a flipped bit in each byte
+    #[arg(long,
+        requires("bytes"))]
+    ecc: bool,
+
+    /// Store a CRC32 of the concealed payload, so reveal can report whether
+    /// it came through intact. Needs no key, but unlike --authenticate
+    /// doesn't stop deliberate tampering
+    #[arg(long,
+        requires("embed"))]
+    checksum: bool,
+
+    /// Reveal a concealed text message, printing it
+    #[arg(long,
+        group="mode",
+        requires("bits"))]
+    reveal_message: bool,
+
+    /// Write the low N bits of every channel rescaled to the full range,
+    /// whether or not they carry a payload, so patterns in them stand out
+    #[arg(long, value_name="N", value_parser=clap::value_parser!(u8).range(1..=16),
+        group="mode")]
+    extract_lsb: Option<u8>,
+
+    /// Write each of the low bit planes to its own black and white image,
+    /// named after the output path like out_plane0.png
+    #[arg(long,
+        group="mode",
+        requires("bits"))]
+    bit_planes: bool,
+
+    /// Numeric cipher key, discouraged as it only has 64 bits of entropy
+    #[arg(short, long, value_name="KEY",
+        group="secret")]
     key: Option<u64>,
 
-    #[arg(short, long, value_name="1-8", value_parser=clap::value_parser!(u8).range(1..9),
+    /// Full 256-bit cipher key as 64 hex digits
+    #[arg(long, value_name="HEX", value_parser=key::from_hex,
+        group="secret")]
+    key_hex: Option<Seed>,
+
+    /// Passphrase hashed into a full 256-bit cipher key
+    #[arg(short, long, value_name="STRING",
+        group="secret")]
+    password: Option<String>,
+
+    /// Stretch the passphrase with this many iterations of PBKDF2 rather
+    /// than hashing it once, slowing guesses at a weak one. The count is
+    /// stored in the header, so reveal finds it by itself
+    #[arg(long, value_name="N", value_parser=clap::value_parser!(u32).range(1..),
+        requires("password"))]
+    kdf_iterations: Option<u32>,
+
+    /// File whose bytes are hashed into a full 256-bit cipher key, keeping
+    /// the key out of shell history
+    #[arg(long, value_name="PATH",
+        group="secret")]
+    key_file: Option<PathBuf>,
+
+    /// Independent 256-bit key for the stream cipher and --authenticate, as
+    /// 64 hex digits, in place of the key above
+    #[arg(long, value_name="HEX", value_parser=key::from_hex,
+        requires("secret"))]
+    key_cipher: Option<Seed>,
+
+    /// Independent 256-bit key for --scatter, as 64 hex digits, in place of
+    /// the key above
+    #[arg(long, value_name="HEX", value_parser=key::from_hex,
+        requires("scatter"))]
+    key_scatter: Option<Seed>,
+
+    /// Shuffle the bit planes of a concealed image by the key, after the
+    /// cipher, so its bits don't sit in their plain order. Reveal needs it
+    /// again to put them back.
+    #[arg(long,
+        requires("secret"),
+        requires("image"))]
+    permute_planes: bool,
+
+    /// Spread the payload over key derived positions
+    #[arg(long,
+        requires("secret"),
+        requires("payload"))]
+    scatter: bool,
+
+    /// Conceal only in textured pixels, where changes to the low bits are
+    /// hardest to see. Needed again to reveal.
+    #[arg(long,
+        requires("image"),
+        conflicts_with_all(["scatter", "layer", "conceal_file", "message", "reveal_file",
+            "reveal_message"]))]
+    adaptive: bool,
+
+    /// Gradient in 8-bit levels a pixel must reach for --adaptive
+    #[arg(long, value_name="LEVELS", default_value_t=16,
+        requires("adaptive"))]
+    edge_threshold: u32,
+
+    /// Tag the payload so tampering is detected on reveal
+    #[arg(long,
+        requires("secret"),
+        requires("payload"))]
+    authenticate: bool,
+
+    /// Estimate the likelihood that each channel carries data embedded in
+    /// its low bits, with a chi-square test of their value pairs
+    #[arg(long,
+        group="mode")]
+    analyze: bool,
+
+    /// Print histograms of R, G, B and luminance (the HSV value) of the
+    /// input as bars, or their counts at each level with --json
+    #[arg(long,
+        group="mode")]
+    histogram: bool,
+
+    /// Write an image of how far each sample strays from this original,
+    /// amplified so changes to the low bits are visible
+    #[arg(long, value_name="ORIGINAL",
+        group="mode")]
+    diff: Option<PathBuf>,
+
+    /// Factor the differences written by --diff are multiplied by
+    #[arg(long, value_name="FACTOR", value_parser=clap::value_parser!(u32).range(1..),
+        requires("diff"),
+        default_value("32"))]
+    diff_gain: u32,
+
+    /// Number of low bits carrying the payload, or one for each of R,G,B
+    /// such as 2,1,3
+    #[arg(short, long, value_name="1-16", value_parser=parse_bits,
+        group="width",
         default_value("8"))]
-    bits: u8,
+    bits: [u8; 3],
+
+    /// Conceal a file or message at the fewest bits it fits in, printing the
+    /// count reveal needs
+    #[arg(long,
+        group="width",
+        requires("bytes"))]
+    auto_bits: bool,
 
+    /// Conceal an image this many bits up from the lowest, in the middle of
+    /// the samples, with its header. Reveal needs the same offset.
+    #[arg(long, value_name="N", value_parser=clap::value_parser!(u8).range(0..16),
+        conflicts_with_all(["planes", "plane", "bytes", "reveal_file", "reveal_message",
+            "authenticate"]),
+        default_value("0"))]
+    bit_offset: u8,
+
+    /// Channels carrying the payload and ciphered, any of r, g and b such as
+    /// g or rb. The others of the cover are left alone apart from the header.
+    #[arg(long, value_name="RGB", value_parser=parse_channels,
+        default_value("rgb"))]
+    channels: [bool; 3],
+
+    /// Stretch the contrast of each channel to the full range
     #[arg(short, long,
-        conflicts_with_all(["equalize", "reveal"]))]
+        conflicts_with_all(["equalize", "equalize_adaptive"]))]
     stretch: bool,
 
+    /// Percent of each channel's darkest and brightest values clipped by stretch
+    #[arg(long, value_name="PERCENT", value_parser=parse_percent,
+        requires("stretch"),
+        default_value("0"))]
+    stretch_clip: f32,
+
+    /// Whether stretch works on each color channel or on brightness alone
+    #[arg(long, value_name="MODE", value_enum,
+        requires("stretch"),
+        default_value("channel"))]
+    stretch_mode: StretchMode,
+
+    /// Equalize the histogram of the HSV value
+    #[arg(short, long)]
+    equalize: bool,
+
+    /// HSV channel equalized, value or sat. Hue is circular so has no order
+    /// to equalize by
+    #[arg(long, value_name="CHANNEL", value_parser=parse_channel,
+        requires("equalize"),
+        default_value("value"))]
+    equalize_channel: EqualizeChannel,
+
+    /// Equalize the HSV value over a grid of tiles, bringing out local detail
+    #[arg(long,
+        conflicts_with_all(["equalize"]))]
+    equalize_adaptive: bool,
+
+    /// Number of tiles across and down the image for adaptive equalization
+    #[arg(long, value_name="N", value_parser=clap::value_parser!(u32).range(1..),
+        requires("equalize_adaptive"),
+        default_value("8"))]
+    tiles: u32,
+
+    /// Histogram bins are clipped at this multiple of their mean count,
+    /// limiting how much adaptive equalization amplifies noise
+    #[arg(long, value_name="FACTOR",
+        requires("equalize_adaptive"),
+        default_value("2"))]
+    clip_limit: f32,
+
+    /// Limit stretch, equalize and gamma to a rectangle of the input, given
+    /// by its top left corner and size
+    #[arg(long, value_name="X,Y,W,H", value_parser=parse_region)]
+    region: Option<[u32; 4]>,
+
+    /// Gamma correct the input before embedding, or a revealed image once
+    /// recovered, above 1 brightens midtones
+    #[arg(long, value_name="FLOAT", value_parser=parse_gamma)]
+    gamma: Option<f32>,
+
+    /// Rotate the hue of every pixel by this many degrees
+    #[arg(long, value_name="DEG", allow_negative_numbers(true),
+        conflicts_with_all(["reveal"]))]
+    hue_shift: Option<f32>,
+
+    /// Multiply the saturation of every pixel
+    #[arg(long, value_name="FACTOR",
+        conflicts_with_all(["reveal"]))]
+    saturate: Option<f32>,
+
+    /// Multiply the brightness (HSV value) of every pixel
+    #[arg(long, value_name="FACTOR",
+        conflicts_with_all(["reveal"]))]
+    brightness: Option<f32>,
+
+    /// Turn the image into its negative, after any color grading. Revealing
+    /// with it again undoes it on a hidden image concealed with it.
+    #[arg(long)]
+    invert: bool,
+
+    /// Round each channel to the nearest of N evenly spaced levels over its
+    /// full range, after any inversion
+    #[arg(long, value_name="N", value_parser=clap::value_parser!(u32).range(2..),
+        conflicts_with_all(["reveal"]))]
+    posterize: Option<u32>,
+
+    /// Write a single channel grayscale image, for gray outputs
+    #[arg(long,
+        conflicts_with_all(["reveal_file", "reveal_message", "capacity", "analyze", "histogram"]))]
+    grayscale: bool,
+
+    /// Report the progress of each pass on stderr
+    #[arg(long,
+        conflicts_with_all(["quiet"]))]
+    progress: bool,
+
+    /// Run the parallel passes on this many threads instead of one per core,
+    /// 1 being fully serial. Only builds with the rayon feature use threads.
+    #[arg(long, value_name="N", value_parser=clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+
+    /// Print nothing but errors and what the mode itself outputs, such as a
+    /// revealed message, leaving out capacity, progress and batch reports
     #[arg(short, long,
+        conflicts_with_all(["verbose"]))]
+    quiet: bool,
+
+    /// Log each stage of the pipeline as it finishes, with its time, on stderr
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Run every check and report what would be done and written, without
+    /// writing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print capacity, PSNR, analysis and dimensions as a single JSON object
+    /// on stdout, or on stderr when the output is written to stdout
+    #[arg(long)]
+    json: bool,
+
+    /// Diffuse the error of cutting samples down to the bits kept, smoothing
+    /// out banding in gradients
+    #[arg(long,
         conflicts_with_all(["reveal"]))]
-    equalize: bool,
+    dither: bool,
+
+    /// Top of the range the kept bits are rescaled onto, rather than the
+    /// sample's maximum, such as 128 for a dim preview
+    #[arg(long, value_name="N",
+        conflicts_with_all(["conceal"]))]
+    rescale_max: Option<u32>,
+
+    /// Background color transparent pixels are composited onto
+    #[arg(long, value_name="R,G,B", value_parser=parse_rgb,
+        default_value("0,0,0"))]
+    background: [u8; 3],
+
+    /// How alpha is turned into color: composited as straight or
+    /// premultiplied alpha, or ignored, keeping the colors as stored
+    #[arg(long, value_name="MODE", value_enum,
+        conflicts_with("keep_alpha"),
+        default_value("composite"))]
+    alpha_mode: AlphaMode,
+
+    /// Effort spent deflating a PNG output, trading encode time for file size.
+    /// The payload is the same either way.
+    #[arg(long, value_name="LEVEL", value_enum,
+        default_value("fast"))]
+    compression: Compression,
+
+    /// Write the result downscaled to this width instead, keeping its aspect
+    /// ratio, for a quick look at a huge image. The payload doesn't survive it
+    #[arg(long, value_name="WIDTH", value_parser=clap::value_parser!(u32).range(1..),
+        conflicts_with_all(["split", "reveal_file", "reveal_message", "capacity", "analyze",
+            "histogram"]))]
+    preview: Option<u32>,
+
+    /// Bits per sample of the output, 8 or 16, scaling samples to it from
+    /// the depth of the input
+    #[arg(long, value_name="8|16", value_parser=parse_depth,
+        conflicts_with_all(["reveal_file", "reveal_message", "capacity", "analyze", "histogram"]))]
+    out_depth: Option<u8>,
+
+    /// Conceal even into a lossy output such as JPEG, which scrambles the
+    /// payload
+    #[arg(long)]
+    force_lossy: bool,
+
+    /// Carry the alpha channel through instead of compositing it
+    #[arg(short='a', long)]
+    keep_alpha: bool,
+
+    /// Refuse to write over any file that already exists, naming it, rather
+    /// than replacing it
+    #[arg(long)]
+    no_clobber: bool,
+
+    /// Drop the gamma, color profile, text and other metadata of the source
+    /// PNG instead of copying them to the output
+    #[arg(long)]
+    strip_metadata: bool,
+
+    /// Decode, process and encode a PNG a row at a time, for images too large
+    /// to hold in memory. Only the passes that look at one pixel at a time run:
+    /// color grading, invert, posterize, the cut to --bits, the cipher, and
+    /// concealing and revealing an image in the top left corner
+    #[arg(long,
+        conflicts_with_all(["batch", "layer", "split", "combine", "conceal_pos", "redundant",
+            "verify", "highlight", "capacity", "conceal_file", "message", "reveal_file",
+            "reveal_message", "extract_lsb", "bit_planes", "analyze", "histogram", "diff",
+            "kdf_iterations", "permute_planes", "scatter", "adaptive", "authenticate",
+            "checksum", "auto_bits", "stretch", "equalize", "equalize_adaptive", "region",
+            "gamma", "grayscale", "progress", "dry_run", "dither", "preview", "out_depth",
+            "keep_alpha"]))]
+    stream: bool,
+}
+
+// Parse a comma separated color such as "255,255,255"
+fn parse_rgb(s: &str) -> Result<[u8; 3], String> {
+    let c: Vec<u8> = s.split(',')
+        .map(|c| c.trim().parse::<u8>().map_err(|e| format!("{}: {}", c, e)))
+        .collect::<Result<_, _>>()?;
+
+    c.try_into().map_err(|_| "expected three values R,G,B".to_string())
+}
+
+// Parse a bit count for every channel, or one for each as "2,1,3"
+fn parse_bits(s: &str) -> Result<[u8; 3], String> {
+    let b: Vec<u8> = s.split(',')
+        .map(|b| match b.trim().parse::<u8>() {
+            Ok(b) if (1..=16).contains(&b) => Ok(b),
+            Ok(_) => Err(format!("{}: expected bits from 1 to 16", b)),
+            Err(e) => Err(format!("{}: {}", b, e)),
+        })
+        .collect::<Result<_, _>>()?;
+
+    match b[..] {
+        [b] => Ok([b; 3]),
+        _ => b.try_into().map_err(|_| "expected one value or three values R,G,B".to_string()),
+    }
+}
+
+// Parse an output sample depth
+fn parse_depth(s: &str) -> Result<u8, String> {
+    match s {
+        "8" => Ok(8),
+        "16" => Ok(16),
+        _ => Err("expected 8 or 16".to_string()),
+    }
+}
+
+// Parse a subset of the channels such as "rb"
+fn parse_channels(s: &str) -> Result<[bool; 3], String> {
+    let mut channels = [false; 3];
+    for c in s.chars() {
+        match "rgb".find(c.to_ascii_lowercase()) {
+            Some(k) => channels[k] = true,
+            None => return Err(format!("{}: expected channels r, g and b", c)),
+        }
+    }
+
+    match channels {
+        [false, false, false] => Err("expected at least one channel".to_string()),
+        channels => Ok(channels),
+    }
+}
+
+// Parse a range of bits such as "2-3", lowest first
+fn parse_planes(s: &str) -> Result<[u8; 2], String> {
+    let (lo, hi) = s.split_once('-').unwrap_or((s, s));
+    let bit = |b: &str| b.trim().parse::<u8>().map_err(|e| format!("{}: {}", b, e));
+
+    match [bit(lo)?, bit(hi)?] {
+        [lo, hi] if lo <= hi && hi < 16 => Ok([lo, hi]),
+        _ => Err("expected bits LO-HI from 0 to 15, lowest first".to_string()),
+    }
+}
+
+// Parse a layer such as "hidden.png:2-3"
+fn parse_layer(s: &str) -> Result<(PathBuf, [u8; 2]), String> {
+    let (path, planes) = s.rsplit_once(':').ok_or("expected IMAGE:LO-HI")?;
+    Ok((PathBuf::from(path), parse_planes(planes)?))
+}
+
+// Parse a rectangle such as "10,20,100,50", which must not be empty
+fn parse_region(s: &str) -> Result<[u32; 4], String> {
+    let r: Vec<u32> = s.split(',')
+        .map(|c| c.trim().parse::<u32>().map_err(|e| format!("{}: {}", c, e)))
+        .collect::<Result<_, _>>()?;
+
+    match r[..] {
+        [_, _, 0, _] | [_, _, _, 0] => Err("expected a width and height above 0".to_string()),
+        [x, y, w, h] => Ok([x, y, w, h]),
+        _ => Err("expected four values X,Y,W,H".to_string()),
+    }
+}
+
+// Parse a clip percentage, which must leave some of the histogram unclipped
+fn parse_percent(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(p) if (0.0..50.0).contains(&p) => Ok(p),
+        Ok(_) => Err("expected a percentage of at least 0 and below 50".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Parse a gamma value, which must be positive
+fn parse_gamma(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(g) if g > 0.0 && g.is_finite() => Ok(g),
+        Ok(_) => Err("expected a positive number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Parse an equalize channel, explaining why hue is not one
+fn parse_channel(s: &str) -> Result<EqualizeChannel, String> {
+    if s.eq_ignore_ascii_case("hue") {
+        return Err("hue is circular and cannot be equalized".to_string());
+    }
+
+    EqualizeChannel::from_str(s, true)
 }
 
 fn main() {
     let args = Args::parse();
+    if args.selftest {
+        process::exit(if selftest() { 0 } else { 1 });
+    } else if args.list_formats {
+        list_formats();
+        return;
+    }
 
-    let (width, height, mut buf) = img::read_image_rgb8(args.input);
+    let threads = args.threads;
+    let result = match args.batch.clone() {
+        Some(dir) => with_threads(threads, || batch(dir, args)),
+        None => with_threads(threads, || run(args).map(|_| true)),
+    };
+    match result {
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("rsteg: {}", e);
+            process::exit(exit_code(&e));
+        },
+    }
+}
 
-    // Contrast stretching algorithm for normalization
-    if args.stretch {
-        img::stretch(&mut buf);
+// Exit status for each kind of error, so scripts can tell them apart. clap
+// exits 2 for arguments it refuses, and 1 is left for a batch or selftest
+// where some image failed.
+fn exit_code(e: &ImgError) -> i32 {
+    match e {
+        ImgError::NotFound(_) => 3,
+        ImgError::Decode(_) | ImgError::SampleSize(_) => 4,
+        ImgError::Format(_) => 5,
+        ImgError::DimensionMismatch | ImgError::HiddenTooLarge(..) => 6,
+        ImgError::Capacity(..) => 7,
+        ImgError::BitDepth(..) | ImgError::UniformBits | ImgError::PlaneOverlap(..) => 8,
+        ImgError::NoPayload => 9,
+        ImgError::Authentication => 10,
+        ImgError::Io(_) => 11,
     }
+}
 
-    // Histogram equalization algorithm, normalizes HSV value
-    else if args.equalize {
-        img::equalize(&mut buf);
+// Runs on every image in a directory whose extension is read, in parallel
+// with rayon. One failing leaves the others be, and the outcome of each is
+// printed at the end. True if all of them succeed.
+fn batch(dir: PathBuf, args: Args) -> Result<bool, ImgError> {
+    let readable = |path: &Path| path.extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|e| img::FORMATS.iter().any(|f| f.extensions.contains(&e.as_str())));
+    let mut inputs: Vec<PathBuf> = fs::read_dir(&dir).map_err(|e| ImgError::io(&dir, e))?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.is_file() && readable(path))
+        .collect();
+    inputs.sort();
+
+    if args.output.as_os_str() == "-" {
+        return Err(ImgError::Format("a batch needs an output directory".to_string()));
+    } else if !args.dry_run {
+        fs::create_dir_all(&args.output)
+            .map_err(|e| ImgError::Io(format!("{}: {}", args.output.display(), e)))?;
     }
 
-    if !args.reveal {
-        for c in buf.iter_mut() {
-            *c >>= 8 - args.bits;
+    let one = |input: &PathBuf| {
+        let mut args = args.clone();
+        args.output = args.output.join(input.file_name().unwrap());
+        args.input = Some(input.clone());
+        run(args)
+    };
+    #[cfg(feature = "rayon")]
+    let results: Vec<_> = {
+        use rayon::prelude::*;
+        inputs.par_iter().map(one).collect()
+    };
+    #[cfg(not(feature = "rayon"))]
+    let results: Vec<_> = inputs.iter().map(one).collect();
+
+    let mut failed = 0;
+    for (input, result) in inputs.iter().zip(&results) {
+        let name = input.file_name().unwrap().to_string_lossy();
+        match result {
+            Ok(()) if args.quiet => {},
+            Ok(()) => println!("ok   {}", name),
+            Err(e) => {
+                println!("FAIL {}: {}", name, e);
+                failed += 1;
+            },
+        }
+    }
+    if !args.quiet {
+        eprintln!("{} of {} images processed", inputs.len() - failed, inputs.len());
+    }
+    Ok(failed == 0)
+}
+
+// One line per format: its extensions, whether it is read and written, and
+// whether it keeps a payload
+fn list_formats() {
+    for f in img::FORMATS {
+        let extensions: Vec<String> = f.extensions.iter().map(|e| format!(".{}", e)).collect();
+        let access = if f.write.is_some() { "read, write" } else { "read" };
+        let safety = if f.lossless { "lossless" } else { "lossy, unsafe for payloads" };
+        println!("{:<6}{:<14}{:<13}{}", f.name, extensions.join(" "), access, safety);
+    }
+}
+
+// Round trips of the core passes on generated images, printing PASS or FAIL
+// for each. True if all of them pass.
+fn selftest() -> bool {
+    type Check = fn() -> Result<bool, ImgError>;
+    let checks: [(&str, Check); 5] = [
+        ("conceal and reveal an image", selftest_conceal),
+        ("conceal and reveal a 16-bit image", selftest_conceal_16),
+        ("encrypt and decrypt", selftest_cipher),
+        ("embed and extract a message", selftest_bytes),
+        ("convert RGB to HSV and back", selftest_hsv),
+    ];
+
+    let mut passed = true;
+    for (name, check) in checks {
+        let result = check();
+        match &result {
+            Ok(true) => println!("PASS {}", name),
+            Ok(false) => println!("FAIL {}", name),
+            Err(e) => println!("FAIL {}: {}", name, e),
         }
+        passed &= matches!(result, Ok(true));
+    }
+    passed
+}
+
+// Samples spread over the whole range, different for each seed
+fn selftest_samples<T: Sample>(len: usize, seed: usize) -> Vec<T> {
+    (0..len).map(|i| T::from_u32((i * 37 + seed).wrapping_mul(2654435761) as u32 % (T::MAX + 1)))
+        .collect()
+}
+
+// Conceal a hidden image in a cover and reveal it back, equal up to the bits
+// dropped for each channel
+fn selftest_round_trip<T: Sample>(bits: [u8; 3]) -> Result<bool, ImgError> {
+    let hidden: Vec<T> = selftest_samples(16 * 12 * 3, 1);
+    let cover = (64, 48, selftest_samples(64 * 48 * 3, 2), None);
+    let shifted: Vec<T> = hidden.iter().enumerate()
+        .map(|(i, c)| T::from_u32(c.to_u32() >> (T::DEPTH - bits[i % 3])))
+        .collect();
+
+    let (width, _, stego, _) = img::conceal((16, 12, &shifted), cover, bits, Plane::Low, 0,
+        Placement::Center, false, None, None)?;
+    let header = stego::read_header(&stego, stego::header_bits(bits))
+        .filter(|h| h.channels == bits)
+        .ok_or(ImgError::NoPayload)?;
+    let Payload::Image { width: w, height: h, x, y } = header.payload else {
+        return Err(ImgError::NoPayload);
+    };
+
+    let revealed = img::reveal_rgb(&img::crop(&stego, 3, width, x, y, w, h), bits);
+    Ok((w, h) == (16, 12) && revealed.iter().zip(&hidden).enumerate()
+        .all(|(i, (r, c))| r.to_u32().abs_diff(c.to_u32()) <= 1 << (T::DEPTH - bits[i % 3])))
+}
+
+fn selftest_conceal() -> Result<bool, ImgError> {
+    selftest_round_trip::<u8>([3, 2, 4])
+}
+
+fn selftest_conceal_16() -> Result<bool, ImgError> {
+    selftest_round_trip::<u16>([4; 3])
+}
+
+// Ciphering twice with one key gives back the original, once changes it
+fn selftest_cipher() -> Result<bool, ImgError> {
+    let original: Vec<u8> = selftest_samples(64 * 48 * 3, 3);
+    let mut buf = original.clone();
+    let key = key::from_password("selftest");
+
+    img::stream_cipher_rgb(&mut buf, key, [8; 3]);
+    let changed = buf != original;
+    img::stream_cipher_rgb(&mut buf, key, [8; 3]);
+    Ok(changed && buf == original)
+}
+
+// A compressed, error corrected, ciphered, scattered and authenticated
+// message comes back whole
+fn selftest_bytes() -> Result<bool, ImgError> {
+    let mut buf: Vec<u8> = selftest_samples(64 * 48 * 3, 4);
+    let (key, scatter) = (key::from_password("selftest"), key::from_u64(7));
+    let message = "rsteg self-test message, rsteg self-test message".as_bytes();
+
+    let embedding = Embedding {
+        message: true,
+        compress: true,
+        ecc: true,
+        checksum: true,
+        kdf: None,
+        key: Some(key),
+        scatter: Some(scatter),
+        mac: Some(key),
+    };
+    stego::embed_bytes(&mut buf, 2, message, &embedding)?;
+    let (payload, data) = stego::extract_bytes(&buf, 2, Some(key), Some(scatter))?;
+    let intact = stego::bytes_checksum_matches(&buf, 2, Some(scatter)) == Some(true);
+    Ok(matches!(payload, Payload::Message { .. }) && data == message && intact)
+}
+
+// Colors come back from HSV as they went in, give or take the level
+// to_rgb can lose truncating
+fn selftest_hsv() -> Result<bool, ImgError> {
+    let colors: Vec<u8> = selftest_samples(4096 * 3, 5);
+    Ok(colors.chunks_exact(3).all(|p| {
+        let rgb = HSVColor::from_rgb(p[0], p[1], p[2], 8).to_rgb(8);
+        rgb.iter().zip(p).all(|(a, b)| a.abs_diff(*b as u16) <= 1)
+    }))
+}
+
+// Runs f on a pool of that many threads, or on rayon's global pool of one
+// per core
+#[cfg(feature = "rayon")]
+fn with_threads<R: Send>(threads: Option<u32>, f: impl FnOnce() -> Result<R, ImgError> + Send)
+        -> Result<R, ImgError> {
+    match threads {
+        Some(n) => rayon::ThreadPoolBuilder::new().num_threads(n as usize).build()
+            .map_err(|e| ImgError::Io(format!("thread pool failed to start: {}", e)))?
+            .install(f),
+        None => f(),
+    }
+}
+
+// Without rayon every pass is serial whatever the count
+#[cfg(not(feature = "rayon"))]
+fn with_threads<R>(_threads: Option<u32>, f: impl FnOnce() -> Result<R, ImgError>)
+        -> Result<R, ImgError> {
+    f()
+}
+
+// Informational output, printed as prose as it comes or with --json
+// gathered into one object printed at the end
+struct Report {
+    json: bool,
+    quiet: bool,
+    verbose: bool,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Report {
+    // Record a value already written as JSON
+    fn field(&mut self, name: &'static str, value: impl ToString) {
+        self.fields.push((name, value.to_string()));
+    }
+
+    // How much of the cover's capacity a payload takes up
+    fn usage(&mut self, used: usize, capacity: usize) {
+        let percent = used as f64 * 100.0 / capacity.max(1) as f64;
+        if self.json {
+            self.field("payload_bytes", used);
+            self.field("capacity_bytes", capacity);
+            self.field("used_percent", number(percent));
+            return;
+        } else if self.quiet {
+            return;
+        }
+
+        let size = |n: usize| match n {
+            n if n >= 1 << 20 => format!("{:.1}MB", n as f64 / (1 << 20) as f64),
+            n if n >= 1 << 10 => format!("{:.1}KB", n as f64 / (1 << 10) as f64),
+            n => format!("{}B", n),
+        };
+        eprintln!("embedded {} of {} capacity ({:.0}%)", size(used), size(capacity), percent);
+    }
+
+    // Bits chosen by --auto-bits
+    fn bits(&mut self, bits: u8) {
+        if self.json {
+            self.field("chosen_bits", bits);
+        } else if !self.quiet {
+            eprintln!("chose {} bits, reveal with --bits {}", bits, bits);
+        }
+    }
+
+    // Run one stage of the pipeline, logging how long it took with --verbose
+    fn stage<R>(&mut self, name: &str, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = f();
+        self.log(name, start);
+        result
+    }
+
+    fn log(&self, name: &str, start: Instant) {
+        if self.verbose {
+            eprintln!("{}: {:.1?}", name, start.elapsed());
+        }
+    }
+
+    // Whether a revealed payload matched its checksum
+    fn checksum(&mut self, intact: bool) {
+        let status = if intact { "OK" } else { "CORRUPT" };
+        if self.json {
+            self.field("checksum", string(status));
+        } else {
+            eprintln!("checksum: {}", status);
+        }
+    }
+
+    fn psnr(&mut self, mse: f64, psnr: f64) {
+        if self.json {
+            self.field("mse", number(mse));
+            self.field("psnr", number(psnr));
+        } else if !self.quiet {
+            eprintln!("PSNR: {:.2} dB (MSE {:.4})", psnr, mse);
+        }
+    }
+
+    // What a dry run would have done, and written where
+    fn plan(&mut self, operations: &[&str], output: String) {
+        if self.json {
+            let names: Vec<String> = operations.iter().map(|o| string(o)).collect();
+            self.field("operations", format!("[{}]", names.join(",")));
+            self.field("would_write", string(&output));
+        } else {
+            eprintln!("dry run: {}", operations.join(", "));
+            eprintln!("would write {}", output);
+        }
+    }
+
+    // Print the JSON object, to stderr when stdout carries the image
+    fn finish(&self, stderr: bool) {
+        if !self.json {
+            return;
+        }
+
+        let body: Vec<String> = self.fields.iter()
+            .map(|(name, value)| format!("\"{}\":{}", name, value))
+            .collect();
+        match stderr {
+            true => eprintln!("{{{}}}", body.join(",")),
+            false => println!("{{{}}}", body.join(",")),
+        }
+    }
+}
+
+// Bars of a histogram scaled to the fullest bin, labeled with the range of
+// levels in each
+fn print_histogram(name: &str, hist: &[usize], levels: usize) {
+    let width = levels / hist.len();
+    let most = hist.iter().copied().max().unwrap_or(0).max(1);
+    eprintln!("{}", name);
+    for (i, &n) in hist.iter().enumerate() {
+        let bar = "#".repeat(n * 50 / most);
+        eprintln!("{:>5}-{:<5} {:<50} {}", i * width, (i + 1) * width - 1, bar, n);
+    }
+}
+
+// JSON number, null where it isn't finite like the PSNR of identical images.
+// Tiny probabilities are written in exponent form rather than in full.
+fn number(v: f64) -> String {
+    match v {
+        v if !v.is_finite() => "null".to_string(),
+        v if v != 0.0 && v.abs() < 1e-6 => format!("{:e}", v),
+        v => v.to_string(),
+    }
+}
+
+// JSON string, escaping quotes, backslashes and control characters
+fn string(s: &str) -> String {
+    let escaped: String = s.chars().map(|c| match c {
+        '"' | '\\' => format!("\\{}", c),
+        c if c.is_control() => format!("\\u{:04x}", c as u32),
+        c => c.to_string(),
+    }).collect();
+    format!("\"{}\"", escaped)
+}
+
+// Names of the steps an invocation runs, in order
+fn operations(args: &Args) -> Vec<&'static str> {
+    let steps = [
+        (args.conceal_file.is_some(), "conceal file"),
+        (args.message.is_some(), "conceal message"),
+        (args.compress, "compress"),
+        (args.ecc, "ecc"),
+        (args.checksum, "checksum"),
+        (args.reveal_file, "reveal file"),
+        (args.bit_planes, "bit planes"),
+        (args.extract_lsb.is_some(), "extract lsb"),
+        (args.diff.is_some(), "diff"),
+        (args.reveal, "reveal"),
+        (!args.combine.is_empty(), "combine"),
+        (args.stretch, "stretch"),
+        (args.equalize, "equalize"),
+        (args.equalize_adaptive, "adaptive equalize"),
+        (args.gamma.is_some(), "gamma"),
+        (args.hue_shift.is_some() || args.saturate.is_some() || args.brightness.is_some(), "hsv"),
+        (args.invert, "invert"),
+        (args.posterize.is_some(), "posterize"),
+        (args.permute_planes, "permute planes"),
+        (args.dither, "dither"),
+        (args.key.is_some() || args.key_hex.is_some() || args.password.is_some()
+            || args.key_file.is_some(), "cipher"),
+        (!args.layer.is_empty(), "layers"),
+        (args.adaptive, "adaptive"),
+        (args.split.is_some(), "split"),
+        (args.decoy.is_some(), "decoy"),
+        (args.redundant.is_some(), "redundant"),
+        (args.conceal.is_some(), "conceal"),
+        (args.highlight.is_some(), "highlight"),
+        (args.grayscale, "grayscale"),
+        (args.preview.is_some(), "preview"),
+        (args.out_depth.is_some(), "out depth"),
+    ];
+    steps.into_iter().filter(|(on, _)| *on).map(|(_, name)| name).collect()
+}
+
+fn run(args: Args) -> Result<(), ImgError> {
+    if args.progress {
+        progress::enable();
+    }
+    if args.stream {
+        return stream(args);
+    }
+
+    // A payload doesn't survive lossy compression of the stego image
+    let embeds = args.conceal.is_some() || args.conceal_file.is_some() || args.message.is_some();
+    let format = Format::from_path(&args.output)?.handler();
+    if embeds && !format.lossless && !args.force_lossy {
+        return Err(ImgError::Format(format!("{} is lossy and would destroy the payload, \
+            --force-lossy writes it anyway", format.name)));
+    }
+
+    // 16-bit sources are processed at full depth end to end
+    let mut report = Report { json: args.json, quiet: args.quiet, verbose: args.verbose,
+        fields: Vec::new() };
+    let input = args.input.clone().expect("input is required");
+    let (frames, animation, mut meta) = report.stage("read", || img::read_frames(input))?;
+    if args.strip_metadata {
+        meta = Metadata::default();
+    }
+    let (width, height, ref raw, _) = frames[0];
+
+    report.field("width", width);
+    report.field("height", height);
+
+    let stderr = args.output.as_os_str() == "-";
+    let result = match raw.depth() {
+        16 => process_frames::<u16>(args, frames, animation, meta, &mut report),
+        _ => process_frames::<u8>(args, frames, animation, meta, &mut report),
+    };
+    report.finish(stderr);
+    result
+}
+
+// Run --stream on the input at its own depth, with the metadata of the cover
+// for a stego image as process gives it
+fn stream(args: Args) -> Result<(), ImgError> {
+    if Format::from_path(&args.output)? != Format::Png {
+        return Err(ImgError::Format("only PNG can be written a row at a time".to_string()));
+    }
+
+    let mut report = Report { json: args.json, quiet: args.quiet, verbose: args.verbose,
+        fields: Vec::new() };
+    let input = args.input.clone().expect("input is required");
+    let meta = match (args.strip_metadata, &args.conceal) {
+        (true, _) => Metadata::default(),
+        (_, Some(cover)) => img::read_metadata(cover.clone())?,
+        _ => img::read_metadata(input.clone())?,
+    };
+    let reader = RowReader::open(input, args.background, args.alpha_mode)?;
+    report.field("width", reader.width());
+    report.field("height", reader.height());
+
+    let stderr = args.output.as_os_str() == "-";
+    unclobbered(&args.output, args.no_clobber)?;
+    let start = Instant::now();
+    let result = match reader.depth() {
+        16 => stream_rows::<u16>(args, reader, &meta, &mut report),
+        _ => stream_rows::<u8>(args, reader, &meta, &mut report),
+    };
+    report.log("stream", start);
+    report.finish(stderr);
+    result
+}
+
+// The passes of process that need no more than a row, run on each row as it
+// is decoded and written out before the next. Only the rows the header spans
+// are held back, to be read or written as one.
+fn stream_rows<T: Sample>(args: Args, mut input: RowReader, meta: &Metadata,
+        report: &mut Report) -> Result<(), ImgError> {
+    let (bits, offset) = match args.planes {
+        Some([lo, hi]) => ([hi - lo + 1; 3], lo),
+        None => (args.bits, args.bit_offset),
+    };
+    let bits: [u8; 3] = std::array::from_fn(|k| if args.channels[k] { bits[k] } else { 0 });
+    let (min_bits, max_bits) = (stego::header_bits(bits), *bits.iter().max().unwrap());
+    if max_bits + offset > T::DEPTH {
+        return Err(ImgError::BitDepth(max_bits + offset, T::DEPTH));
+    }
+    let (width, height) = (input.width(), input.height());
+    let truncated = || ImgError::Decode("PNG ended before its last row".to_string());
+
+    // A revealed image is cut out to the size its header records, and the
+    // key stretched as it says
+    if args.reveal {
+        let reserved = ((stego::HEADER_LEN + stego::TAG_LEN + stego::CHECKSUM_LEN
+            + stego::KDF_LEN) * 8).div_ceil(min_bits.max(1) as usize);
+        let rows = (reserved.div_ceil(width as usize * 3) as u32).min(height);
+        let mut held = Vec::new();
+        for _ in 0..rows {
+            held.extend(input.next_row::<T>()?.ok_or_else(truncated)?);
+        }
+
+        let header = stego::read_header_at(&held, min_bits, offset).filter(|h| h.channels == bits);
+        if header.as_ref().is_some_and(|header| !header.fits(width, height)) {
+            return Err(if args.force { ImgError::DimensionMismatch } else { ImgError::NoPayload });
+        }
+        let (w, h, x, y, plane) = match header {
+            Some(Header { authenticated: true, .. }) => {
+                return Err(ImgError::Format("an authenticated payload can't be verified a row \
+                    at a time".to_string()));
+            },
+            Some(Header { payload: Payload::Image { width: w, height: h, x, y }, plane, .. }) => {
+                (w, h, x, y, plane)
+            },
+            _ if args.force => (width, height, 0, 0, args.plane),
+            _ => return Err(ImgError::NoPayload),
+        };
+        let kdf = header.and_then(|h| stego::read_kdf(&held, &h));
+        let key = args.key_cipher.or(secret(&args, kdf)?);
+
+        let shifts = bits.map(|b| if plane == Plane::Low { offset } else { T::DEPTH - b });
+        let max = args.rescale_max.unwrap_or(T::MAX);
+        let (x, w_n) = (x as usize * 3, w as usize * 3);
+        let mut out = RowWriter::<T>::create(args.output, w, h, meta, args.compression)?;
+        let held: Vec<Vec<T>> = held.chunks_exact(width as usize * 3).map(<[T]>::to_vec).collect();
+        let mut rows = held.into_iter().map(Ok)
+            .chain(std::iter::from_fn(|| input.next_row::<T>().transpose()))
+            .skip(y as usize);
+        for i in 0..h as usize {
+            let mut row = rows.next().ok_or_else(truncated)??[x..x + w_n].to_vec();
+            if args.invert {
+                img::invert(&mut row);
+            }
+            if plane == Plane::High || offset > 0 {
+                for (j, c) in row.iter_mut().enumerate() {
+                    *c = T::from_u32(c.to_u32() >> shifts[j % 3]);
+                }
+            }
+            if let Some(key) = key {
+                img::stream_cipher_at(&mut row, key, bits, i * w_n);
+            }
+            img::rescale_bits_to(&mut row, bits, max);
+            out.write_row(&row)?;
+        }
+        return out.finish();
+    }
+
+    // Color grading and the cut to the top bits, then the cipher, of a plain
+    // image or one to be concealed
+    let key = args.key_cipher.or(secret(&args, None)?);
+    let kept = if args.conceal.is_some() {
+        bits
+    } else {
+        bits.map(|b| if b == 0 { T::DEPTH } else { b })
+    };
+    let grade = |row: &mut Vec<T>, start: usize| {
+        if args.hue_shift.is_some() || args.saturate.is_some() || args.brightness.is_some() {
+            img::adjust_hsv(row, args.hue_shift.unwrap_or(0.0), args.saturate.unwrap_or(1.0),
+                args.brightness.unwrap_or(1.0));
+        }
+        if args.invert {
+            img::invert(row);
+        }
+        if let Some(levels) = args.posterize {
+            img::posterize(row, levels);
+        }
+        for (i, c) in row.iter_mut().enumerate() {
+            *c = T::from_u32(c.to_u32() >> (T::DEPTH - kept[i % 3]));
+        }
+        if let Some(key) = key {
+            img::stream_cipher_at(row, key, bits, start);
+        }
+    };
+
+    let Some(path) = args.conceal.clone() else {
+        let mut out = RowWriter::<T>::create(args.output, width, height, meta, args.compression)?;
+        let (max, mut start) = (args.rescale_max.unwrap_or(T::MAX), 0);
+        while let Some(mut row) = input.next_row::<T>()? {
+            grade(&mut row, start);
+            img::rescale_bits_to(&mut row, kept, max);
+            start += row.len();
+            out.write_row(&row)?;
+        }
+        return out.finish();
+    };
+
+    // Rows of the hidden image go over the top rows of the cover as both are
+    // read, and the header over the rows it spans once they all are
+    let mut cover = RowReader::open(path, args.background, args.alpha_mode)?;
+    let (c_width, c_height) = (cover.width(), cover.height());
+    if width > c_width || height > c_height {
+        return Err(ImgError::HiddenTooLarge((c_width, c_height), (width, height)));
+    }
+    let offset = if args.plane == Plane::High { 0 } else { offset };
+    let header = Header {
+        payload: Payload::Image { width, height, x: 0, y: 0 },
+        bits: min_bits,
+        channels: bits,
+        authenticated: false,
+        plane: args.plane,
+        offset,
+        compressed: false,
+        ecc: false,
+        checksum: false,
+        kdf: false,
+    };
+    let (reserved, len) = (header.reserved_slots(), c_width as usize * c_height as usize * 3);
+    if reserved > len {
+        let bytes = |n: usize| n * min_bits as usize / 8;
+        return Err(ImgError::Capacity(bytes(reserved), bytes(len)));
+    }
+    report.usage(stego::capacity(width, height, bits), stego::capacity(c_width, c_height, bits));
+
+    let head = reserved.div_ceil(c_width as usize * 3);
+    let mut out = RowWriter::<T>::create(args.output, c_width, c_height, meta,
+        args.compression)?;
+    let mut held = Vec::new();
+    for y in 0..c_height as usize {
+        let mut row = cover.next_row::<T>()?.ok_or_else(truncated)?;
+        if y < height as usize {
+            let mut hidden = input.next_row::<T>()?.ok_or_else(truncated)?;
+            grade(&mut hidden, y * width as usize * 3);
+            stream::conceal_row(&mut row[..hidden.len()], &hidden, bits, args.plane, offset);
+        }
+        if y >= head {
+            out.write_row(&row)?;
+            continue;
+        }
+
+        held.extend(row);
+        if y + 1 == head {
+            stego::write_header(&mut held, &header);
+            for row in held.chunks_exact(c_width as usize * 3) {
+                out.write_row(row)?;
+            }
+        }
+    }
+    out.finish()
+}
+
+// Process each frame of an animation the same way, or a still image on its
+// own. Modes that only report on the input look at the first frame alone.
+fn process_frames<T: Sample>(args: Args, frames: Vec<Raw>, animation: Option<Animation>,
+        mut meta: Metadata, report: &mut Report) -> Result<(), ImgError> {
+    let (output, dry_run, operations) = (args.output.clone(), args.dry_run, operations(&args));
+    let (compression, preview, depth) = (args.compression, args.preview, args.out_depth);
+    let no_clobber = args.no_clobber;
+    let plan = |w: u32, h: u32, frames: usize| {
+        let frames = if frames > 1 { format!(" of {} frames", frames) } else { String::new() };
+        format!("{}x{} image{} to {}", w, h, frames, output.display())
+    };
+
+    let Some(animation) = animation else {
+        let (width, height, raw, samples) = frames.into_iter().next().unwrap();
+        let image = process::<T>(args, width, height, raw, samples, &mut meta, report)?;
+        if let Some((w, h, buf, alpha)) = image.map(|image| shrink(image, preview)) {
+            if dry_run {
+                report.plan(&operations, plan(w, h, 1));
+            } else {
+                unclobbered(&output, no_clobber)?;
+                let frames = vec![(w, h, buf, alpha)];
+                report.stage("write", || {
+                    write_at(frames, None, depth, output, &meta, compression)
+                })?;
+            }
+        }
+        return Ok(());
+    };
+
+    if args.split.is_some() {
+        return Err(ImgError::Format("an animation can't be split into shares".to_string()));
+    }
+
+    // Later frames report nothing, into a report that is never printed
+    let mut quiet = Report { json: true, quiet: true, verbose: false, fields: Vec::new() };
+    let mut out = Vec::new();
+    for (i, (width, height, raw, samples)) in frames.into_iter().enumerate() {
+        let report = if i == 0 { &mut *report } else { &mut quiet };
+        match process::<T>(args.clone(), width, height, raw, samples, &mut meta, report)? {
+            Some(frame) => out.push(shrink(frame, preview)),
+            None => return Ok(()),
+        }
+    }
+
+    if dry_run {
+        report.plan(&operations, plan(out[0].0, out[0].1, out.len()));
+        return Ok(());
+    }
+    unclobbered(&output, no_clobber)?;
+    report.stage("write", || write_at(out, Some(&animation), depth, output, &meta, compression))
+}
+
+// Write a still image or the frames of an animation, scaled to the output
+// depth if it differs from that they were processed at
+fn write_at<T: Sample>(frames: Vec<Rgba<T>>, animation: Option<&Animation>, depth: Option<u8>,
+        output: PathBuf, meta: &Metadata, compression: Compression) -> Result<(), ImgError> {
+    fn scale<T: Sample, U: Sample>(frames: Vec<Rgba<T>>) -> Vec<Rgba<U>> {
+        let scale = |b: Vec<T>| {
+            b.into_iter().map(|c| U::from_depth(c.to_u32(), T::DEPTH)).collect()
+        };
+        frames.into_iter().map(|(w, h, buf, alpha)| (w, h, scale(buf), alpha.map(scale))).collect()
+    }
+
+    match (depth, animation) {
+        (Some(16), _) if T::DEPTH != 16 => {
+            write_at(scale::<T, u16>(frames), animation, None, output, meta, compression)
+        },
+        (Some(8), _) if T::DEPTH != 8 => {
+            write_at(scale::<T, u8>(frames), animation, None, output, meta, compression)
+        },
+        (_, Some(animation)) => img::write_frames(&frames, animation, meta, compression, output),
+        (_, None) => {
+            let (w, h, buf, alpha) = &frames[0];
+            img::write_image_with(buf, alpha.as_deref(), *w, *h, output, meta, compression)
+        },
+    }
+}
+
+// Runs the chosen mode on one image, returning the image to write if it
+// makes one. A stego image takes the metadata of its cover.
+fn process<T: Sample>(args: Args, width: u32, height: u32, raw: Samples, samples: usize,
+        meta: &mut Metadata, report: &mut Report) -> Result<Option<Rgba<T>>, ImgError> {
+    // A file or text message to hide in the input
+    let data = match (&args.conceal_file, &args.message) {
+        (Some(path), _) => {
+            Some((fs::read(path).map_err(|e| ImgError::io(path, e))?, false))
+        },
+        (_, Some(text)) => Some((text.clone().into_bytes(), true)),
+        _ => None,
+    };
+
+    // A layer is revealed from the bits it takes up, as if they were the lowest
+    let (bits, mut offset) = match args.planes {
+        Some([lo, hi]) => ([hi - lo + 1; 3], lo),
+        None => (args.bits, args.bit_offset),
+    };
+    // Channels left out carry no bits
+    let bits: [u8; 3] = std::array::from_fn(|k| if args.channels[k] { bits[k] } else { 0 });
+    let (min_bits, max_bits) = (stego::header_bits(bits), *bits.iter().max().unwrap());
+    if max_bits + offset > T::DEPTH {
+        return Err(ImgError::BitDepth(max_bits + offset, T::DEPTH));
+    }
+
+    // Dry run reporting how much the input can carry
+    if let Some(payload) = args.capacity {
+        return capacity(width, height, bits, payload, report).map(|_| None);
+    }
+
+    // Alpha is either turned into color or carried through untouched
+    let raw = raw.into_depth::<T>();
+    let (mut buf, mut alpha) = if args.keep_alpha {
+        img::split_alpha(raw, samples)?
+    } else {
+        (img::expand_rgb(&raw, samples, args.background, args.alpha_mode)?, None)
+    };
+    let (mut width, mut height) = (width, height);
+
+    // A redundant payload is revealed from the first whole block found, as
+    // if that block were the whole image
+    let reveals = args.reveal || args.reveal_file || args.reveal_message;
+    if let Some(size) = args.redundant.filter(|_| reveals) {
+        let (x, y) = stego::find_block(&buf, width, height, size, min_bits, offset)
+            .ok_or(ImgError::NoPayload)?;
+        buf = img::crop(&buf, 3, width, x, y, size, size);
+        alpha = alpha.map(|a| img::crop(&a, 1, width, x, y, size, size));
+        (width, height) = (size, size);
+    }
+
+    // A payload being revealed records how its password was stretched
+    let stored = stego::read_header_at(&buf, min_bits, offset).filter(|_| reveals)
+        .and_then(|header| stego::read_kdf(&buf, &header));
+    let kdf = stored.or(args.kdf_iterations);
+    let key = secret(&args, kdf)?;
+    let scatter = args.key_scatter.or(key).filter(|_| args.scatter);
+    let key = args.key_cipher.or(key);
+    let mac = key.filter(|_| args.authenticate);
+
+    // Steganalysis of the input, leaving it untouched
+    if args.analyze {
+        for (name, c) in ["r", "g", "b"].iter().zip(analysis::chi_square(&buf)) {
+            if report.json {
+                report.field(name, format!("{{\"probability\":{},\"chi_square\":{},\"pairs\":{}}}",
+                    number(c.probability), number(c.statistic), c.pairs));
+            } else {
+                println!("{}: embedding probability {:.1}% (chi-square {:.2} over {} pairs)",
+                    name.to_uppercase(), c.probability * 100.0, c.statistic, c.pairs);
+            }
+        }
+        return Ok(None);
+    }
+
+    // Histograms, at every 8-bit level for --json and in coarse bins for bars
+    if args.histogram {
+        let names = ["red", "green", "blue", "value"];
+        let bins = if report.json { 256 } else { 16 };
+        for (name, hist) in names.iter().zip(analysis::histogram(&buf, bins)) {
+            if report.json {
+                let counts: Vec<String> = hist.iter().map(|n| n.to_string()).collect();
+                report.field(name, format!("[{}]", counts.join(",")));
+            } else {
+                print_histogram(name, &hist, T::MAX as usize + 1);
+            }
+        }
+        return Ok(None);
+    }
+
+    // Map of the samples changed from the original
+    if let Some(path) = args.diff {
+        let (_, _, original) = img::read_image_as::<T>(path, args.background, args.alpha_mode)?;
+        return Ok(Some((width, height, img::diff(&original, &buf, args.diff_gain)?, None)));
+    }
+
+    // The low bits alone, brought up to full range with no header looked for
+    if let Some(n) = args.extract_lsb {
+        if n > T::DEPTH {
+            return Err(ImgError::BitDepth(n, T::DEPTH));
+        }
+        report.stage("extract", || img::rescale_bits(&mut buf, [n; 3]));
+        return Ok(Some((width, height, buf, alpha)));
+    }
+
+    // Hiding a file or text message in the input, or recovering it. Bytes
+    // are packed as a stream so every channel must carry the same bits.
+    if (data.is_some() || args.reveal_file || args.reveal_message) && bits != [min_bits; 3] {
+        return Err(ImgError::UniformBits);
+    }
+
+    if let Some((data, message)) = data {
+        let embedding = Embedding {
+            message,
+            compress: args.compress,
+            ecc: args.ecc,
+            checksum: args.checksum,
+            kdf: args.kdf_iterations,
+            key,
+            scatter,
+            mac,
+        };
+        // The fewest bits the bytes fit in, within one block when redundant
+        let min_bits = if args.auto_bits {
+            let side = |n: u32| args.redundant.map_or(n, |size| size.min(n)) as usize;
+            let n = side(width) * side(height) * 3;
+            let bits = stego::auto_bits(n, &data, &embedding)?;
+            report.bits(bits);
+            bits
+        } else {
+            min_bits
+        };
+        let bits = [min_bits; 3];
+        let embed = |buf: &mut Vec<T>| {
+            stego::embed_bytes(buf, min_bits, &data, &embedding)
+        };
+        report.stage("conceal", || match args.redundant {
+            Some(size) => img::for_each_block(&mut buf, 3, width, height, size, embed),
+            None => embed(&mut buf),
+        })?;
+        let (first, side) = match args.redundant {
+            Some(size) => (img::crop(&buf, 3, width, 0, 0, size, size), [size; 2]),
+            None => (buf.clone(), [width, height]),
+        };
+        if let Some(Header { payload: Payload::File { len } | Payload::Message { len }, .. })
+                = stego::read_header(&first, min_bits) {
+            report.usage(len as usize, stego::capacity(side[0], side[1], bits));
+        }
+        if let Some(path) = args.highlight.filter(|_| !args.dry_run) {
+            let carriers = stego::byte_positions(&buf, min_bits, scatter).unwrap_or_default();
+            let carriers: Vec<usize> = carriers.into_iter().map(|i| i / 3).collect();
+            let reserved = stego::read_header(&buf, min_bits).map_or(0, |h| h.reserved_slots());
+            unclobbered(&path, args.no_clobber)?;
+            highlight(path, (width, height, &buf), &carriers, reserved, args.compression)?;
+        }
+        if let Some(path) = args.verify {
+            verify(path, &buf, args.background, args.alpha_mode, report)?;
+        }
+        if args.grayscale {
+            buf = img::to_gray(&buf)?;
+        }
+        return Ok(Some((width, height, buf, alpha)));
+    } else if args.reveal_file {
+        let extract = || stego::extract_bytes(&buf, min_bits, key, scatter);
+        let (_, data) = report.stage("reveal", extract)?;
+        if let Some(intact) = stego::bytes_checksum_matches(&buf, min_bits, scatter) {
+            report.checksum(intact);
+        }
+        if args.dry_run {
+            let output = format!("{} bytes to {}", data.len(), args.output.display());
+            report.plan(&operations(&args), output);
+        } else if args.output.as_os_str() == "-" {
+            io::stdout().write_all(&data)?;
+        } else {
+            unclobbered(&args.output, args.no_clobber)?;
+            fs::write(&args.output, data)
+                .map_err(|e| ImgError::Io(format!("{}: {}", args.output.display(), e)))?;
+        }
+        return Ok(None);
+    } else if args.reveal_message {
+        match report.stage("reveal", || stego::extract_bytes(&buf, min_bits, key, scatter))? {
+            (Payload::Message { .. }, data) => println!("{}", String::from_utf8_lossy(&data)),
+            _ => return Err(ImgError::NoPayload),
+        }
+        if let Some(intact) = stego::bytes_checksum_matches(&buf, min_bits, scatter) {
+            report.checksum(intact);
+        }
+        return Ok(None);
+    } else if args.bit_planes && args.dry_run {
+        let first = numbered_path(&args.output, "plane", 0);
+        let output = format!("{} bit planes like {}", max_bits, first.display());
+        report.plan(&operations(&args), output);
+        return Ok(None);
+    } else if args.bit_planes {
+        for bit in 0..max_bits {
+            unclobbered(&numbered_path(&args.output, "plane", bit as usize), args.no_clobber)?;
+        }
+        for bit in 0..max_bits {
+            let plane = img::bit_plane(&buf, bit);
+            let path = numbered_path(&args.output, "plane", bit as usize);
+            img::write_image_with(&plane, None, width, height, path, &Metadata::default(),
+                args.compression)?;
+        }
+        return Ok(None);
+    }
+
+    // Cut a revealed image out to the size recorded in its header
+    let mut plane = args.plane;
+    if args.reveal {
+        let start = Instant::now();
+        let found = stego::read_header_at(&buf, min_bits, offset).filter(|h| h.channels == bits);
+
+        // An authenticated payload the key doesn't open may be a decoy, with
+        // the one it does in the bits above
+        let mut verified = false;
+        let hidden = match (&found, key) {
+            (Some(header), Some(key)) if header.authenticated => {
+                verified = stego::verify(&buf, header, Some(key)).is_ok();
+                stego::read_keyed_header_at(&buf, min_bits, offset + max_bits, key)
+                    .filter(|h| h.channels == bits && !verified)
+            },
+            _ => None,
+        };
+        if hidden.is_some() {
+            offset += max_bits;
+        }
+
+        // A header whose image runs off the input, as once it is cropped,
+        // describes none of it
+        let header = hidden.or(found);
+        if header.as_ref().is_some_and(|header| !header.fits(width, height)) {
+            return Err(if args.force { ImgError::DimensionMismatch } else { ImgError::NoPayload });
+        }
+
+        if let Some(header @ Header { payload: Payload::Image { width: w, height: h, x, y }, .. })
+                = header {
+            if header.authenticated && !verified {
+                stego::verify(&buf, &header, key)?;
+            }
+            plane = header.plane;
+            if header.checksum {
+                let placement = match (scatter, args.adaptive) {
+                    (Some(key), _) => Placement::Scatter(key),
+                    (_, true) => Placement::Adaptive(args.edge_threshold),
+                    _ => Placement::TopLeft,
+                };
+                let pixels = img::carrier_pixels((width, height, &buf), &header, placement)?;
+                report.checksum(stego::checksum_matches(&buf, &header,
+                    &stego::pixel_samples(&pixels)));
+            }
+
+            let reserved = header.reserved_slots();
+            let order = match (scatter, args.adaptive) {
+                (Some(key), _) => {
+                    Some(stego::scatter_pixels(width as usize * height as usize, reserved, key))
+                },
+                (_, true) => {
+                    Some(img::textured_pixels(&buf, width, max_bits + offset, reserved,
+                        args.edge_threshold))
+                },
+                _ => None,
+            };
+            let order = match order {
+                Some(order) if order.len() < w as usize * h as usize => {
+                    return Err(ImgError::NoPayload);
+                },
+                order => order.map(|mut order| {
+                    order.truncate(w as usize * h as usize);
+                    order
+                }),
+            };
+            let cut = |b: &[T], samples| match &order {
+                Some(order) => img::gather(b, samples, order),
+                None => img::crop(b, samples, width, x, y, w, h),
+            };
+            buf = cut(&buf, 3);
+            alpha = alpha.map(|a| cut(&a, 1));
+
+            // The other shares of a split image sit just where this one does
+            for path in &args.combine {
+                let (s_width, s_height, share) =
+                    img::read_image_as::<T>(path.clone(), args.background, args.alpha_mode)?;
+                let s_header = stego::read_header_at(&share, min_bits, offset)
+                    .filter(|s| s.payload == header.payload)
+                    .filter(|_| (s_width, s_height) == (width, height))
+                    .ok_or(ImgError::NoPayload)?;
+                if s_header.authenticated {
+                    stego::verify(&share, &s_header, key)?;
+                }
+                img::combine_shares(&mut buf, &cut(&share, 3))?;
+            }
+            (width, height) = (w, h);
+        } else if !args.force {
+            return Err(ImgError::NoPayload);
+        }
+        report.log("reveal", start);
+    }
+
+    // A revealed image is only normalized once it is brought back up to
+    // full range, as it is too dark to see before
+    let normalizes = args.stretch || args.equalize || args.equalize_adaptive
+        || args.gamma.is_some();
+    if normalizes && !args.reveal {
+        report.stage("normalize", || normalize(&args, &mut buf, width, height))?;
+    }
+
+    // Color grading in HSV
+    if args.hue_shift.is_some() || args.saturate.is_some() || args.brightness.is_some() {
+        report.stage("hsv", || img::adjust_hsv(&mut buf, args.hue_shift.unwrap_or(0.0),
+            args.saturate.unwrap_or(1.0), args.brightness.unwrap_or(1.0)));
+    }
+
+    if args.invert {
+        report.stage("invert", || img::invert(&mut buf));
+    }
+
+    if let Some(levels) = args.posterize {
+        report.stage("posterize", || img::posterize(&mut buf, levels));
+    }
+
+    // Hidden images are cut down to their top bits, as are those revealed
+    // from the high plane, and layers are brought down from their offset. An
+    // image only ciphered keeps the channels left out whole.
+    let kept = if args.conceal.is_some() || args.reveal {
+        bits
+    } else {
+        bits.map(|b| if b == 0 { T::DEPTH } else { b })
+    };
+    if args.dither {
+        img::dither(&mut buf, width, kept);
+    } else if !args.reveal || plane == Plane::High || offset > 0 {
+        let layer = args.reveal && plane == Plane::Low;
+        let shifts = kept.map(|b| if layer { offset } else { T::DEPTH - b });
+        for (i, c) in buf.iter_mut().enumerate() {
+            *c = T::from_u32(c.to_u32() >> shifts[i % 3]);
+        }
+    }
+
+    // Bit planes are shuffled inside the cipher, so put back before it
+    let permute = key.filter(|_| args.permute_planes);
+    if let Some(key) = permute.filter(|_| args.reveal) {
+        report.stage("permute", || img::permute_planes(&mut buf, key, kept, true));
     }
 
     // Encryption/decryption using a stream cipher
-    if let Some(key) = args.key {
-        img::stream_cipher(&mut buf, key, args.bits);
+    if let Some(key) = key {
+        report.stage("cipher", || img::stream_cipher_rgb(&mut buf, key, bits));
+    }
+    if let Some(key) = permute.filter(|_| !args.reveal) {
+        report.stage("permute", || img::permute_planes(&mut buf, key, kept, false));
     }
 
     // Concealing an image in another
+    let steps = operations(&args);
     if let Some(image) = args.conceal {
-        img::conceal(&mut buf, args.bits, width, height, image);
+        if !args.strip_metadata {
+            *meta = img::read_metadata(image.clone())?;
+        }
+        let mut cover = if args.keep_alpha {
+            img::read_image_rgba(image)?
+        } else {
+            let (w, h, b) = img::read_image_as(image, args.background, args.alpha_mode)?;
+            (w, h, b, None)
+        };
+
+        let placement = match (scatter, args.conceal_pos) {
+            _ if args.adaptive => Placement::Adaptive(args.edge_threshold),
+            (Some(key), _) => Placement::Scatter(key),
+            (_, ConcealPos::Topleft) => Placement::TopLeft,
+            (_, ConcealPos::Center) => Placement::Center,
+            (_, ConcealPos::Tile) => Placement::Tile,
+        };
+
+        // Layers go in first, the main payload's header and tag last of all
+        check_layers::<T>(&args.layer, bits, args.plane, offset)?;
+        for (path, planes) in args.layer {
+            let (w, h, b) = img::read_image_as::<T>(path, args.background, args.alpha_mode)?;
+            cover = conceal_layer((w, h, b), cover, planes, key, args.permute_planes,
+                placement, args.kdf_iterations)?;
+        }
+
+        let used = stego::capacity(width, height, bits);
+        if let Some(n) = args.split {
+            let shares = img::split_shares(&buf, bits, n as usize);
+            if !args.dry_run {
+                for i in 0..n as usize {
+                    unclobbered(&numbered_path(&args.output, "share", i), args.no_clobber)?;
+                }
+            }
+            for (i, share) in shares.iter().enumerate() {
+                let (w, h, b, a) = img::conceal((width, height, share), cover.clone(), bits,
+                    args.plane, offset, placement, args.checksum, args.kdf_iterations, mac)?;
+                if i == 0 {
+                    report.usage(used, stego::capacity(w, h, bits));
+                }
+                let path = numbered_path(&args.output, "share", i);
+                if !args.dry_run {
+                    img::write_image_with(&b, a.as_deref(), w, h, path, meta, args.compression)?;
+                }
+            }
+            if args.dry_run {
+                let first = numbered_path(&args.output, "share", 0);
+                report.plan(&steps, format!("{} shares like {}", n, first.display()));
+            }
+            return Ok(None);
+        }
+        let hide = |cover| img::conceal((width, height, &buf), cover, bits, args.plane, offset,
+            placement, args.checksum, args.kdf_iterations, mac);
+        let decoy = match (args.decoy, args.decoy_key) {
+            (Some(path), Some(d_key)) => Some((img::read_image_as::<T>(path, args.background,
+                args.alpha_mode)?, key::from_u64(d_key))),
+            _ => None,
+        };
+        (width, height, buf, alpha) = report.stage("conceal", || match (args.redundant, decoy) {
+            (_, Some((decoy, d_key))) => {
+                let key = key.expect("a decoy requires a key");
+                conceal_decoy((width, height, &buf), decoy, cover, bits, placement, key, d_key)
+            },
+            (Some(size), _) => {
+                let (c_width, c_height, mut c_buf, c_alpha) = cover;
+                img::for_each_block(&mut c_buf, 3, c_width, c_height, size, |block| {
+                    *block = hide((size, size, std::mem::take(block), None))?.2;
+                    Ok(())
+                })?;
+                Ok((c_width, c_height, c_buf, c_alpha))
+            },
+            (None, _) => hide(cover),
+        })?;
+        let side = args.redundant.map_or([width, height], |size| [size; 2]);
+        report.usage(used, stego::capacity(side[0], side[1], bits));
+        if let Some(path) = args.highlight.filter(|_| !args.dry_run) {
+            let header = stego::read_header_at(&buf, min_bits, offset)
+                .ok_or(ImgError::NoPayload)?;
+            let carriers = img::carrier_pixels((width, height, &buf), &header, placement)?;
+            let reserved = header.reserved_slots();
+            unclobbered(&path, args.no_clobber)?;
+            highlight(path, (width, height, &buf), &carriers, reserved, args.compression)?;
+        }
+        if let Some(path) = args.verify {
+            verify(path, &buf, args.background, args.alpha_mode, report)?;
+        }
     } else {
-        let max_out = u8::MAX;
-        let mask = max_out >> (8 - args.bits);
+        let max = args.rescale_max.unwrap_or(T::MAX);
+        report.stage("rescale", || img::rescale_bits_to(&mut buf, kept, max));
+        if normalizes && args.reveal {
+            report.stage("normalize", || normalize(&args, &mut buf, width, height))?;
+        }
+    };
+
+    if args.grayscale {
+        buf = img::to_gray(&buf)?;
+    }
+
+    Ok(Some((width, height, buf, alpha)))
+}
+
+// The key given in any of its forms, a password stretched over the given
+// PBKDF2 iterations or hashed once without
+fn secret(args: &Args, kdf: Option<u32>) -> Result<Option<Seed>, ImgError> {
+    Ok(match (args.key, args.key_hex, &args.password, &args.key_file) {
+        (Some(key), _, _, _) => Some(key::from_u64(key)),
+        (_, Some(key), _, _) => Some(key),
+        (_, _, Some(password), _) => Some(match kdf {
+            Some(iterations) => key::stretch_password(password, iterations),
+            None => key::from_password(password),
+        }),
+        (_, _, _, Some(path)) => {
+            Some(key::from_bytes(&fs::read(path).map_err(|e| ImgError::io(path, e))?))
+        },
+        _ => None,
+    })
+}
+
+// Normalization and gamma work on the region alone, cut out so its
+// histogram is all they see
+fn normalize<T: Sample>(args: &Args, buf: &mut Vec<T>, width: u32, height: u32)
+        -> Result<(), ImgError> {
+    let [r_x, r_y, r_w, r_h] = args.region.unwrap_or([0, 0, width, height]);
+    if r_x.saturating_add(r_w) > width || r_y.saturating_add(r_h) > height {
+        return Err(ImgError::DimensionMismatch);
+    }
+    let mut part = match args.region {
+        Some(_) => img::crop(buf, 3, width, r_x, r_y, r_w, r_h),
+        None => std::mem::take(buf),
+    };
+
+    // Contrast stretching algorithm for normalization
+    if args.stretch {
+        img::stretch(&mut part, args.stretch_clip, args.stretch_mode);
+    }
+
+    // Histogram equalization algorithm, normalizes HSV value
+    else if args.equalize {
+        img::equalize(&mut part, args.equalize_channel);
+    }
+
+    else if args.equalize_adaptive {
+        img::equalize_adaptive(&mut part, r_w, r_h, args.tiles, args.clip_limit);
+    }
+
+    // Gamma correction, applied after any normalization
+    if let Some(value) = args.gamma {
+        img::gamma(&mut part, value);
+    }
+
+    match args.region {
+        Some(_) => img::paste(buf, 3, width, r_x, r_y, r_w, &part),
+        None => *buf = part,
+    }
+
+    Ok(())
+}
+
+// An image downscaled to the --preview width, if given
+fn shrink<T: Sample>(image: Rgba<T>, preview: Option<u32>) -> Rgba<T> {
+    let Some(to) = preview else {
+        return image;
+    };
+    let (width, height, buf, alpha) = image;
+    let (w, h, buf) = img::downscale(&buf, 3, width, height, to);
+    (w, h, buf, alpha.map(|a| img::downscale(&a, 1, width, height, to).2))
+}
 
-        for c in buf.iter_mut() {
-            *c = ((*c & mask) as u16 * max_out as u16 / mask as u16) as u8;
+// Write a copy of a stego image with the carrier pixels maxed out in red,
+// and those holding the reserved samples of its header and any tag in blue
+fn highlight<T: Sample>(path: PathBuf, stego: (u32, u32, &[T]), carriers: &[usize],
+        reserved: usize, compression: Compression) -> Result<(), ImgError> {
+    let (width, height, buf) = stego;
+
+    let mut marked = buf.to_vec();
+    for &i in carriers {
+        marked[i * 3] = T::from_u32(T::MAX);
+    }
+    for i in 0..reserved.div_ceil(3) {
+        marked[i * 3 + 2] = T::from_u32(T::MAX);
+    }
+    img::write_image_with(&marked, None, width, height, path, &Metadata::default(), compression)
+}
+
+// Refuse layers whose bits overlap each other or the main payload, which
+// with its header takes the low bits from its offset, and the high bits too
+// in that plane
+fn check_layers<T: Sample>(layers: &[(PathBuf, [u8; 2])], bits: [u8; 3], plane: Plane,
+        offset: u8) -> Result<(), ImgError> {
+    let (min_bits, max_bits) = (stego::header_bits(bits), *bits.iter().max().unwrap());
+    let mut taken = match plane {
+        Plane::Low => vec![[offset, offset + max_bits - 1]],
+        Plane::High => vec![[0, min_bits - 1], [T::DEPTH - max_bits, T::DEPTH - 1]],
+    };
+
+    for (_, [lo, hi]) in layers {
+        if *hi >= T::DEPTH {
+            return Err(ImgError::BitDepth(hi + 1, T::DEPTH));
+        } else if taken.iter().any(|[t_lo, t_hi]| lo <= t_hi && t_lo <= hi) {
+            return Err(ImgError::PlaneOverlap(*lo, *hi));
+        }
+        taken.push([*lo, *hi]);
+    }
+
+    Ok(())
+}
+
+// Conceal one layer in its range of bits, cut down, ciphered and permuted
+// the way the main payload is, recording the same password stretching
+fn conceal_layer<T: Sample>(hidden: (u32, u32, Vec<T>), cover: Rgba<T>, planes: [u8; 2],
+        key: Option<Seed>, permute: bool, placement: Placement, kdf: Option<u32>)
+        -> Result<Rgba<T>, ImgError> {
+    let (width, height, mut buf) = hidden;
+    let bits = planes[1] - planes[0] + 1;
+
+    for c in buf.iter_mut() {
+        *c = T::from_u32(c.to_u32() >> (T::DEPTH - bits));
+    }
+    if let Some(key) = key {
+        img::stream_cipher(&mut buf, key, bits);
+        if permute {
+            img::permute_planes(&mut buf, key, [bits; 3], false);
         }
+    }
+
+    img::conceal((width, height, &buf), cover, [bits; 3], Plane::Low, planes[0], placement, false,
+        kdf, None)
+}
+
+// Conceal a payload, already cut down and ciphered, in the bits above those
+// a decoy goes in, with a header masked by its key, then the decoy cut down,
+// ciphered and authenticated by its own key the way the payload would be
+// without one. The decoy's tag covers the payload's bits too, so both must
+// be in place before it is signed.
+fn conceal_decoy<T: Sample>(hidden: (u32, u32, &[T]), decoy: (u32, u32, Vec<T>),
+        cover: Rgba<T>, bits: [u8; 3], placement: Placement, key: Seed, decoy_key: Seed)
+        -> Result<Rgba<T>, ImgError> {
+    let (min_bits, max_bits) = (stego::header_bits(bits), *bits.iter().max().unwrap());
+    let (c_width, c_height, mut c_buf, c_alpha) = img::conceal(hidden, cover, bits, Plane::Low,
+        max_bits, placement, false, None, None)?;
+    let header = stego::read_header_at(&c_buf, min_bits, max_bits)
+        .expect("conceal writes a header");
+    stego::write_keyed_header(&mut c_buf, &header, key);
+
+    let (width, height, mut buf) = decoy;
+    for (i, c) in buf.iter_mut().enumerate() {
+        *c = T::from_u32(c.to_u32() >> (T::DEPTH - bits[i % 3]));
+    }
+    img::stream_cipher_rgb(&mut buf, decoy_key, bits);
+    img::conceal((width, height, &buf), (c_width, c_height, c_buf, c_alpha), bits, Plane::Low, 0,
+        placement, false, None, Some(decoy_key))
+}
+
+// Report how far the stego image strays from the original cover
+fn verify<T: Sample>(path: PathBuf, buf: &[T], background: [u8; 3], mode: AlphaMode,
+        report: &mut Report) -> Result<(), ImgError> {
+    let (_, _, original) = img::read_image_as::<T>(path, background, mode)?;
+    let (mse, psnr) = img::psnr(&original, buf)?;
+
+    report.psnr(mse, psnr);
+    Ok(())
+}
+
+// Refuse an output that is already there with --no-clobber. Stdout is never
+// refused.
+fn unclobbered(path: &Path, no_clobber: bool) -> Result<(), ImgError> {
+    if no_clobber && path.as_os_str() != "-" && path.exists() {
+        return Err(ImgError::Io(format!("{} already exists, --no-clobber leaves it be",
+            path.display())));
+    }
+    Ok(())
+}
+
+// Path of one of several images, such as bit plane 0 of out.png becoming
+// out_plane0.png
+fn numbered_path(output: &Path, name: &str, i: usize) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = output.extension().map_or("png".into(), |e| e.to_string_lossy());
+    output.with_file_name(format!("{}_{}{}.{}", stem, name, i, ext))
+}
+
+fn capacity(width: u32, height: u32, bits: [u8; 3], payload: Option<PathBuf>,
+        report: &mut Report) -> Result<(), ImgError> {
+    let label = match bits {
+        [r, g, b] if r == g && g == b => r.to_string(),
+        [r, g, b] => format!("{},{},{}", r, g, b),
     };
+    if report.json {
+        report.field("bits", format!("[{},{},{}]", bits[0], bits[1], bits[2]));
+        report.field("capacity_bytes", stego::capacity(width, height, bits));
+    } else {
+        println!("capacity: {} bytes ({}x{} at {} bits)",
+            stego::capacity(width, height, bits), width, height, label);
+    }
+
+    if let Some(path) = payload {
+        let (p_width, p_height, _) = img::read_image_rgb8(path, [0, 0, 0])?;
+        let fits = p_width <= width && p_height <= height;
+
+        if report.json {
+            report.field("payload_bytes", stego::capacity(p_width, p_height, bits));
+            report.field("payload_width", p_width);
+            report.field("payload_height", p_height);
+            report.field("fits", fits);
+        } else {
+            println!("payload: {} bytes ({}x{}), {}",
+                stego::capacity(p_width, p_height, bits), p_width, p_height,
+                if fits { "fits" } else { "does not fit" });
+        }
 
-    img::write_image_rgb8(&buf, width, height, args.output);
+        if !fits {
+            return Err(ImgError::HiddenTooLarge((width, height), (p_width, p_height)));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_error_has_its_exit_code() {
+        let codes = [
+            (ImgError::NotFound(PathBuf::from("in.png")), 3),
+            (ImgError::Decode("truncated".to_string()), 4),
+            (ImgError::SampleSize(5), 4),
+            (ImgError::Format("lossy".to_string()), 5),
+            (ImgError::DimensionMismatch, 6),
+            (ImgError::HiddenTooLarge((4, 4), (8, 8)), 6),
+            (ImgError::Capacity(100, 10), 7),
+            (ImgError::BitDepth(9, 8), 8),
+            (ImgError::UniformBits, 8),
+            (ImgError::PlaneOverlap(0, 1), 8),
+            (ImgError::NoPayload, 9),
+            (ImgError::Authentication, 10),
+            (ImgError::Io("disk full".to_string()), 11),
+        ];
+        for (e, code) in codes {
+            assert_eq!(exit_code(&e), code, "{}", e);
+        }
+    }
+
+    #[test]
+    fn quiet_json_still_reports_usage() {
+        let mut report = Report { json: true, quiet: true, verbose: false, fields: Vec::new() };
+        report.usage(25, 100);
+        let names: Vec<_> = report.fields.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["payload_bytes", "capacity_bytes", "used_percent"]);
+    }
+
+    #[test]
+    fn cropped_stego_images_reveal_no_payload() {
+        let path = |name: &str| {
+            std::env::temp_dir().join(format!("rsteg-crop-{}-{}.png", name, process::id()))
+        };
+        let (hidden, cover, stego, cropped, out) =
+            (path("hidden"), path("cover"), path("stego"), path("cropped"), path("out"));
+        let rgb = |n: usize| (0..n * 3).map(|i| (i * 37 % 256) as u8).collect::<Vec<u8>>();
+        img::write_image(&rgb(50 * 30), None, 50, 30, hidden.clone()).unwrap();
+        img::write_image(&rgb(200 * 200), None, 200, 200, cover.clone()).unwrap();
+
+        let arg = |p: &PathBuf| p.to_str().unwrap().to_string();
+        let rsteg = |argv: &[&str]| run(Args::parse_from(["rsteg", "--quiet"].iter().chain(argv)));
+        let (hidden_arg, cover_arg, stego_arg) = (arg(&hidden), arg(&cover), arg(&stego));
+        rsteg(&[&hidden_arg, "-c", &cover_arg, "-b", "2", "-o", &stego_arg]).unwrap();
+
+        // Only part of the hidden image's rectangle is left, with the header
+        let (_, _, buf) = img::read_image_rgb8(stego.clone(), [0; 3]).unwrap();
+        img::write_image(&img::crop(&buf, 3, 200, 0, 0, 40, 60), None, 40, 60, cropped.clone())
+            .unwrap();
+        let (cropped_arg, out_arg) = (arg(&cropped), arg(&out));
+        let reveal = |force: &[&str]| {
+            rsteg(&[&[cropped_arg.as_str(), "-r", "-b", "2", "-o", &out_arg], force].concat())
+        };
+        let (refused, forced) = (reveal(&[]), reveal(&["--force"]));
+        let streamed = (reveal(&["--stream"]), reveal(&["--stream", "--force"]));
+        let written = out.exists();
+        for p in [hidden, cover, stego, cropped] {
+            fs::remove_file(p).unwrap();
+        }
+
+        assert!(matches!(refused, Err(ImgError::NoPayload)));
+        assert!(matches!(forced, Err(ImgError::DimensionMismatch)));
+        assert!(matches!(streamed, (Err(ImgError::NoPayload), Err(ImgError::DimensionMismatch))));
+        assert!(!written);
+    }
+
+    #[test]
+    fn lossy_outputs_need_force_lossy() {
+        let cover = std::env::temp_dir().join(format!("rsteg-lossy-{}.png", process::id()));
+        let output = cover.with_extension("jpg");
+        let rgb: Vec<u8> = (0..32 * 32 * 3).map(|i| (i * 37 % 256) as u8).collect();
+        img::write_image(&rgb, None, 32, 32, cover.clone()).unwrap();
+
+        let (input, out) = (cover.to_str().unwrap(), output.to_str().unwrap());
+        let args = |force: &[&str]| {
+            let argv = ["rsteg", input, "--message", "hi", "--bits", "2", "--quiet", "-o", out];
+            Args::parse_from(argv.iter().chain(force))
+        };
+        let refused = run(args(&[]));
+        let refused_written = output.exists();
+        let forced = run(args(&["--force-lossy"]));
+        let forced_written = output.exists();
+        fs::remove_file(&cover).unwrap();
+        let _ = fs::remove_file(&output);
+
+        assert!(matches!(refused, Err(ImgError::Format(e)) if e.contains("--force-lossy")));
+        assert!(!refused_written);
+        assert!(forced.is_ok());
+        assert!(forced_written);
+    }
 }
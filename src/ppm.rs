@@ -0,0 +1,120 @@
+use std::io::{self, Read, Write};
+use crate::img::{ImgError, Raw, Sample, Samples};
+
+fn err(msg: &str) -> ImgError {
+    ImgError::Decode(format!("PPM {}", msg))
+}
+
+// Decodes a binary PPM (P6) into RGB samples, or PGM (P5) into gray, 16-bit
+// when the maximum value needs two bytes. Values are scaled up to the full
+// range of the depth.
+pub fn read_ppm<R: Read>(mut r: R) -> Result<Raw, ImgError> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data).map_err(|e| ImgError::Decode(e.to_string()))?;
+
+    let samples = match data.get(..2) {
+        Some(b"P6") => 3,
+        Some(b"P5") => 1,
+        _ => return Err(err("is not binary RGB (P6) or gray (P5)")),
+    };
+
+    // Width, height and maximum value, separated by whitespace and comments
+    let mut i = 2;
+    let mut fields = [0u32; 3];
+    for field in fields.iter_mut() {
+        loop {
+            match data.get(i) {
+                Some(b'#') => while data.get(i).is_some_and(|c| *c != b'\n') { i += 1 },
+                Some(c) if c.is_ascii_whitespace() => i += 1,
+                _ => break,
+            }
+        }
+
+        let start = i;
+        while data.get(i).is_some_and(|c| c.is_ascii_digit()) {
+            i += 1;
+        }
+        *field = std::str::from_utf8(&data[start..i]).unwrap().parse()
+            .map_err(|_| err("header is invalid"))?;
+    }
+
+    // A single whitespace byte separates the header from the samples
+    let [width, height, max] = fields;
+    if width == 0 || height == 0 || max == 0 || max > 65535 {
+        return Err(err("header is invalid"));
+    }
+
+    let n = (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(samples))
+        .ok_or_else(|| err("is too large"))?;
+    let data = data.get(i + 1..).unwrap_or_default();
+
+    // Samples above the maximum are taken as the maximum
+    if max < 256 {
+        let buf = data.get(..n).ok_or_else(|| err("pixel data is truncated"))?;
+        let buf = buf.iter().map(|c| ((*c as u32).min(max) * 255 / max) as u8).collect();
+        Ok((width, height, Samples::Eight(buf), samples))
+    } else {
+        let buf = n.checked_mul(2).and_then(|len| data.get(..len))
+            .ok_or_else(|| err("pixel data is truncated"))?;
+        let buf = buf.chunks_exact(2)
+            .map(|c| ((u16::from_be_bytes([c[0], c[1]]) as u32).min(max) * 65535 / max) as u16)
+            .collect();
+        Ok((width, height, Samples::Sixteen(buf), samples))
+    }
+}
+
+// Encodes RGB samples as a binary PPM, or one sample per pixel as a PGM, two
+// bytes big endian per sample when 16-bit
+pub fn write_ppm<W: Write, T: Sample>(mut w: W, width: u32, height: u32, buf: &[T], samples: usize)
+        -> io::Result<()> {
+    let magic = if samples == 1 { "P5" } else { "P6" };
+    write!(w, "{}\n{} {}\n{}\n", magic, width, height, T::MAX)?;
+
+    let n = T::DEPTH as usize / 8;
+    let data: Vec<u8> = buf.iter()
+        .flat_map(|c| c.to_u32().to_be_bytes().into_iter().skip(4 - n))
+        .collect();
+
+    w.write_all(&data)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_rgb_and_gray() {
+        let rgb: Vec<u8> = (0..5 * 3 * 3).map(|i| (i * 37 % 256) as u8).collect();
+        let mut data = Vec::new();
+        write_ppm(&mut data, 5, 3, &rgb, 3).unwrap();
+        assert!(data.starts_with(b"P6\n5 3\n255\n"));
+        let (w, h, out, samples) = read_ppm(&data[..]).unwrap();
+        assert_eq!((w, h, samples), (5, 3, 3));
+        assert_eq!(out.into_depth::<u8>(), rgb);
+
+        let gray: Vec<u16> = (0..4 * 2).map(|i| (i * 9001 % 65536) as u16).collect();
+        let mut data = Vec::new();
+        write_ppm(&mut data, 4, 2, &gray, 1).unwrap();
+        assert!(data.starts_with(b"P5\n4 2\n65535\n"));
+        let (w, h, out, samples) = read_ppm(&data[..]).unwrap();
+        assert_eq!((w, h, samples), (4, 2, 1));
+        assert_eq!(out.into_depth::<u16>(), gray);
+    }
+
+    #[test]
+    fn samples_above_the_maximum_are_clamped() {
+        let (_, _, out, _) = read_ppm(&b"P5 # comment\n3 1 100\n\x00\x32\xFF"[..]).unwrap();
+        assert_eq!(out.into_depth::<u8>(), [0, 127, 255]);
+
+        let (_, _, out, _) = read_ppm(&b"P5\n2 1\n1000\n\x01\xF4\xFF\xFF"[..]).unwrap();
+        assert_eq!(out.into_depth::<u16>(), [32767, 65535]);
+    }
+
+    #[test]
+    fn refuses_oversized_and_truncated_files() {
+        let huge = format!("P6\n{} {}\n255\n", u32::MAX, u32::MAX);
+        assert!(matches!(read_ppm(huge.as_bytes()), Err(ImgError::Decode(_))));
+        assert!(matches!(read_ppm(&b"P6\n2 2\n255\n\x00\x00"[..]), Err(ImgError::Decode(_))));
+    }
+}
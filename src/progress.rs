@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Reporting is off unless enabled, keeping piped output clean
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+// Percent complete of one pass, printed to stderr as it advances. Ticks may
+// come from several threads at once.
+pub struct Progress {
+    label: &'static str,
+    total: usize,
+    done: AtomicUsize,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: usize) -> Self {
+        Progress { label, total, done: AtomicUsize::new(0) }
+    }
+
+    // Count n more units done, printing whenever another percent is reached
+    pub fn tick(&self, n: usize) {
+        if !ENABLED.load(Ordering::Relaxed) || self.total == 0 {
+            return;
+        }
+
+        let before = self.done.fetch_add(n, Ordering::Relaxed);
+        let (a, b) = (before * 100 / self.total, (before + n).min(self.total) * 100 / self.total);
+        if b > a {
+            eprint!("\r{}: {}%", self.label, b);
+            if b == 100 {
+                eprintln!();
+            }
+        }
+    }
+}
@@ -0,0 +1,863 @@
+use hmac::{Hmac, KeyInit, Mac};
+use rand::{RngCore, SeedableRng};
+use rand::seq::SliceRandom;
+use rand_chacha::ChaCha20Rng;
+use sha2::Sha256;
+
+use crate::ecc;
+use crate::img::{self, ImgError, Sample};
+use crate::key::Seed;
+
+// The header is packed into the low bits of the first samples of the cover,
+// taking header_slots(bits) samples, followed by the authentication tag when
+// there is one, then any checksum and then any key stretching iterations.
+// Byte payloads come right after these reserved samples. Hidden images keep
+// their place in the cover instead, so the header is written over their
+// first 216 / bits samples (72 pixels at 1 bit, 9 pixels at 8 bits), a tag
+// over the next 256 / bits and a checksum and iterations over 32 / bits
+// each. When the channels of an image carry different bit counts the header
+// is packed at the fewest.
+// Layers concealed above the low bits carry their own header, packed into
+// the bits they take up.
+pub const MAGIC: [u8; 4] = *b"RSTG";
+pub const HEADER_LEN: usize = 27;
+pub const TAG_LEN: usize = 32;
+pub const CHECKSUM_LEN: usize = 4;
+pub const KDF_LEN: usize = 4;
+
+const AUTHENTICATED: u8 = 1;
+const HIGH_PLANE: u8 = 2;
+const COMPRESSED: u8 = 4;
+const ECC: u8 = 8;
+const CHECKSUM: u8 = 16;
+const KDF: u8 = 32;
+
+// Bits of the cover a hidden image is written over
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Plane {
+    /// The low bits, the least perceptible
+    Low,
+    /// The high bits, surviving more of any later processing but replacing
+    /// the look of the cover with the hidden image
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Payload {
+    // Offset by x, y in the cover
+    Image { width: u32, height: u32, x: u32, y: u32 },
+    File { len: u32 },
+    Message { len: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub payload: Payload,
+    // Bits the header itself is packed in
+    pub bits: u8,
+    // Bits of R, G and B holding the payload
+    pub channels: [u8; 3],
+    pub authenticated: bool,
+    pub plane: Plane,
+    // Lowest bit of a payload in the low plane, above 0 for layers
+    pub offset: u8,
+    // Byte payloads deflated before packing
+    pub compressed: bool,
+    // Byte payloads coded with ecc::encode to correct flipped bits
+    pub ecc: bool,
+    // Followed by a CRC32 of the payload bits, see checksum
+    pub checksum: bool,
+    // Followed by the iterations the password was stretched with, see
+    // key::stretch_password
+    pub kdf: bool,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let (kind, a, b, c, d) = match self.payload {
+            Payload::Image { width, height, x, y } => (0, width, height, x, y),
+            Payload::File { len } => (1, len, 0, 0, 0),
+            Payload::Message { len } => (2, len, 0, 0, 0),
+        };
+
+        let mut flags = 0;
+        if self.authenticated {
+            flags |= AUTHENTICATED;
+        }
+        if self.plane == Plane::High {
+            flags |= HIGH_PLANE;
+        }
+        if self.compressed {
+            flags |= COMPRESSED;
+        }
+        if self.ecc {
+            flags |= ECC;
+        }
+        if self.checksum {
+            flags |= CHECKSUM;
+        }
+        if self.kdf {
+            flags |= KDF;
+        }
+
+        let mut h = [0; HEADER_LEN];
+        h[..4].copy_from_slice(&MAGIC);
+        h[4] = kind;
+        h[5] = self.bits;
+        h[6] = flags;
+        h[7..10].copy_from_slice(&self.channels);
+        h[10] = self.offset;
+        h[11..15].copy_from_slice(&a.to_be_bytes());
+        h[15..19].copy_from_slice(&b.to_be_bytes());
+        h[19..23].copy_from_slice(&c.to_be_bytes());
+        h[23..27].copy_from_slice(&d.to_be_bytes());
+        h
+    }
+
+    pub fn from_bytes(h: &[u8]) -> Option<Self> {
+        if h.len() < HEADER_LEN || h[..4] != MAGIC {
+            return None;
+        }
+
+        let [a, b, c, d] = [11, 15, 19, 23]
+            .map(|i| u32::from_be_bytes([h[i], h[i + 1], h[i + 2], h[i + 3]]));
+
+        let payload = match h[4] {
+            0 => Payload::Image { width: a, height: b, x: c, y: d },
+            1 => Payload::File { len: a },
+            2 => Payload::Message { len: a },
+            _ => return None,
+        };
+
+        let plane = if h[6] & HIGH_PLANE != 0 { Plane::High } else { Plane::Low };
+        Some(Header {
+            payload,
+            bits: h[5],
+            channels: [h[7], h[8], h[9]],
+            authenticated: h[6] & AUTHENTICATED != 0,
+            plane,
+            offset: h[10],
+            compressed: h[6] & COMPRESSED != 0,
+            ecc: h[6] & ECC != 0,
+            checksum: h[6] & CHECKSUM != 0,
+            kdf: h[6] & KDF != 0,
+        })
+    }
+
+    // Bit the header itself is packed from. High plane images keep theirs
+    // in the low bits, where reveal looks first.
+    pub fn shift(&self) -> u8 {
+        if self.plane == Plane::High { 0 } else { self.offset }
+    }
+
+    // Number of samples taken up by the header, tag, checksum and iterations
+    pub fn reserved_slots(&self) -> usize {
+        let bits = self.bits as usize;
+        self.kdf_start() + if self.kdf { (KDF_LEN * 8).div_ceil(bits) } else { 0 }
+    }
+
+    // First sample of the iterations, just past the checksum
+    fn kdf_start(&self) -> usize {
+        let bits = self.bits as usize;
+        self.checksum_start() + if self.checksum { (CHECKSUM_LEN * 8).div_ceil(bits) } else { 0 }
+    }
+
+    // First sample of the checksum, just past the header and tag
+    fn checksum_start(&self) -> usize {
+        let tag = if self.authenticated { (TAG_LEN * 8).div_ceil(self.bits as usize) } else { 0 };
+        header_slots(self.bits) + tag
+    }
+
+    // Whether a hidden image lies within a width by height stego image, as
+    // it no longer may once that image is cropped. Other payloads always do.
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        match self.payload {
+            Payload::Image { width: w, height: h, x, y } => {
+                x.checked_add(w).is_some_and(|right| right <= width)
+                    && y.checked_add(h).is_some_and(|bottom| bottom <= height)
+            },
+            _ => true,
+        }
+    }
+}
+
+// Bytes of payload an RGB image can carry in the low bits of each channel
+pub fn capacity(width: u32, height: u32, bits: [u8; 3]) -> usize {
+    width as usize * height as usize * bits.iter().map(|&b| b as usize).sum::<usize>() / 8
+}
+
+// Bits a header is packed in, those of the channel carrying fewest. Channels
+// carrying none are passed over.
+pub fn header_bits(channels: [u8; 3]) -> u8 {
+    channels.into_iter().filter(|&b| b > 0).min().unwrap_or(0)
+}
+
+// Number of samples taken up by the header alone
+pub fn header_slots(bits: u8) -> usize {
+    (HEADER_LEN * 8).div_ceil(bits as usize)
+}
+
+// Key derived permutation of 0..n, drawn from a separate stream of the same
+// seed used by the cipher
+pub fn scatter_order(n: usize, key: Seed) -> Vec<usize> {
+    let mut rng = ChaCha20Rng::from_seed(key);
+    rng.set_stream(1);
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.shuffle(&mut rng);
+    order
+}
+
+// Scatter order of the pixels of a hidden image, over the pixels of the
+// cover past those holding reserved samples
+pub fn scatter_pixels(pixels: usize, reserved: usize, key: Seed) -> Vec<usize> {
+    let skip = reserved.div_ceil(3).min(pixels);
+    scatter_order(pixels - skip, key).into_iter().map(|i| i + skip).collect()
+}
+
+pub fn write_header<T: Sample>(buf: &mut [T], header: &Header) {
+    pack_at(buf, header.bits, header.shift(), &header.to_bytes());
+}
+
+// None if the samples don't start with a header for this bit count
+pub fn read_header<T: Sample>(buf: &[T], bits: u8) -> Option<Header> {
+    read_header_at(buf, bits, 0)
+}
+
+// Like read_header, for a header packed from the given bit up
+pub fn read_header_at<T: Sample>(buf: &[T], bits: u8, shift: u8) -> Option<Header> {
+    masked_header_at(buf, bits, shift, [0; HEADER_LEN])
+}
+
+// Bytes a header hidden under a decoy is XORed with, from a fourth stream of
+// the key, so that without the key it reads as noise rather than a header
+fn header_mask(key: Seed) -> [u8; HEADER_LEN] {
+    let mut rng = ChaCha20Rng::from_seed(key);
+    rng.set_stream(3);
+    let mut mask = [0; HEADER_LEN];
+    rng.fill_bytes(&mut mask);
+    mask
+}
+
+// Write a header only the key can find, for a payload under a decoy
+pub fn write_keyed_header<T: Sample>(buf: &mut [T], header: &Header, key: Seed) {
+    let bytes: Vec<u8> = header.to_bytes().iter().zip(header_mask(key)).map(|(b, m)| b ^ m)
+        .collect();
+    pack_at(buf, header.bits, header.shift(), &bytes);
+}
+
+// Like read_header_at, for a header written by write_keyed_header
+pub fn read_keyed_header_at<T: Sample>(buf: &[T], bits: u8, shift: u8, key: Seed)
+        -> Option<Header> {
+    masked_header_at(buf, bits, shift, header_mask(key))
+}
+
+fn masked_header_at<T: Sample>(buf: &[T], bits: u8, shift: u8, mask: [u8; HEADER_LEN])
+        -> Option<Header> {
+    if bits == 0 || shift + bits > T::DEPTH || buf.len() < header_slots(bits) {
+        return None;
+    }
+
+    let bytes = unpack_at(buf, bits, shift, HEADER_LEN);
+    let bytes: Vec<u8> = bytes.iter().zip(mask).map(|(b, m)| b ^ m).collect();
+    Header::from_bytes(&bytes)
+        .filter(|h| h.bits == bits && h.shift() == shift && h.reserved_slots() <= buf.len())
+}
+
+// Origin of the first size by size block of the image, among those a pixel
+// apart, that starts with a header. A cropped --redundant image still holds
+// whole blocks, though no longer on a grid from its own top left corner.
+pub fn find_block<T: Sample>(buf: &[T], width: u32, height: u32, size: u32, bits: u8, shift: u8)
+        -> Option<(u32, u32)> {
+    if bits == 0 || size == 0 || size > width || size > height {
+        return None;
+    }
+
+    // Only the rows that can hold the reserved samples are cut out to look
+    let reserved = ((HEADER_LEN + TAG_LEN + CHECKSUM_LEN + KDF_LEN) * 8).div_ceil(bits as usize);
+    let rows = (reserved.div_ceil(size as usize * 3) as u32).min(size);
+    let mut origins = (0..size.min(height - size + 1))
+        .flat_map(|y| (0..size.min(width - size + 1)).map(move |x| (x, y)));
+    origins.find(|&(x, y)| {
+        let block = img::crop(buf, 3, width, x, y, size, rows);
+        read_header_at(&block, bits, shift).is_some()
+    })
+}
+
+// HMAC of every sample in the image, with the low bits of the tag samples
+// themselves zeroed
+fn tag<T: Sample>(buf: &[T], header: &Header, key: Seed) -> Hmac<Sha256> {
+    let tag = header_slots(header.bits)..header.checksum_start();
+    let mask = T::low_mask(header.bits);
+    let n = T::DEPTH as usize / 8;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+    for (i, c) in buf.iter().enumerate() {
+        let c = if tag.contains(&i) { c.to_u32() & !mask } else { c.to_u32() };
+        mac.update(&c.to_be_bytes()[4 - n..]);
+    }
+    mac
+}
+
+// Store the tag of an image whose header is already written
+pub fn sign<T: Sample>(buf: &mut [T], bits: u8, key: Seed) {
+    let header = read_header(buf, bits).expect("Image must have a header to sign");
+    let tag = tag(buf, &header, key).finalize().into_bytes();
+    pack(&mut buf[header_slots(bits)..], bits, &tag);
+}
+
+// Check the tag of an authenticated image
+pub fn verify<T: Sample>(buf: &[T], header: &Header, key: Option<Seed>) -> Result<(), ImgError> {
+    let key = key.ok_or(ImgError::Authentication)?;
+    let stored = unpack(&buf[header_slots(header.bits)..], header.bits, TAG_LEN);
+
+    tag(buf, header, key).verify_slice(&stored).map_err(|_| ImgError::Authentication)
+}
+
+// CRC32 with the IEEE polynomial, as used by PNG and zip
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| (0..8).fold(crc ^ b as u32, |c, _| {
+        if c & 1 == 1 { c >> 1 ^ 0xEDB8_8320 } else { c >> 1 }
+    }))
+}
+
+// CRC32 of the payload bits of the given samples, those past the reserved
+// samples. Any change to them is caught whether or not they are encrypted.
+fn checksum<T: Sample>(buf: &[T], header: &Header, samples: &[usize]) -> u32 {
+    let n = T::DEPTH as usize / 8;
+    let reserved = header.reserved_slots();
+
+    let mut data = Vec::with_capacity(samples.len() * n);
+    for &i in samples.iter().filter(|&&i| i >= reserved && i < buf.len()) {
+        let bits = header.channels[i % 3];
+        let shift = if header.plane == Plane::High { T::DEPTH - bits } else { header.offset };
+        let v = buf[i].to_u32() >> shift & T::low_mask(bits);
+        data.extend_from_slice(&v.to_be_bytes()[4 - n..]);
+    }
+    crc32(&data)
+}
+
+// Store the checksum of a payload whose header is already written, before
+// any tag is signed over it
+pub fn write_checksum<T: Sample>(buf: &mut [T], header: &Header, samples: &[usize]) {
+    let crc = checksum(buf, header, samples);
+    pack_at(&mut buf[header.checksum_start()..], header.bits, header.shift(), &crc.to_be_bytes());
+}
+
+// Whether the payload bits of the samples still match the stored checksum
+pub fn checksum_matches<T: Sample>(buf: &[T], header: &Header, samples: &[usize]) -> bool {
+    let stored = unpack_at(&buf[header.checksum_start()..], header.bits, header.shift(),
+        CHECKSUM_LEN);
+    stored == checksum(buf, header, samples).to_be_bytes()
+}
+
+// Store the iterations a password was stretched with, after a header that
+// records them
+pub fn write_kdf<T: Sample>(buf: &mut [T], header: &Header, iterations: u32) {
+    pack_at(&mut buf[header.kdf_start()..], header.bits, header.shift(), &iterations.to_be_bytes());
+}
+
+// Iterations the password of a payload was stretched with, None if it was
+// only hashed
+pub fn read_kdf<T: Sample>(buf: &[T], header: &Header) -> Option<u32> {
+    let n = unpack_at(&buf[header.kdf_start()..], header.bits, header.shift(), KDF_LEN);
+    header.kdf.then(|| u32::from_be_bytes([n[0], n[1], n[2], n[3]]))
+}
+
+// Samples of each pixel in turn
+pub fn pixel_samples(pixels: &[usize]) -> Vec<usize> {
+    pixels.iter().flat_map(|&p| p * 3..p * 3 + 3).collect()
+}
+
+// Positions of the samples holding len bytes of data, after the reserved
+// samples in order or shuffled across the rest of the image by the scatter key
+fn data_positions(n: usize, start: usize, bits: u8, len: usize, scatter: Option<Seed>)
+        -> Option<Vec<usize>> {
+    let slots = (len * 8).div_ceil(bits as usize);
+    if start + slots > n {
+        return None;
+    }
+
+    Some(match scatter {
+        Some(key) => scatter_order(n - start, key).into_iter()
+            .take(slots)
+            .map(|i| i + start)
+            .collect(),
+        None => (start..start + slots).collect(),
+    })
+}
+
+// Bytes of a file or message as stored, deflated first if that shrinks them
+// and then coded for ecc. True if they were deflated.
+fn encode(data: &[u8], compress: bool, ecc: bool) -> (Vec<u8>, bool) {
+    let deflated = compress.then(|| miniz_oxide::deflate::compress_to_vec(data, 9))
+        .filter(|d| d.len() < data.len());
+    let compressed = deflated.is_some();
+    let data = deflated.unwrap_or_else(|| data.to_vec());
+    (if ecc { ecc::encode(&data) } else { data }, compressed)
+}
+
+// How embed_bytes stores a file or message. With ecc the data is stored with
+// parity to correct a flipped bit in each byte, and with checksum a CRC32 of
+// it is stored to catch corruption. Its samples are encrypted when a key is
+// given, and authenticated with the mac key. The iterations of a stretched
+// password are stored for reveal to stretch it again.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Embedding {
+    pub message: bool,
+    pub compress: bool,
+    pub ecc: bool,
+    pub checksum: bool,
+    pub kdf: Option<u32>,
+    pub key: Option<Seed>,
+    pub scatter: Option<Seed>,
+    pub mac: Option<Seed>,
+}
+
+// Fewest bits, up to 8, at which embed_bytes fits a file or message into n
+// samples
+pub fn auto_bits(n: usize, data: &[u8], embedding: &Embedding) -> Result<u8, ImgError> {
+    let len = encode(data, embedding.compress, embedding.ecc).0.len();
+    let reserved = |bits| Header {
+        payload: Payload::File { len: 0 },
+        bits,
+        channels: [bits; 3],
+        authenticated: embedding.mac.is_some(),
+        plane: Plane::Low,
+        offset: 0,
+        compressed: false,
+        ecc: embedding.ecc,
+        checksum: embedding.checksum,
+        kdf: embedding.kdf.is_some(),
+    }.reserved_slots();
+
+    (1..=8).find(|&bits| reserved(bits) + (len * 8).div_ceil(bits as usize) <= n)
+        .ok_or_else(|| ImgError::Capacity(len, n.saturating_sub(reserved(8))))
+}
+
+// Embed a file or message after the header as embedding describes
+pub fn embed_bytes<T: Sample>(buf: &mut [T], bits: u8, data: &[u8], embedding: &Embedding)
+        -> Result<(), ImgError> {
+    let &Embedding { message, compress, ecc, checksum, kdf, key, scatter, mac } = embedding;
+    if bits == 0 || bits > T::DEPTH {
+        return Err(ImgError::BitDepth(bits, T::DEPTH));
+    }
+    let (data, compressed) = encode(data, compress, ecc);
+
+    let len = data.len() as u32;
+    let payload = if message { Payload::Message { len } } else { Payload::File { len } };
+    let header = Header {
+        payload,
+        bits,
+        channels: [bits; 3],
+        authenticated: mac.is_some(),
+        plane: Plane::Low,
+        offset: 0,
+        compressed,
+        ecc,
+        checksum,
+        kdf: kdf.is_some(),
+    };
+    let start = header.reserved_slots();
+
+    let positions = data_positions(buf.len(), start, bits, data.len(), scatter)
+        .filter(|_| data.len() <= u32::MAX as usize)
+        .ok_or_else(|| {
+            let available = buf.len().saturating_sub(start) * bits as usize / 8;
+            ImgError::Capacity(data.len(), available)
+        })?;
+
+    let mut slots: Vec<T> = positions.iter().map(|i| buf[*i]).collect();
+    pack(&mut slots, bits, &data);
+    if let Some(key) = key {
+        img::stream_cipher(&mut slots, key, bits);
+    }
+
+    for (i, c) in positions.iter().zip(slots) {
+        buf[*i] = c;
+    }
+
+    write_header(buf, &header);
+    if checksum {
+        write_checksum(buf, &header, &positions);
+    }
+    if let Some(iterations) = kdf {
+        write_kdf(buf, &header, iterations);
+    }
+    if let Some(mac) = mac {
+        sign(buf, bits, mac);
+    }
+
+    Ok(())
+}
+
+// Inverse of embed_bytes, returning the payload kind along with the data
+pub fn extract_bytes<T: Sample>(buf: &[T], bits: u8, key: Option<Seed>, scatter: Option<Seed>)
+        -> Result<(Payload, Vec<u8>), ImgError> {
+    let header = read_header(buf, bits).ok_or(ImgError::NoPayload)?;
+    let len = match header.payload {
+        Payload::File { len } | Payload::Message { len } => len as usize,
+        _ => return Err(ImgError::NoPayload),
+    };
+
+    if header.authenticated {
+        verify(buf, &header, key)?;
+    }
+
+    let positions = data_positions(buf.len(), header.reserved_slots(), bits, len, scatter)
+        .ok_or(ImgError::NoPayload)?;
+
+    let mut data: Vec<T> = positions.into_iter().map(|i| buf[i]).collect();
+    if let Some(key) = key {
+        img::stream_cipher(&mut data, key, bits);
+    }
+
+    let mut data = unpack(&data, bits, len);
+    if header.ecc {
+        data = ecc::decode(&data)?;
+    }
+    if header.compressed {
+        data = miniz_oxide::inflate::decompress_to_vec(&data)
+            .map_err(|e| ImgError::Decode(format!("payload failed to inflate: {}", e)))?;
+    }
+
+    Ok((header.payload, data))
+}
+
+// Samples holding the file or message embedded in an image, as found by its
+// header
+pub fn byte_positions<T: Sample>(buf: &[T], bits: u8, scatter: Option<Seed>)
+        -> Option<Vec<usize>> {
+    let header = read_header(buf, bits)?;
+    let (Payload::File { len } | Payload::Message { len }) = header.payload else {
+        return None;
+    };
+    data_positions(buf.len(), header.reserved_slots(), bits, len as usize, scatter)
+}
+
+// Whether the file or message embedded in an image matches its checksum,
+// None when it was embedded without one
+pub fn bytes_checksum_matches<T: Sample>(buf: &[T], bits: u8, scatter: Option<Seed>)
+        -> Option<bool> {
+    let header = read_header(buf, bits).filter(|h| h.checksum)?;
+    let positions = byte_positions(buf, bits, scatter)?;
+    Some(checksum_matches(buf, &header, &positions))
+}
+
+// Pack bytes most significant bit first into the low bits of each sample
+pub fn pack<T: Sample>(buf: &mut [T], bits: u8, data: &[u8]) {
+    pack_at(buf, bits, 0, data)
+}
+
+// Like pack, into the bits from shift up
+pub fn pack_at<T: Sample>(buf: &mut [T], bits: u8, shift: u8, data: &[u8]) {
+    let mask = T::low_mask(bits) << shift;
+    let mut stream = data.iter().flat_map(|b| (0..8).rev().map(move |i| (b >> i) as u32 & 1));
+
+    for c in buf.iter_mut().take((data.len() * 8).div_ceil(bits as usize)) {
+        let v = (0..bits).fold(0, |v, _| v << 1 | stream.next().unwrap_or(0));
+        *c = T::from_u32(c.to_u32() & !mask | v << shift);
+    }
+}
+
+// Inverse of pack, reading len bytes
+pub fn unpack<T: Sample>(buf: &[T], bits: u8, len: usize) -> Vec<u8> {
+    unpack_at(buf, bits, 0, len)
+}
+
+// Inverse of pack_at
+pub fn unpack_at<T: Sample>(buf: &[T], bits: u8, shift: u8, len: usize) -> Vec<u8> {
+    let mut stream = buf.iter()
+        .flat_map(|c| (0..bits).rev().map(move |i| c.to_u32() >> (i + shift) & 1));
+
+    (0..len).map(|_| (0..8).fold(0, |b, _| b << 1 | stream.next().unwrap_or(0) as u8)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cover(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = Header {
+            payload: Payload::Image { width: 640, height: 1, x: 3, y: 70000 },
+            bits: 3,
+            channels: [3, 4, 5],
+            authenticated: true,
+            plane: Plane::High,
+            offset: 0,
+            compressed: true,
+            ecc: true,
+            checksum: true,
+            kdf: true,
+        };
+        assert_eq!(Header::from_bytes(&header.to_bytes()), Some(header));
+        assert_eq!(Header::from_bytes(b"not a header at all at all..."), None);
+    }
+
+    #[test]
+    fn headers_pack_into_their_own_bits() {
+        let mut buf = cover(400);
+        let header = Header {
+            payload: Payload::Image { width: 4, height: 4, x: 0, y: 0 },
+            bits: 2,
+            channels: [2; 3],
+            authenticated: false,
+            plane: Plane::Low,
+            offset: 3,
+            compressed: false,
+            ecc: false,
+            checksum: false,
+            kdf: false,
+        };
+        let before = buf.clone();
+        write_header(&mut buf, &header);
+
+        assert!(buf.iter().zip(&before).all(|(a, b)| a & !0b11000 == b & !0b11000));
+        assert_eq!(read_header_at(&buf, 2, 3), Some(header));
+        assert_eq!(read_header(&buf, 2), None);
+    }
+
+    #[test]
+    fn bytes_round_trip_at_every_bit_count() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+
+        for bits in 1..=8 {
+            let mut buf = cover(3000);
+            embed_bytes(&mut buf, bits, data, &Embedding::default()).unwrap();
+            let (payload, out) = extract_bytes(&buf, bits, None, None).unwrap();
+            assert_eq!(payload, Payload::File { len: data.len() as u32 });
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn bytes_need_bits_within_the_depth() {
+        for bits in [0, 9] {
+            let err = embed_bytes(&mut cover(3000), bits, b"x", &Embedding::default());
+            assert!(matches!(err, Err(ImgError::BitDepth(b, 8)) if b == bits));
+            assert!(read_header(&cover(3000), bits).is_none());
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip_with_key_scatter_and_mac() {
+        let (key, scatter) = ([1; 32], [2; 32]);
+        let mut buf = cover(3000);
+        let embedding = Embedding {
+            message: true,
+            key: Some(key),
+            scatter: Some(scatter),
+            mac: Some(key),
+            ..Embedding::default()
+        };
+        embed_bytes(&mut buf, 2, b"secret", &embedding).unwrap();
+
+        let (payload, out) = extract_bytes(&buf, 2, Some(key), Some(scatter)).unwrap();
+        assert_eq!(payload, Payload::Message { len: 6 });
+        assert_eq!(out, b"secret");
+
+        // Wrong keys fail authentication rather than returning garbage
+        assert!(matches!(extract_bytes(&buf, 2, Some([9; 32]), Some(scatter)),
+            Err(ImgError::Authentication)));
+        assert!(matches!(extract_bytes(&buf, 2, None, None), Err(ImgError::Authentication)));
+    }
+
+    #[test]
+    fn tampering_fails_authentication() {
+        let key = [5; 32];
+        let mut buf = cover(3000);
+        let embedding = Embedding { mac: Some(key), ..Embedding::default() };
+        embed_bytes(&mut buf, 1, b"payload", &embedding).unwrap();
+
+        buf[2999] ^= 1;
+        assert!(matches!(extract_bytes(&buf, 1, Some(key), None), Err(ImgError::Authentication)));
+    }
+
+    #[test]
+    fn oversized_payloads_are_refused() {
+        let mut buf = cover(300);
+        let available = 300 - header_slots(8);
+        let err = embed_bytes(&mut buf, 8, &[0; 300], &Embedding::default());
+        assert!(matches!(err, Err(ImgError::Capacity(300, a)) if a == available));
+    }
+
+    #[test]
+    fn compressed_payloads_round_trip() {
+        // Fits only once deflated
+        let data = [7; 1000];
+        let mut buf = cover(2000);
+        let embedding = Embedding { compress: true, ..Embedding::default() };
+        embed_bytes(&mut buf, 1, &data, &embedding).unwrap();
+        assert!(read_header(&buf, 1).unwrap().compressed);
+        assert_eq!(extract_bytes(&buf, 1, None, None).unwrap().1, data);
+    }
+
+    #[test]
+    fn incompressible_payloads_are_stored_as_is() {
+        let mut buf = cover(3000);
+        let embedding = Embedding { compress: true, ..Embedding::default() };
+        embed_bytes(&mut buf, 2, b"xyz", &embedding).unwrap();
+        assert!(!read_header(&buf, 2).unwrap().compressed);
+        assert_eq!(extract_bytes(&buf, 2, None, None).unwrap().1, b"xyz");
+    }
+
+    #[test]
+    fn ecc_corrects_flipped_bits() {
+        let mut buf = cover(3000);
+        let embedding = Embedding { ecc: true, ..Embedding::default() };
+        embed_bytes(&mut buf, 2, b"noisy channel", &embedding).unwrap();
+        let header = read_header(&buf, 2).unwrap();
+        assert!(header.ecc);
+
+        // One flipped bit in every second coded byte, four samples apiece
+        let start = header.reserved_slots();
+        for i in (start..start + 26 * 4).step_by(8) {
+            buf[i] ^= 1;
+        }
+        assert_eq!(extract_bytes(&buf, 2, None, None).unwrap().1, b"noisy channel");
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn checksum_catches_corrupted_payloads() {
+        let mut buf = cover(3000);
+        let embedding = Embedding { checksum: true, ..Embedding::default() };
+        embed_bytes(&mut buf, 2, b"fragile", &embedding).unwrap();
+        assert_eq!(bytes_checksum_matches(&buf, 2, None), Some(true));
+
+        // Bits past the payload are left out
+        let positions = byte_positions(&buf, 2, None).unwrap();
+        buf[positions[positions.len() - 1] + 1] ^= 3;
+        assert_eq!(bytes_checksum_matches(&buf, 2, None), Some(true));
+
+        buf[positions[5]] ^= 1;
+        assert_eq!(bytes_checksum_matches(&buf, 2, None), Some(false));
+        assert_ne!(extract_bytes(&buf, 2, None, None).unwrap().1, b"fragile");
+
+        let mut plain = cover(3000);
+        embed_bytes(&mut plain, 2, b"fragile", &Embedding::default()).unwrap();
+        assert_eq!(bytes_checksum_matches(&plain, 2, None), None);
+    }
+
+    #[test]
+    fn kdf_iterations_follow_the_checksum() {
+        let mut buf = cover(3000);
+        let embedding = Embedding { checksum: true, kdf: Some(100_000), ..Embedding::default() };
+        embed_bytes(&mut buf, 2, b"stretched", &embedding).unwrap();
+        let header = read_header(&buf, 2).unwrap();
+        assert_eq!(read_kdf(&buf, &header), Some(100_000));
+        assert_eq!(bytes_checksum_matches(&buf, 2, None), Some(true));
+        assert_eq!(extract_bytes(&buf, 2, None, None).unwrap().1, b"stretched");
+
+        let mut plain = cover(3000);
+        embed_bytes(&mut plain, 2, b"hashed", &Embedding::default()).unwrap();
+        assert_eq!(read_kdf(&plain, &read_header(&plain, 2).unwrap()), None);
+    }
+
+    #[test]
+    fn auto_bits_picks_the_fewest_that_fit() {
+        let n = header_slots(1) + 800;
+        let plain = Embedding::default();
+        let ecc = Embedding { ecc: true, ..plain };
+        assert_eq!(auto_bits(n, &[1; 100], &plain).unwrap(), 1);
+        assert_eq!(auto_bits(n, &[1; 101], &plain).unwrap(), 2);
+        assert_eq!(auto_bits(n, &[1; 120], &ecc).unwrap(), 3);
+        assert!(matches!(auto_bits(100, &[1; 100], &plain), Err(ImgError::Capacity(100, _))));
+
+        // The tag takes room too
+        let data = [1; 500];
+        let signed = Embedding { mac: Some([5; 32]), ..plain };
+        assert_eq!(auto_bits(3000, &data, &signed).unwrap(), 2);
+        let embed = |bits| embed_bytes(&mut cover(3000), bits, &data, &signed);
+        assert!(embed(2).is_ok());
+        assert!(embed(1).is_err());
+    }
+
+    #[test]
+    fn scattered_pixels_skip_the_header() {
+        // 216 slots at 1 bit, the samples of the first 72 pixels
+        let past: Vec<usize> = (72..400).collect();
+        let mut order = scatter_pixels(400, header_slots(1), [3; 32]);
+        assert_ne!(order, past);
+        order.sort_unstable();
+        assert_eq!(order, past);
+        assert!(scatter_pixels(50, header_slots(1), [3; 32]).is_empty());
+    }
+
+    #[test]
+    fn cropped_images_no_longer_fit() {
+        let header = |x, y| Header {
+            payload: Payload::Image { width: 50, height: 30, x, y },
+            bits: 2,
+            channels: [2; 3],
+            authenticated: false,
+            plane: Plane::Low,
+            offset: 0,
+            compressed: false,
+            ecc: false,
+            checksum: false,
+            kdf: false,
+        };
+        assert!(header(0, 0).fits(50, 30));
+        assert!(header(150, 170).fits(200, 200));
+        assert!(!header(0, 0).fits(40, 60));
+        assert!(!header(0, 171).fits(200, 200));
+        assert!(!header(u32::MAX, 0).fits(200, 200));
+    }
+
+    #[test]
+    fn extract_needs_a_header() {
+        assert!(matches!(extract_bytes(&cover(3000), 2, None, None), Err(ImgError::NoPayload)));
+    }
+
+    #[test]
+    fn keyed_headers_read_only_with_their_key() {
+        let header = Header {
+            payload: Payload::Image { width: 5, height: 4, x: 0, y: 0 },
+            bits: 2,
+            channels: [2; 3],
+            authenticated: false,
+            plane: Plane::Low,
+            offset: 2,
+            compressed: false,
+            ecc: false,
+            checksum: false,
+            kdf: false,
+        };
+        let mut buf = cover(300);
+        write_keyed_header(&mut buf, &header, [7; 32]);
+
+        assert_eq!(read_keyed_header_at(&buf, 2, 2, [7; 32]), Some(header));
+        assert_eq!(read_keyed_header_at(&buf, 2, 2, [8; 32]), None);
+        assert_eq!(read_header_at(&buf, 2, 2), None);
+    }
+
+    #[test]
+    fn redundant_blocks_survive_cropping() {
+        let mut buf = cover(60 * 60 * 3);
+        let message = Embedding { message: true, ..Embedding::default() };
+        img::for_each_block(&mut buf, 3, 60, 60, 20, |block| {
+            embed_bytes(block, 1, b"crop me", &message)
+        }).unwrap();
+
+        // Cut off 7 columns and 5 rows, leaving the block at (20, 20) at (13, 15)
+        let cropped = img::crop(&buf, 3, 60, 7, 5, 40, 40);
+        assert_eq!(find_block(&cropped, 40, 40, 20, 1, 0), Some((13, 15)));
+        let block = img::crop(&cropped, 3, 40, 13, 15, 20, 20);
+        assert_eq!(extract_bytes(&block, 1, None, None).unwrap().1, b"crop me");
+
+        // Too little is left for a whole block
+        let cropped = img::crop(&buf, 3, 60, 7, 5, 30, 30);
+        assert_eq!(find_block(&cropped, 30, 30, 20, 1, 0), None);
+    }
+}
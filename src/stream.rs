@@ -0,0 +1,163 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+use crate::img::{self, AlphaMode, Compression, ImgError, Metadata, Sample};
+use crate::stego::Plane;
+
+// PNGs read and written a row at a time, for passes that only ever look at
+// one pixel so need never hold a whole image in memory
+
+// Rows of a PNG decoded in turn as RGB, expanded the way read_png expands a
+// whole image and alpha handled as --alpha-mode asks
+pub struct RowReader {
+    reader: png::Reader<BufReader<Box<dyn Read>>>,
+    samples: usize,
+    depth: u8,
+    bkgd: [u8; 3],
+    mode: AlphaMode,
+}
+
+impl RowReader {
+    // Read up to the image data, refusing interlaced PNGs as their rows come
+    // in passes rather than in order
+    pub fn open(path: PathBuf, bkgd: [u8; 3], mode: AlphaMode) -> Result<Self, ImgError> {
+        let mut decoder = png::Decoder::new(img::open(path)?);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let reader = decoder.read_info()?;
+        if reader.info().interlaced {
+            return Err(ImgError::Decode("an interlaced PNG can't be read a row at a time"
+                .to_string()));
+        }
+
+        let (color, depth) = reader.output_color_type();
+        if color == png::ColorType::Indexed {
+            return Err(ImgError::Decode("PNG palette was not expanded".to_string()));
+        }
+        let depth = if depth == png::BitDepth::Sixteen { 16 } else { 8 };
+        Ok(RowReader { reader, samples: color.samples(), depth, bkgd, mode })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.reader.info().width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.reader.info().height
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    // The next row as RGB samples at the depth of T, or None past the last
+    pub fn next_row<T: Sample>(&mut self) -> Result<Option<Vec<T>>, ImgError> {
+        let Some(row) = self.reader.next_row()? else {
+            return Ok(None);
+        };
+        let raw: Vec<T> = match self.depth {
+            16 => row.data().chunks_exact(2)
+                .map(|b| T::from_depth(u16::from_be_bytes([b[0], b[1]]) as u32, 16))
+                .collect(),
+            _ => row.data().iter().map(|&v| T::from_depth(v as u32, 8)).collect(),
+        };
+        img::expand_rgb(&raw, self.samples, self.bkgd, self.mode).map(Some)
+    }
+}
+
+// An RGB PNG at the depth of T written a row at a time, to stdout when the
+// path is -. Every row must be written before finish.
+pub struct RowWriter<T> {
+    writer: png::StreamWriter<'static, BufWriter<Box<dyn Write>>>,
+    sample: PhantomData<T>,
+}
+
+impl<T: Sample> RowWriter<T> {
+    pub fn create(path: PathBuf, width: u32, height: u32, meta: &Metadata,
+            compression: Compression) -> Result<Self, ImgError> {
+        let file = BufWriter::new(img::create(path)?);
+        let encoder = img::png_encoder::<T, _>(file, 3, false, width, height, meta,
+            compression)?;
+        let writer = encoder.write_header()?.into_stream_writer()?;
+        Ok(RowWriter { writer, sample: PhantomData })
+    }
+
+    pub fn write_row(&mut self, row: &[T]) -> Result<(), ImgError> {
+        Ok(self.writer.write_all(&img::png_data(row, 3, None))?)
+    }
+
+    pub fn finish(self) -> Result<(), ImgError> {
+        Ok(self.writer.finish()?)
+    }
+}
+
+// Write a row of hidden samples, already cut down to their top bits, over
+// the plane of a row of cover samples, as img::conceal does for a whole image
+pub fn conceal_row<T: Sample>(row: &mut [T], hidden: &[T], bits: [u8; 3], plane: Plane,
+        offset: u8) {
+    let shifts = bits.map(|b| if plane == Plane::High { T::DEPTH - b } else { offset });
+    let masks: [u32; 3] = std::array::from_fn(|k| {
+        T::MAX & !(T::low_mask(bits[k]) << shifts[k])
+    });
+    for (i, (c, h)) in row.iter_mut().zip(hidden).enumerate() {
+        *c = T::from_u32(c.to_u32() & masks[i % 3] | h.to_u32() << shifts[i % 3]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img::Placement;
+    use crate::stego::{self, Header, Payload};
+
+    #[test]
+    fn rows_round_trip_through_png() {
+        let buf: Vec<u16> = (0..5 * 4 * 3).map(|i| (i * 1031 % 65536) as u16).collect();
+        let path = std::env::temp_dir().join(format!("rsteg-rows-{}.png", std::process::id()));
+
+        let mut writer = RowWriter::<u16>::create(path.clone(), 5, 4, &Metadata::default(),
+            Compression::Fast).unwrap();
+        for row in buf.chunks_exact(5 * 3) {
+            writer.write_row(row).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = RowReader::open(path.clone(), [0; 3], AlphaMode::Composite).unwrap();
+        assert_eq!((reader.width(), reader.height(), reader.depth()), (5, 4, 16));
+        let mut read = Vec::new();
+        while let Some(row) = reader.next_row::<u16>().unwrap() {
+            read.extend(row);
+        }
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(read, buf);
+    }
+
+    #[test]
+    fn rows_conceal_like_the_whole_image() {
+        let hidden: Vec<u8> = (0..6 * 2 * 3).map(|i| (i * 7 % 8) as u8).collect();
+        let cover: Vec<u8> = (0..40 * 5 * 3).map(|i| (i * 37 % 256) as u8).collect();
+        let bits = [3, 2, 3];
+        let (whole_w, _, whole, _) = img::conceal((6, 2, &hidden), (40, 5, cover.clone(), None),
+            bits, Plane::Low, 1, Placement::TopLeft, false, None, None).unwrap();
+        assert_eq!(whole_w, 40);
+
+        let mut rows = cover;
+        for (row, h_row) in rows.chunks_exact_mut(40 * 3).zip(hidden.chunks_exact(6 * 3)) {
+            conceal_row(&mut row[..6 * 3], h_row, bits, Plane::Low, 1);
+        }
+        let header = Header {
+            payload: Payload::Image { width: 6, height: 2, x: 0, y: 0 },
+            bits: stego::header_bits(bits),
+            channels: bits,
+            authenticated: false,
+            plane: Plane::Low,
+            offset: 1,
+            compressed: false,
+            ecc: false,
+            checksum: false,
+            kdf: false,
+        };
+        stego::write_header(&mut rows, &header);
+        assert_eq!(rows, whole);
+    }
+}
@@ -0,0 +1,243 @@
+use std::io::{self, Read, Write};
+use crate::img::{ImgError, Raw, Sample, Samples};
+
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const PLANAR_CONFIG: u16 = 284;
+const EXTRA_SAMPLES: u16 = 338;
+
+const NO_COMPRESSION: u32 = 1;
+const PACKBITS: u32 = 32773;
+
+const WHITE_IS_ZERO: u32 = 0;
+const BLACK_IS_ZERO: u32 = 1;
+const RGB: u32 = 2;
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+fn err(msg: &str) -> ImgError {
+    ImgError::Decode(format!("TIFF {}", msg))
+}
+
+// File contents with the byte order given by its header
+struct Tiff {
+    data: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Tiff {
+    fn bytes<const N: usize>(&self, i: usize) -> Result<[u8; N], ImgError> {
+        let b: [u8; N] = self.data.get(i..i + N).and_then(|b| b.try_into().ok())
+            .ok_or_else(|| err("is truncated"))?;
+        Ok(b)
+    }
+
+    fn u16_at(&self, i: usize) -> Result<u16, ImgError> {
+        let b = self.bytes(i)?;
+        Ok(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32_at(&self, i: usize) -> Result<u32, ImgError> {
+        let b = self.bytes(i)?;
+        Ok(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    // Values of the IFD entry at i, kept in the entry itself when they fit
+    fn values(&self, i: usize) -> Result<(u16, Vec<u32>), ImgError> {
+        let (tag, kind, count) = (self.u16_at(i)?, self.u16_at(i + 2)?, self.u32_at(i + 4)?);
+        let size = match kind {
+            SHORT => 2,
+            LONG => 4,
+            _ => return Ok((tag, Vec::new())),
+        };
+
+        let count = count as usize;
+        let start = if count * size <= 4 { i + 8 } else { self.u32_at(i + 8)? as usize };
+        let values = (0..count).map(|n| match kind {
+            SHORT => self.u16_at(start + n * 2).map(u32::from),
+            _ => self.u32_at(start + n * 4),
+        }).collect::<Result<_, _>>()?;
+        Ok((tag, values))
+    }
+}
+
+// Expands a strip compressed with PackBits run lengths
+fn unpack_bits(data: &[u8]) -> Result<Vec<u8>, ImgError> {
+    let (mut out, mut i) = (Vec::new(), 0);
+    while i < data.len() {
+        let n = data[i] as i8;
+        i += 1;
+        if n >= 0 {
+            let literal = data.get(i..i + n as usize + 1);
+            out.extend(literal.ok_or_else(|| err("strip is truncated"))?);
+            i += n as usize + 1;
+        } else if n != -128 {
+            let byte = *data.get(i).ok_or_else(|| err("strip is truncated"))?;
+            out.extend(std::iter::repeat_n(byte, 1 - n as isize as usize));
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+// Decodes the first image of a TIFF with 8 or 16-bit gray or RGB samples,
+// interleaved in strips either uncompressed or PackBits compressed. Any
+// extra sample is taken as alpha.
+pub fn read_tiff<R: Read>(mut r: R) -> Result<Raw, ImgError> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data).map_err(|e| ImgError::Decode(e.to_string()))?;
+
+    let big_endian = match data.get(..4) {
+        Some(b"II*\0") => false,
+        Some(b"MM\0*") => true,
+        _ => return Err(err("header is invalid")),
+    };
+    let tiff = Tiff { data, big_endian };
+
+    let ifd = tiff.u32_at(4)? as usize;
+    let (mut width, mut height, mut photometric) = (0, 0, None);
+    let (mut bits, mut compression, mut samples, mut planar) = (vec![1], NO_COMPRESSION, 1, 1);
+    let (mut offsets, mut counts, mut rows) = (Vec::new(), Vec::new(), u32::MAX);
+    for n in 0..tiff.u16_at(ifd)? as usize {
+        let (tag, values) = tiff.values(ifd + 2 + n * 12)?;
+        let first = values.first().copied().unwrap_or(0);
+        match tag {
+            IMAGE_WIDTH => width = first,
+            IMAGE_LENGTH => height = first,
+            BITS_PER_SAMPLE => bits = values,
+            COMPRESSION => compression = first,
+            PHOTOMETRIC => photometric = Some(first),
+            STRIP_OFFSETS => offsets = values,
+            SAMPLES_PER_PIXEL => samples = first as usize,
+            ROWS_PER_STRIP => rows = first,
+            STRIP_BYTE_COUNTS => counts = values,
+            PLANAR_CONFIG => planar = first,
+            _ => {},
+        }
+    }
+
+    if width == 0 || height == 0 || rows == 0 || offsets.len() != counts.len() {
+        return Err(err("dimensions are invalid"));
+    }
+    let (color, photometric) = match (photometric, samples) {
+        (Some(p @ (WHITE_IS_ZERO | BLACK_IS_ZERO)), 1 | 2) => (1, p),
+        (Some(RGB), 3 | 4) => (3, RGB),
+        _ => return Err(err("is not gray or RGB")),
+    };
+    let depth = match bits[..] {
+        [b, ..] if (b == 8 || b == 16) && bits.iter().all(|&c| c == b) => b,
+        _ => return Err(err("samples are not all 8 or 16-bit")),
+    };
+    if planar != 1 {
+        return Err(err("planes are stored separately"));
+    }
+
+    let mut pixels = Vec::new();
+    for (&offset, &count) in offsets.iter().zip(&counts) {
+        let strip = tiff.data.get(offset as usize..offset as usize + count as usize)
+            .ok_or_else(|| err("strip is truncated"))?;
+        match compression {
+            NO_COMPRESSION => pixels.extend(strip),
+            PACKBITS => pixels.extend(unpack_bits(strip)?),
+            _ => return Err(err("compression is not supported")),
+        }
+    }
+
+    let n = width as usize * height as usize * samples;
+    let pixels = pixels.get(..n * depth as usize / 8)
+        .ok_or_else(|| err("pixel data is truncated"))?;
+    let mut buf = if depth == 8 {
+        Samples::Eight(pixels.to_vec())
+    } else {
+        let sample = |b: &[u8]| {
+            let b = [b[0], b[1]];
+            if big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) }
+        };
+        Samples::Sixteen(pixels.chunks_exact(2).map(sample).collect())
+    };
+
+    // Gray stored with white as zero is flipped, leaving any alpha be
+    if photometric == WHITE_IS_ZERO {
+        match &mut buf {
+            Samples::Eight(b) => b.iter_mut().step_by(samples).for_each(|c| *c = !*c),
+            Samples::Sixteen(b) => b.iter_mut().step_by(samples).for_each(|c| *c = !*c),
+        }
+    }
+
+    Ok((width, height, buf, color + (samples > color) as usize))
+}
+
+// Encodes gray or RGB samples, with alpha when given, as an uncompressed
+// little endian TIFF in a single strip
+pub fn write_tiff<W: Write, T: Sample>(mut w: W, width: u32, height: u32, buf: &[T],
+        samples: usize, alpha: Option<&[T]>) -> io::Result<()> {
+    let n = T::DEPTH as usize / 8;
+    let data: Vec<u8> = match alpha {
+        Some(alpha) => buf.chunks_exact(samples).zip(alpha)
+            .flat_map(|(p, a)| p.iter().copied().chain([*a]))
+            .flat_map(|c| c.to_u32().to_le_bytes().into_iter().take(n))
+            .collect(),
+        None => buf.iter().flat_map(|c| c.to_u32().to_le_bytes().into_iter().take(n)).collect(),
+    };
+
+    let per_pixel = samples + alpha.is_some() as usize;
+    let mut entries: Vec<(u16, u16, u32, u32)> = vec![
+        (IMAGE_WIDTH, LONG, 1, width),
+        (IMAGE_LENGTH, LONG, 1, height),
+        (BITS_PER_SAMPLE, SHORT, per_pixel as u32, 0),
+        (COMPRESSION, SHORT, 1, NO_COMPRESSION),
+        (PHOTOMETRIC, SHORT, 1, if samples == 1 { BLACK_IS_ZERO } else { RGB }),
+        (STRIP_OFFSETS, LONG, 1, 0),
+        (SAMPLES_PER_PIXEL, SHORT, 1, per_pixel as u32),
+        (ROWS_PER_STRIP, LONG, 1, height),
+        (STRIP_BYTE_COUNTS, LONG, 1, data.len() as u32),
+        (PLANAR_CONFIG, SHORT, 1, 1),
+    ];
+    if alpha.is_some() {
+        // Unassociated alpha
+        entries.push((EXTRA_SAMPLES, SHORT, 1, 2));
+    }
+
+    // Bits per sample go after the IFD when they don't fit in the entry,
+    // and the pixels after them
+    let ifd_len = 2 + entries.len() * 12 + 4;
+    let bits_offset = 8 + ifd_len;
+    let data_offset = bits_offset + if per_pixel > 2 { per_pixel * 2 } else { 0 };
+    for entry in entries.iter_mut() {
+        entry.3 = match entry.0 {
+            BITS_PER_SAMPLE if per_pixel > 2 => bits_offset as u32,
+            BITS_PER_SAMPLE => (0..per_pixel).map(|i| (T::DEPTH as u32) << (16 * i)).sum(),
+            STRIP_OFFSETS => data_offset as u32,
+            _ => entry.3,
+        };
+    }
+
+    let mut h = Vec::with_capacity(data_offset);
+    h.extend(b"II*\0");
+    h.extend(8u32.to_le_bytes());
+    h.extend((entries.len() as u16).to_le_bytes());
+    for (tag, kind, count, value) in entries {
+        h.extend(tag.to_le_bytes());
+        h.extend(kind.to_le_bytes());
+        h.extend(count.to_le_bytes());
+        h.extend(value.to_le_bytes());
+    }
+    h.extend(0u32.to_le_bytes());
+    if per_pixel > 2 {
+        for _ in 0..per_pixel {
+            h.extend((T::DEPTH as u16).to_le_bytes());
+        }
+    }
+
+    w.write_all(&h)?;
+    w.write_all(&data)?;
+    w.flush()
+}
@@ -0,0 +1,724 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Read, Write};
+use crate::img::{ImgError, Raw, Sample, Samples};
+
+const SIGNATURE: u32 = 0x2F;
+
+const PREDICTOR: u32 = 0;
+const COLOR: u32 = 1;
+const SUBTRACT_GREEN: u32 = 2;
+
+// Alphabets of the five codes of a group: green with the length prefixes
+// and cache indices, red, blue, alpha and the distance prefixes
+const LITERALS: usize = 256;
+const LENGTH_PREFIXES: usize = 24;
+const DISTANCE_PREFIXES: usize = 40;
+
+const CODE_LENGTH_ORDER: [usize; 19] = [17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13,
+    14, 15];
+
+// Offsets across and down of the first 120 distance codes, nearest first
+const DISTANCE_MAP: [(i8, i8); 120] = [
+    (0, 1), (1, 0), (1, 1), (-1, 1), (0, 2), (2, 0), (1, 2), (-1, 2), (2, 1), (-2, 1), (2, 2),
+    (-2, 2), (0, 3), (3, 0), (1, 3), (-1, 3), (3, 1), (-3, 1), (2, 3), (-2, 3), (3, 2), (-3, 2),
+    (0, 4), (4, 0), (1, 4), (-1, 4), (4, 1), (-4, 1), (3, 3), (-3, 3), (2, 4), (-2, 4), (4, 2),
+    (-4, 2), (0, 5), (3, 4), (-3, 4), (4, 3), (-4, 3), (5, 0), (1, 5), (-1, 5), (5, 1), (-5, 1),
+    (2, 5), (-2, 5), (5, 2), (-5, 2), (4, 4), (-4, 4), (3, 5), (-3, 5), (5, 3), (-5, 3), (0, 6),
+    (6, 0), (1, 6), (-1, 6), (6, 1), (-6, 1), (2, 6), (-2, 6), (6, 2), (-6, 2), (4, 5), (-4, 5),
+    (5, 4), (-5, 4), (3, 6), (-3, 6), (6, 3), (-6, 3), (0, 7), (7, 0), (1, 7), (-1, 7), (5, 5),
+    (-5, 5), (7, 1), (-7, 1), (4, 6), (-4, 6), (6, 4), (-6, 4), (2, 7), (-2, 7), (7, 2), (-7, 2),
+    (3, 7), (-3, 7), (7, 3), (-7, 3), (5, 6), (-5, 6), (6, 5), (-6, 5), (8, 0), (4, 7), (-4, 7),
+    (7, 4), (-7, 4), (8, 1), (8, 2), (6, 6), (-6, 6), (8, 3), (5, 7), (-5, 7), (7, 5), (-7, 5),
+    (8, 4), (6, 7), (-6, 7), (7, 6), (-7, 6), (8, 5), (7, 7), (-7, 7), (8, 6), (8, 7),
+];
+
+fn err(msg: &str) -> ImgError {
+    ImgError::Decode(format!("WebP {}", msg))
+}
+
+// Blocks of 2^bits needed to cover size
+fn blocks(size: usize, bits: u32) -> usize {
+    (size + (1 << bits) - 1) >> bits
+}
+
+// Bits read least significant first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, n: u32) -> Result<u32, ImgError> {
+        let mut v = 0;
+        for i in 0..n {
+            let byte = *self.data.get(self.pos / 8).ok_or_else(|| err("is truncated"))?;
+            v |= (byte as u32 >> (self.pos % 8) & 1) << i;
+            self.pos += 1;
+        }
+        Ok(v)
+    }
+}
+
+// Canonical prefix code, decoded a bit at a time. A code of one symbol
+// takes no bits at all.
+struct Code {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Code {
+    fn new(lengths: &[u8]) -> Result<Self, ImgError> {
+        let mut counts = [0u16; 16];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(err("prefix code is invalid"));
+            }
+        }
+
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|&s| lengths[s as usize] > 0)
+            .collect();
+        symbols.sort_by_key(|&s| lengths[s as usize]);
+        Ok(Code { counts, symbols })
+    }
+
+    fn read(&self, r: &mut BitReader) -> Result<usize, ImgError> {
+        if let [symbol] = self.symbols[..] {
+            return Ok(symbol as usize);
+        }
+
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= r.bits(1)? as i32;
+            let count = count as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(err("prefix code is invalid"))
+    }
+}
+
+// Reads a prefix code over an alphabet of size symbols, given either as one
+// or two symbols or as code lengths, themselves prefix coded
+fn read_code(r: &mut BitReader, size: usize) -> Result<Code, ImgError> {
+    let mut lengths = vec![0u8; size];
+    if r.bits(1)? == 1 {
+        let n = r.bits(1)? + 1;
+        let first_bits = if r.bits(1)? == 1 { 8 } else { 1 };
+        let mut symbols = vec![r.bits(first_bits)?];
+        if n == 2 {
+            symbols.push(r.bits(8)?);
+        }
+        for s in symbols {
+            *lengths.get_mut(s as usize).ok_or_else(|| err("prefix code is invalid"))? = 1;
+        }
+        return Code::new(&lengths);
+    }
+
+    let mut code_lengths = [0u8; 19];
+    let n = 4 + r.bits(4)? as usize;
+    for &i in &CODE_LENGTH_ORDER[..n] {
+        code_lengths[i] = r.bits(3)? as u8;
+    }
+    let code_lengths = Code::new(&code_lengths)?;
+
+    let mut max = if r.bits(1)? == 1 {
+        let n = 2 + 2 * r.bits(3)?;
+        2 + r.bits(n)? as usize
+    } else {
+        size
+    };
+    let (mut s, mut prev) = (0, 8);
+    while s < size && max > 0 {
+        max -= 1;
+        let (len, repeat) = match code_lengths.read(r)? {
+            len @ 0..=15 => {
+                lengths[s] = len as u8;
+                s += 1;
+                if len != 0 {
+                    prev = len as u8;
+                }
+                continue;
+            },
+            16 => (prev, 3 + r.bits(2)? as usize),
+            17 => (0, 3 + r.bits(3)? as usize),
+            _ => (0, 11 + r.bits(7)? as usize),
+        };
+        let run = lengths.get_mut(s..s + repeat).ok_or_else(|| err("prefix code is invalid"))?;
+        run.fill(len);
+        s += repeat;
+    }
+    Code::new(&lengths)
+}
+
+// Value of a length or distance prefix and its extra bits
+fn prefix_value(r: &mut BitReader, prefix: usize) -> Result<usize, ImgError> {
+    if prefix < 4 {
+        return Ok(prefix + 1);
+    }
+    let extra = (prefix as u32 - 2) >> 1;
+    let offset = (2 + (prefix & 1)) << extra;
+    Ok(offset + r.bits(extra)? as usize + 1)
+}
+
+// Decodes width by height ARGB pixels, coded with literals, backward
+// references and a color cache. Only the main image may switch between
+// groups of codes from block to block.
+fn read_pixels(r: &mut BitReader, width: usize, height: usize, main: bool)
+        -> Result<Vec<u32>, ImgError> {
+    let cache_bits = match r.bits(1)? {
+        1 => match r.bits(4)? {
+            b @ 1..=11 => b,
+            _ => return Err(err("color cache is invalid")),
+        },
+        _ => 0,
+    };
+    let cache_size = if cache_bits > 0 { 1 << cache_bits } else { 0 };
+
+    let (group_bits, group_map) = if main && r.bits(1)? == 1 {
+        let bits = r.bits(3)? + 2;
+        let map = read_pixels(r, blocks(width, bits), blocks(height, bits), false)?;
+        (bits, map.into_iter().map(|p| (p >> 8 & 0xFFFF) as usize).collect())
+    } else {
+        (0, Vec::new())
+    };
+    let group_width = blocks(width, group_bits);
+
+    let sizes = [LITERALS + LENGTH_PREFIXES + cache_size, LITERALS, LITERALS, LITERALS,
+        DISTANCE_PREFIXES];
+    let n_groups = group_map.iter().max().map_or(1, |g| g + 1);
+    let groups = (0..n_groups)
+        .map(|_| sizes.iter().map(|&size| read_code(r, size)).collect::<Result<Vec<_>, _>>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Grown as pixels decode rather than sized by the header, which a file
+    // cut short can't back up
+    let n = width * height;
+    let (mut out, mut cache, mut cached) = (Vec::new(), vec![0u32; cache_size], 0);
+    while out.len() < n {
+        let pos = out.len();
+        let codes = match group_map.is_empty() {
+            true => &groups[0],
+            false => {
+                let (x, y) = ((pos % width) >> group_bits, (pos / width) >> group_bits);
+                &groups[group_map[y * group_width + x]]
+            },
+        };
+
+        match codes[0].read(r)? {
+            green @ 0..LITERALS => {
+                let red = codes[1].read(r)? as u32;
+                let blue = codes[2].read(r)? as u32;
+                let alpha = codes[3].read(r)? as u32;
+                out.push(alpha << 24 | red << 16 | (green as u32) << 8 | blue);
+            },
+            s if s < LITERALS + LENGTH_PREFIXES => {
+                let len = prefix_value(r, s - LITERALS)?;
+                let prefix = codes[4].read(r)?;
+                let code = prefix_value(r, prefix)?;
+                let dist = match code {
+                    d if d > DISTANCE_MAP.len() => d - DISTANCE_MAP.len(),
+                    d => {
+                        let (dx, dy) = DISTANCE_MAP[d - 1];
+                        (dx as isize + dy as isize * width as isize).max(1) as usize
+                    },
+                };
+                if dist > pos || pos + len > n {
+                    return Err(err("backward reference is invalid"));
+                }
+                for _ in 0..len {
+                    out.push(out[out.len() - dist]);
+                }
+            },
+            s => out.push(cache[s - LITERALS - LENGTH_PREFIXES]),
+        }
+
+        if cache_size > 0 {
+            for &p in &out[cached..] {
+                cache[(0x1E35A7BDu32.wrapping_mul(p) >> (32 - cache_bits)) as usize] = p;
+            }
+            cached = out.len();
+        }
+    }
+    Ok(out)
+}
+
+fn channels(p: u32) -> [i32; 4] {
+    [24, 16, 8, 0].map(|s| (p >> s & 0xFF) as i32)
+}
+
+fn pack(c: [i32; 4]) -> u32 {
+    c.iter().zip([24, 16, 8, 0]).fold(0, |p, (&v, s)| p | (v as u32 & 0xFF) << s)
+}
+
+fn per_channel(a: u32, b: u32, f: impl Fn(i32, i32) -> i32) -> u32 {
+    let (a, b) = (channels(a), channels(b));
+    pack(std::array::from_fn(|k| f(a[k], b[k])))
+}
+
+fn average(a: u32, b: u32) -> u32 {
+    per_channel(a, b, |a, b| (a + b) / 2)
+}
+
+// Prediction of a pixel from its neighbours left, top, top left and top
+// right, by one of the 14 modes
+fn predict(mode: u32, l: u32, t: u32, tl: u32, tr: u32) -> u32 {
+    match mode {
+        1 => l,
+        2 => t,
+        3 => tr,
+        4 => tl,
+        5 => average(average(l, tr), t),
+        6 => average(l, tl),
+        7 => average(l, t),
+        8 => average(tl, t),
+        9 => average(t, tr),
+        10 => average(average(l, tl), average(t, tr)),
+        11 => {
+            let distance = |a: u32| {
+                channels(a).iter().zip(channels(tl)).map(|(a, c)| (a - c).abs()).sum::<i32>()
+            };
+            if distance(t) < distance(l) { l } else { t }
+        },
+        12 => {
+            let (l, t, tl) = (channels(l), channels(t), channels(tl));
+            pack(std::array::from_fn(|k| (l[k] + t[k] - tl[k]).clamp(0, 255)))
+        },
+        13 => {
+            let (a, tl) = (channels(average(l, t)), channels(tl));
+            pack(std::array::from_fn(|k| (a[k] + (a[k] - tl[k]) / 2).clamp(0, 255)))
+        },
+        _ => 0xFF000000,
+    }
+}
+
+// Transforms undone in reverse of the order they were read
+enum Transform {
+    Predictor { bits: u32, width: usize, modes: Vec<u32> },
+    Color { bits: u32, width: usize, elements: Vec<u32> },
+    SubtractGreen,
+    ColorIndexing { bits: u32, width: usize, table: Vec<u32> },
+}
+
+impl Transform {
+    fn undo(&self, px: Vec<u32>, height: usize) -> Vec<u32> {
+        match self {
+            Transform::Predictor { bits, width, modes } => {
+                let (w, mut px) = (*width, px);
+                for i in 0..px.len() {
+                    let (x, y) = (i % w, i / w);
+                    let prediction = match (x, y) {
+                        (0, 0) => 0xFF000000,
+                        (_, 0) => px[i - 1],
+                        (0, _) => px[i - w],
+                        _ => {
+                            let mode = modes[(y >> bits) * blocks(w, *bits) + (x >> bits)] >> 8;
+                            predict(mode & 0xF, px[i - 1], px[i - w], px[i - w - 1], px[i - w + 1])
+                        },
+                    };
+                    px[i] = per_channel(px[i], prediction, |a, b| a + b);
+                }
+                px
+            },
+            Transform::Color { bits, width, elements } => {
+                let delta = |t: u32, c: i32| (t as u8 as i8 as i32 * c as u8 as i8 as i32) >> 5;
+                px.into_iter().enumerate().map(|(i, p)| {
+                    let (x, y) = (i % width, i / width);
+                    let e = elements[(y >> bits) * blocks(*width, *bits) + (x >> bits)];
+                    let [a, r, g, b] = channels(p);
+                    let r = r + delta(e, g);
+                    let b = b + delta(e >> 8, g) + delta(e >> 16, r);
+                    pack([a, r, g, b])
+                }).collect()
+            },
+            Transform::SubtractGreen => px.into_iter().map(|p| {
+                let [a, r, g, b] = channels(p);
+                pack([a, r + g, g, b + g])
+            }).collect(),
+            Transform::ColorIndexing { bits, width, table } => {
+                let (per_byte, packed) = (1usize << bits, blocks(*width, *bits));
+                let (depth, mask) = (8 >> bits, (1 << (8 >> bits)) - 1);
+                (0..width * height).map(|i| {
+                    let (x, y) = (i % width, i / width);
+                    let p = px[y * packed + x / per_byte];
+                    let index = (p >> 8 & 0xFF) >> (depth * (x % per_byte)) & mask;
+                    table.get(index as usize).copied().unwrap_or(0)
+                }).collect()
+            },
+        }
+    }
+}
+
+// Decodes the bitstream of a VP8L chunk
+fn read_vp8l(data: &[u8]) -> Result<Raw, ImgError> {
+    let r = &mut BitReader { data, pos: 0 };
+    if r.bits(8)? != SIGNATURE {
+        return Err(err("lossless signature is invalid"));
+    }
+    let (width, height) = (r.bits(14)? as usize + 1, r.bits(14)? as usize + 1);
+    let alpha = r.bits(1)? == 1;
+    if r.bits(3)? != 0 {
+        return Err(err("version is not supported"));
+    }
+
+    let (mut transforms, mut seen, mut packed) = (Vec::new(), 0, width);
+    while r.bits(1)? == 1 {
+        let kind = r.bits(2)?;
+        if seen & 1 << kind != 0 {
+            return Err(err("transform is repeated"));
+        }
+        seen |= 1 << kind;
+
+        transforms.push(match kind {
+            PREDICTOR | COLOR => {
+                let bits = r.bits(3)? + 2;
+                let data = read_pixels(r, blocks(packed, bits), blocks(height, bits), false)?;
+                match kind {
+                    PREDICTOR => Transform::Predictor { bits, width: packed, modes: data },
+                    _ => Transform::Color { bits, width: packed, elements: data },
+                }
+            },
+            SUBTRACT_GREEN => Transform::SubtractGreen,
+            _ => {
+                let size = r.bits(8)? as usize + 1;
+                let mut table = read_pixels(r, size, 1, false)?;
+                for i in 1..size {
+                    table[i] = per_channel(table[i], table[i - 1], |a, b| a + b);
+                }
+                let bits = match size {
+                    1..=2 => 3,
+                    3..=4 => 2,
+                    5..=16 => 1,
+                    _ => 0,
+                };
+                let transform = Transform::ColorIndexing { bits, width: packed, table };
+                packed = blocks(packed, bits);
+                transform
+            },
+        });
+    }
+
+    let mut px = read_pixels(r, packed, height, true)?;
+    for t in transforms.iter().rev() {
+        px = t.undo(px, height);
+    }
+
+    let samples = if alpha { 4 } else { 3 };
+    let buf = px.iter()
+        .flat_map(|&p| [p >> 16, p >> 8, p, p >> 24].map(|c| c as u8).into_iter().take(samples))
+        .collect();
+    Ok((width as u32, height as u32, Samples::Eight(buf), samples))
+}
+
+// Decodes a lossless WebP, in a simple or extended file. Lossy WebP would
+// have lost whatever its low bits carried, so is refused.
+pub fn read_webp<R: Read>(mut r: R) -> Result<Raw, ImgError> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data).map_err(|e| ImgError::Decode(e.to_string()))?;
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(err("header is invalid"));
+    }
+
+    let mut i = 12;
+    while let Some(chunk) = data.get(i..i + 8) {
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+        let body = data.get(i + 8..i + 8 + size).ok_or_else(|| err("is truncated"))?;
+        match &chunk[..4] {
+            b"VP8L" => return read_vp8l(body),
+            b"VP8 " => return Err(err("is lossy, only lossless WebP can be read")),
+            b"ANMF" => return Err(err("animations are not supported")),
+            _ => i += 8 + size + size % 2,
+        }
+    }
+    Err(err("has no image"))
+}
+
+// Bits written least significant first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    n: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, v: u32, bits: u32) {
+        self.acc |= (v as u64) << self.n;
+        self.n += bits;
+        while self.n >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.n -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.n > 0 {
+            self.bytes.push(self.acc as u8);
+        }
+        self.bytes
+    }
+}
+
+// Huffman code lengths for the counts of each symbol, all at most max bits.
+// Counts are halved until the longest code fits.
+fn code_lengths(counts: &[u32], max: u8) -> Vec<u8> {
+    let mut counts = counts.to_vec();
+    loop {
+        let mut heap: BinaryHeap<_> = counts.iter().enumerate()
+            .filter(|(_, &c)| c > 0)
+            .map(|(s, &c)| Reverse((c as u64, vec![s])))
+            .collect();
+        let mut lengths = vec![0u8; counts.len()];
+        if heap.len() == 1 {
+            heap.iter().for_each(|Reverse((_, s))| lengths[s[0]] = 1);
+        }
+        while heap.len() > 1 {
+            let (Reverse((a, mut x)), Reverse((b, y))) = (heap.pop().unwrap(), heap.pop().unwrap());
+            x.extend(y);
+            x.iter().for_each(|&s| lengths[s] += 1);
+            heap.push(Reverse((a + b, x)));
+        }
+
+        if lengths.iter().all(|&l| l <= max) {
+            return lengths;
+        }
+        counts.iter_mut().filter(|c| **c > 0).for_each(|c| *c = (*c >> 1).max(1));
+    }
+}
+
+// Reversed canonical code and its length for each symbol, ready to write
+// least significant first. A code of one symbol takes no bits.
+fn canonical(lengths: &[u8]) -> Vec<(u32, u32)> {
+    if lengths.iter().filter(|&&l| l > 0).count() == 1 {
+        return vec![(0, 0); lengths.len()];
+    }
+
+    let mut counts = [0u32; 16];
+    lengths.iter().filter(|&&l| l > 0).for_each(|&l| counts[l as usize] += 1);
+    let mut next = [0u32; 16];
+    let mut code = 0;
+    for len in 1..16 {
+        code = (code + counts[len - 1]) << 1;
+        next[len] = code;
+    }
+
+    lengths.iter().map(|&l| match l {
+        0 => (0, 0),
+        l => {
+            let code = next[l as usize];
+            next[l as usize] += 1;
+            (code.reverse_bits() >> (32 - l), l as u32)
+        },
+    }).collect()
+}
+
+// Writes the code for the counts of each symbol, as one or two 8-bit
+// symbols when that's all there are, or else as code lengths
+fn write_code(w: &mut BitWriter, counts: &[u32]) -> Vec<(u32, u32)> {
+    let used: Vec<usize> = (0..counts.len()).filter(|&s| counts[s] > 0).collect();
+    if used.len() <= 2 && used.iter().all(|&s| s < LITERALS) {
+        let mut codes = vec![(0, 0); counts.len()];
+        w.put(1, 1);
+        w.put(used.len().max(1) as u32 - 1, 1);
+        w.put(1, 1);
+        for (i, &s) in used.iter().enumerate() {
+            w.put(s as u32, 8);
+            codes[s] = (i as u32, used.len() as u32 - 1);
+        }
+        if used.is_empty() {
+            w.put(0, 8);
+        }
+        return codes;
+    }
+
+    let lengths = code_lengths(counts, 15);
+    let mut length_counts = [0u32; 19];
+    lengths.iter().for_each(|&l| length_counts[l as usize] += 1);
+    let length_lengths = code_lengths(&length_counts, 7);
+
+    w.put(0, 1);
+    w.put(CODE_LENGTH_ORDER.len() as u32 - 4, 4);
+    for i in CODE_LENGTH_ORDER {
+        w.put(length_lengths[i] as u32, 3);
+    }
+    w.put(0, 1);
+    let length_codes = canonical(&length_lengths);
+    for &l in &lengths {
+        let (code, bits) = length_codes[l as usize];
+        w.put(code, bits);
+    }
+    canonical(&lengths)
+}
+
+// Encodes gray or RGB samples, with alpha when given, as a lossless WebP of
+// literal pixels with green subtracted from red and blue
+pub fn write_webp<W: Write, T: Sample>(mut w: W, width: u32, height: u32, buf: &[T],
+        samples: usize, alpha: Option<&[T]>) -> io::Result<()> {
+    if !(1..=1 << 14).contains(&width) || !(1..=1 << 14).contains(&height) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "WebP is from 1 to 16384 pixels wide and high"));
+    }
+
+    let byte = |c: &T| c.to_u32() >> (T::DEPTH - 8);
+    let pixels: Vec<[u32; 4]> = buf.chunks_exact(samples).enumerate().map(|(i, p)| {
+        let (r, g, b) = (byte(&p[0]), byte(&p[samples / 2]), byte(&p[samples - 1]));
+        let a = alpha.map_or(255, |a| byte(&a[i]));
+        [g, r.wrapping_sub(g) & 0xFF, b.wrapping_sub(g) & 0xFF, a]
+    }).collect();
+
+    let mut counts = [vec![0u32; LITERALS + LENGTH_PREFIXES], vec![0; LITERALS],
+        vec![0; LITERALS], vec![0; LITERALS], vec![0; DISTANCE_PREFIXES]];
+    for p in &pixels {
+        for (k, &c) in p.iter().enumerate() {
+            counts[k][c as usize] += 1;
+        }
+    }
+
+    let mut bits = BitWriter::default();
+    bits.put(SIGNATURE, 8);
+    bits.put(width - 1, 14);
+    bits.put(height - 1, 14);
+    bits.put(alpha.is_some() as u32, 1);
+    bits.put(0, 3);
+    bits.put(1, 1);
+    bits.put(SUBTRACT_GREEN, 2);
+    // No more transforms, color cache or groups of codes
+    bits.put(0, 3);
+
+    let codes: Vec<_> = counts.iter().map(|c| write_code(&mut bits, c)).collect();
+    for p in &pixels {
+        for (k, &c) in p.iter().enumerate() {
+            let (code, n) = codes[k][c as usize];
+            bits.put(code, n);
+        }
+    }
+    let data = bits.finish();
+
+    let chunk = data.len() as u32;
+    w.write_all(b"RIFF")?;
+    w.write_all(&(4 + 8 + chunk + chunk % 2).to_le_bytes())?;
+    w.write_all(b"WEBPVP8L")?;
+    w.write_all(&chunk.to_le_bytes())?;
+    w.write_all(&data)?;
+    if chunk % 2 == 1 {
+        w.write_all(&[0])?;
+    }
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img;
+
+    #[test]
+    fn round_trips_exactly() {
+        let (w, h) = (21usize, 13usize);
+        let rgb: Vec<u8> = (0..w * h * 3).map(|i| (i * 37 % 256) as u8).collect();
+        let alpha: Vec<u8> = (0..w * h).map(|i| (i * 11 % 256) as u8).collect();
+        for alpha in [None, Some(&alpha[..])] {
+            let mut data = Vec::new();
+            write_webp(&mut data, w as u32, h as u32, &rgb, 3, alpha).unwrap();
+            let (dw, dh, out, s) = read_webp(&data[..]).unwrap();
+            assert_eq!((dw, dh, s), (w as u32, h as u32, 3 + alpha.is_some() as usize));
+            assert_eq!(img::split_alpha(out.into_depth::<u8>(), s).unwrap(), (rgb.clone(),
+                alpha.map(|a| a.to_vec())));
+        }
+
+        // Flat images, coded with a single symbol of no bits
+        let mut data = Vec::new();
+        write_webp(&mut data, 3, 2, &[9u8; 18], 3, None).unwrap();
+        assert_eq!(read_webp(&data[..]).unwrap().2.into_depth::<u8>(), [9; 18]);
+    }
+
+    #[test]
+    fn reads_predicted_and_color_transformed_images() {
+        let data = [
+            0x52, 0x49, 0x46, 0x46, 0x48, 0x00, 0x00, 0x00, 0x57, 0x45, 0x42, 0x50,
+            0x56, 0x50, 0x38, 0x4C, 0x3B, 0x00, 0x00, 0x00, 0x2F, 0x05, 0x00, 0x01,
+            0x00, 0x99, 0x32, 0x44, 0xF4, 0x3F, 0x36, 0x11, 0xD1, 0xFF, 0x80, 0x9A,
+            0x48, 0x92, 0x94, 0xCD, 0x0E, 0xF2, 0x4B, 0x59, 0xC1, 0x2B, 0x78, 0xFF,
+            0xBA, 0x48, 0x83, 0x58, 0x30, 0xC5, 0xBD, 0x99, 0x8C, 0x30, 0xDB, 0x38,
+            0xD5, 0xFF, 0x08, 0xCE, 0xE5, 0x62, 0x02, 0xD0, 0x09, 0x63, 0x58, 0x6C,
+            0x7C, 0xA8, 0xF3, 0x4B, 0xEB, 0x82, 0x06, 0x00,
+        ];
+        let (w, h, out, samples) = read_webp(&data[..]).unwrap();
+        assert_eq!((w, h, samples), (6, 5, 3));
+        let expected: Vec<u8> = (0..6 * 5 * 3).map(|i| {
+            let (x, y) = (i / 3 % 6, i / 18);
+            [(x * 40 + y * 9) % 256, (x * x * 7 + y * 30) % 256, x * y * 11 + 60][i % 3] as u8
+        }).collect();
+        assert_eq!(out.into_depth::<u8>(), expected);
+    }
+
+    // Made by libwebp from 6 colors, indexed two pixels to a byte and
+    // predicted
+    #[test]
+    fn reads_color_indexed_images() {
+        let data = [
+            0x52, 0x49, 0x46, 0x46, 0x32, 0x00, 0x00, 0x00, 0x57, 0x45, 0x42, 0x50,
+            0x56, 0x50, 0x38, 0x4C, 0x26, 0x00, 0x00, 0x00, 0x2F, 0x07, 0x00, 0x01,
+            0x00, 0x2F, 0x20, 0x10, 0x48, 0x31, 0x45, 0x33, 0x3C, 0x33, 0x47, 0x3E,
+            0x9F, 0xFF, 0x98, 0x66, 0xCD, 0x2C, 0x91, 0x11, 0xFD, 0x0F, 0x10, 0x64,
+            0xDB, 0xE2, 0xDF, 0x60, 0x8D, 0x88, 0x58, 0x0C, 0xE1, 0x08,
+        ];
+        let (w, h, out, samples) = read_webp(&data[..]).unwrap();
+        assert_eq!((w, h, samples), (8, 5, 3));
+        let expected: Vec<u8> = (0..8 * 5 * 3).map(|i| {
+            let k = (i / 3 % 8 + i / 24) * 5 / 11;
+            [k * 51, k * 128 / 6 + 40, 200 - k * 25][i % 3] as u8
+        }).collect();
+        assert_eq!(out.into_depth::<u8>(), expected);
+    }
+
+    // A file around a VP8L bitstream
+    fn riff(vp8l: &[u8]) -> Vec<u8> {
+        let size = vp8l.len() as u32;
+        let mut data = b"RIFF".to_vec();
+        data.extend((4 + 8 + size + size % 2).to_le_bytes());
+        data.extend(b"WEBPVP8L");
+        data.extend(size.to_le_bytes());
+        data.extend(vp8l);
+        data.resize(data.len() + size as usize % 2, 0);
+        data
+    }
+
+    #[test]
+    fn refuses_truncated_and_lossy_files() {
+        let rgb: Vec<u8> = (0..21 * 13 * 3).map(|i| (i * 37 % 256) as u8).collect();
+        let mut data = Vec::new();
+        write_webp(&mut data, 21, 13, &rgb, 3, None).unwrap();
+        let size = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize;
+        let vp8l = &data[20..20 + size];
+        assert_eq!(riff(vp8l), data);
+
+        // A chunk running past the end, and a bitstream ending early
+        assert!(matches!(read_webp(&data[..data.len() - 8]), Err(ImgError::Decode(_))));
+        let cut = riff(&vp8l[..vp8l.len() / 2]);
+        assert!(matches!(read_webp(&cut[..]), Err(ImgError::Decode(_))));
+
+        // 16384 pixels square and nothing more, failing before any are kept
+        let mut header = BitWriter::default();
+        header.put(SIGNATURE, 8);
+        header.put(16383, 14);
+        header.put(16383, 14);
+        header.put(0, 4);
+        let huge = riff(&header.finish());
+        assert!(matches!(read_webp(&huge[..]), Err(ImgError::Decode(_))));
+
+        let lossy = b"RIFF\x0c\x00\x00\x00WEBPVP8 \x00\x00\x00\x00";
+        assert!(matches!(read_webp(&lossy[..]), Err(ImgError::Decode(e)) if e.contains("lossy")));
+    }
+}